@@ -0,0 +1,114 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::provider;
+use crate::settings;
+
+/// Aliases file in the command-k directory, for the shell commands are written for.
+/// It is meant to be sourced from the shell's rc file.
+pub fn get_aliases_file() -> PathBuf {
+    let name = if is_fish() { "aliases.fish" } else { "aliases.sh" };
+    settings::get_command_k_dir().join(name)
+}
+
+/// Line that loads the aliases file, for the shell's rc file
+pub fn source_line() -> String {
+    format!("source {}", get_aliases_file().display())
+}
+
+fn is_fish() -> bool {
+    provider::target_shell().as_deref() == Some("fish")
+}
+
+/// Alias names are letters, digits, `_`, `-` and `.`, not starting with `-`
+/// and with at least one letter or digit, so `.` and `..` are out
+pub fn is_valid_name(name: &str) -> bool {
+    !name.starts_with('-')
+        && name.chars().any(|c| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// POSIX sh function names are letters, digits and `_`, not starting with a digit;
+/// fish functions take any alias name
+fn is_valid_function_name(name: &str, fish: bool) -> bool {
+    fish || (!name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// Definition of `name` running `command`: an alias for a one-liner, a function
+/// for a multi-line command
+pub fn definition(name: &str, command: &str, fish: bool) -> String {
+    let command = command.trim();
+    if command.lines().count() > 1 {
+        let body: String = command.lines().map(|line| format!("    {}\n", line)).collect();
+        return if fish {
+            format!("function {}\n{}end\n", name, body)
+        } else {
+            format!("{}() {{\n{}}}\n", name, body)
+        };
+    }
+    if fish {
+        // fish single quotes only treat \' and \\ specially
+        let quoted = command.replace('\\', "\\\\").replace('\'', "\\'");
+        format!("alias {} '{}'\n", name, quoted)
+    } else {
+        format!("alias {}='{}'\n", name, command.replace('\'', r"'\''"))
+    }
+}
+
+/// Append `name` to the aliases file; returns the file
+pub fn save(name: &str, command: &str) -> Result<PathBuf> {
+    if !is_valid_name(name) {
+        bail!("Invalid alias name: {} (use letters, digits, _, - and .)", name);
+    }
+    let fish = is_fish();
+    if command.trim().lines().count() > 1 && !is_valid_function_name(name, fish) {
+        bail!("Invalid function name: {} (a multi-line command becomes a function; use letters, digits and _)", name);
+    }
+    let path = get_aliases_file();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open aliases file: {:?}", path))?;
+    file.write_all(definition(name, command, fish).as_bytes())
+        .with_context(|| format!("Failed to write aliases file: {:?}", path))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definition() {
+        let cmd = r#"grep -r 'TODO' . | awk '{print $1}' \; echo "it's done""#;
+        assert_eq!(
+            definition("todos", cmd, false),
+            "alias todos='grep -r '\\''TODO'\\'' . | awk '\\''{print $1}'\\'' \\; echo \"it'\\''s done\"'\n"
+        );
+        assert_eq!(
+            definition("todos", cmd, true),
+            "alias todos 'grep -r \\'TODO\\' . | awk \\'{print $1}\\' \\\\; echo \"it\\'s done\"'\n"
+        );
+        assert_eq!(definition("b", "cd build\nmake\n", false), "b() {\n    cd build\n    make\n}\n");
+        assert_eq!(definition("b", "cd build\nmake", true), "function b\n    cd build\n    make\nend\n");
+
+        assert!(is_valid_name("gs.log"));
+        assert!(!is_valid_name("-x"));
+        assert!(!is_valid_name("rm -rf"));
+        assert!(!is_valid_name("a'b"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("."));
+        assert!(!is_valid_name(".."));
+        assert!(is_valid_function_name("build_2", false));
+        assert!(!is_valid_function_name("gs.log", false));
+        assert!(!is_valid_function_name("2x", false));
+        assert!(is_valid_function_name("gs.log", true));
+    }
+}
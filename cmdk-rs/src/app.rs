@@ -1,20 +1,35 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    event::{
+        DisableMouseCapture, EnableMouseCapture, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
-use std::io::{self, Stdout};
+use serde::Serialize;
+use std::cell::Cell;
+use std::io::{self, IsTerminal, Read, Stdout, Write};
 use std::process::Command;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
 use std::thread;
+use std::time::Instant;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
+use crate::aliases;
+use crate::attachment::Attachment;
+use crate::cache::{self, CacheEntry};
+use crate::capabilities;
 use crate::context;
 use crate::events::{key_to_action, key_to_input_action, AppEvent, EventHandler, KeyAction};
-use crate::provider;
+use crate::format::{self, format_response, split_at_operators, CodeBlock, Destination};
+use crate::presets::{self, TaskPreset};
+use crate::provider::{self, QueryChunk, QueryError, QueryMode};
 use crate::session;
 use crate::settings;
+use crate::templates::{self, Template};
 use crate::ui;
 
 /// Application state
@@ -22,11 +37,29 @@ use crate::ui;
 pub enum AppState {
     MainMenu,
     PromptInput,
+    /// Entering a shell command to pipe the response into
+    PipeInput,
+    /// Editing the suggested command before running it
+    EditCommandInput,
     Loading,
     ShowingResult { response: String },
     ContextView,
     SettingsMenu,
     RecentPrompts,
+    /// Choosing a prompt template
+    Templates,
+    /// Entering the value of a template placeholder
+    TemplateInput,
+    /// Naming a privacy profile to save
+    ProfileNameInput,
+    /// Browsing cached responses
+    ResponseCache,
+    /// Editing the current session's tags
+    TagInput,
+    /// Naming an alias to save the command as
+    AliasNameInput,
+    /// No provider is available at startup; shows how to set one up
+    NoProvider { reason: String },
     Error { message: String },
 }
 
@@ -35,34 +68,143 @@ pub enum AppState {
 pub enum MenuItem {
     AskQuestion,
     RecentPrompts,
+    Templates,
     ViewContext,
     PrivacySettings,
+    ResponseCache,
+    TagSession,
     ClearConversation,
     Exit,
 }
 
+impl MenuItem {
+    /// All main menu items, in display order
+    pub const ALL: [MenuItem; 9] = [
+        MenuItem::AskQuestion,
+        MenuItem::RecentPrompts,
+        MenuItem::Templates,
+        MenuItem::ViewContext,
+        MenuItem::PrivacySettings,
+        MenuItem::ResponseCache,
+        MenuItem::TagSession,
+        MenuItem::ClearConversation,
+        MenuItem::Exit,
+    ];
+
+    /// Name used for this item in the `hidden_menu_items` setting
+    pub fn setting_name(&self) -> &'static str {
+        match self {
+            MenuItem::AskQuestion => "ask_question",
+            MenuItem::RecentPrompts => "recent_prompts",
+            MenuItem::Templates => "templates",
+            MenuItem::ViewContext => "view_context",
+            MenuItem::PrivacySettings => "privacy_settings",
+            MenuItem::ResponseCache => "response_cache",
+            MenuItem::TagSession => "tag_session",
+            MenuItem::ClearConversation => "clear_conversation",
+            MenuItem::Exit => "exit",
+        }
+    }
+}
+
+/// Main menu items minus those listed in `hidden_menu_items`.
+/// Never returns an empty menu; Exit stays if everything is hidden.
+fn visible_menu_items() -> Vec<MenuItem> {
+    let hidden = settings::get_setting("hidden_menu_items").unwrap_or_default();
+    let hidden: Vec<&str> = hidden
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let items: Vec<MenuItem> = MenuItem::ALL
+        .into_iter()
+        .filter(|item| !hidden.contains(&item.setting_name()))
+        .collect();
+    if items.is_empty() {
+        vec![MenuItem::Exit]
+    } else {
+        items
+    }
+}
+
 /// Result action items
 #[derive(Debug, Clone, PartialEq)]
 pub enum ResultAction {
     RunCommand,
+    /// Edit the command, then run it like RunCommand
+    EditAndRun,
     CopyToClipboard,
+    PipeTo,
+    /// `man` for the command's program, or its `--help` if there is no man page
+    OpenManPage,
+    /// Append the command to the aliases file under a name
+    SaveAsAlias,
     AskFollowUp,
+    /// Ask the same question again for a different answer (also g)
+    Regenerate,
+    /// Diff of the previous command against this one
+    WhatChanged,
     BackToMenu,
+    Quit,
+}
+
+/// Line range selected in the result view, for copying part of a response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineSelection {
+    pub anchor: usize,
+    pub cursor: usize,
+}
+
+impl LineSelection {
+    /// First and last selected line (inclusive)
+    pub fn range(&self) -> (usize, usize) {
+        (self.anchor.min(self.cursor), self.anchor.max(self.cursor))
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        let (start, end) = self.range();
+        (start..=end).contains(&line)
+    }
+}
+
+/// A command from Run that exited with an error, sent along when asking for a fix
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunFailure {
+    pub command: String,
+    pub code: i32,
+    /// The end of what the command wrote to stderr
+    pub stderr: String,
 }
 
+/// Question asked about a failed command; the details go in the context
+const FIX_QUERY: &str = "The command failed (see Failed Command in the context). Fix it.";
+
+/// How much of a failed command's error output is kept
+const FAILURE_STDERR_BYTES: usize = 4000;
+
 /// Settings menu items
 #[derive(Debug, Clone)]
 pub enum SettingsMenuItem {
     ChangeProvider,
+    /// Remove this directory's provider override (`project-<hash>.conf`)
+    ClearProjectProvider,
     Separator,
+    /// Cycle `privacy_mode`
+    PrivacyMode { mode: String },
     Toggle {
         key: String,
         label: String,
         enabled: bool,
+        /// The toggle has no effect because `privacy_mode` isn't `full`
+        ignored: bool,
     },
     Separator2,
     EnableAll,
     DisableAll,
+    /// Cycle through the saved privacy profiles
+    SwitchProfile { current: Option<String> },
+    SaveProfile,
     Back,
 }
 
@@ -83,28 +225,131 @@ pub struct App {
     pub result_actions: Vec<ResultAction>,
     pub result_selected: usize,
     pub last_response: Option<String>,
+    pub last_query: Option<String>,
+    /// Mode of the query behind the displayed result
+    pub query_mode: QueryMode,
+    /// Response for the other mode, kept so `e` can toggle back without re-querying
+    pub other_response: Option<(QueryMode, String)>,
+    /// Shell command to pipe the response into after the TUI exits
+    pub pipe_command: Option<String>,
+    /// Command from Edit and run, run instead of the response after the TUI exits
+    pub edited_command: Option<String>,
+    /// Active line selection in the result view (`v` to start)
+    pub line_selection: Option<LineSelection>,
+    /// Prefix response lines with their number (display only)
+    pub show_line_numbers: bool,
+    /// The two latest command-mode responses, for "What changed"
+    pub last_command: Option<String>,
+    pub previous_command: Option<String>,
+    /// The result area shows the diff from the previous command
+    pub show_changes: bool,
+    /// `f`: the response is shown full screen, scrolled down this many lines
+    pub full_view: Option<u16>,
+    /// Largest useful scroll offset for the full view, recorded when it is drawn
+    pub full_view_max_scroll: Cell<u16>,
 
     // Settings state
     pub settings_items: Vec<SettingsMenuItem>,
     pub settings_selected: usize,
     pub current_provider: String,
+    /// Result of the last provider warm-up (`None` until one completes)
+    pub provider_ready: Option<bool>,
+    pub warmup_receiver: Option<mpsc::Receiver<bool>>,
 
     // Recent prompts state
     pub recent_prompts: Vec<String>,
     pub prompts_selected: usize,
+    /// Waiting for `y` to delete the selected prompt from history
+    pub confirming_delete: bool,
+
+    // Response cache state
+    pub cache_entries: Vec<CacheEntry>,
+    pub cache_selected: usize,
+    /// Waiting for `y` to clear the whole cache
+    pub confirming_clear_cache: bool,
+
+    // Prompt templates state
+    pub templates: Vec<Template>,
+    pub templates_selected: usize,
+    /// Values entered so far for the selected template's placeholders
+    pub template_values: Vec<String>,
+    /// The provider was just changed: waiting for `y` to save it for this
+    /// directory instead. Holds the global provider to restore.
+    pub confirming_project_save: Option<String>,
+    /// This directory's `project-<hash>.conf` or the project's `.command-k.conf` sets the provider
+    pub provider_pinned: bool,
 
     // Context display
     pub context_display: String,
+    /// Ctrl+G in the prompt: whether the next query is sent with context
+    pub include_context: bool,
+    /// Whether the current query (and its retries/explanations) uses context
+    pub query_context: bool,
+    /// Ctrl+T in the prompt: task preset (index into `TASK_PRESETS`) for the next query
+    pub task_preset: Option<usize>,
+    /// Task preset the current query's context is gathered with
+    pub query_preset: Option<usize>,
+    /// Size of the context gathered when the prompt was opened
+    pub context_bytes: Option<usize>,
+    /// A failed Run being fixed; included in the context until a new question
+    pub run_failure: Option<RunFailure>,
 
     // Session info
     pub session_turns: usize,
 
+    // Whether the status bar shows the working directory
+    pub show_cwd: bool,
+
+    // Whether long commands are shown broken at `&&`, `|` and `;`
+    pub pretty_wrap: bool,
+
+    // Whether commands and code blocks in responses are highlighted
+    pub highlight: bool,
+
+    // Title of the question input box (`input_prompt_label`)
+    pub input_label: String,
+
+    // Prompts larger than this get a warning (`prompt_warn_bytes`, 0 = never)
+    pub prompt_warn_bytes: usize,
+
     // Spinner animation frame
     pub spinner_frame: usize,
 
     // Pending query for async execution
     pub pending_query: Option<String>,
-    pub query_receiver: Option<mpsc::Receiver<Result<String, String>>>,
+    pub pending_stats: Option<session::TurnStats>,
+    /// Cache key to store the pending query's response under
+    pub pending_cache_key: Option<String>,
+    /// The pending query asks the last question again in another form (e), so
+    /// its answer isn't saved as a new session turn
+    pub derived_query: bool,
+    /// How many times the current answer has been regenerated (g)
+    pub regenerate_count: u32,
+    /// Age in seconds of the cache entry the shown response came from
+    pub cached_age: Option<u64>,
+    /// Reasoning the provider gave for the last response (`show_reasoning`)
+    pub last_reasoning: Option<String>,
+    /// Whether the reasoning is expanded above the response (t)
+    pub reasoning_expanded: bool,
+    /// Code block that Run/Copy/Pipe use when a response has several (Tab)
+    pub selected_block: usize,
+    /// Full prompt of the last query, kept for `R` (copy as reproduction)
+    pub last_prompt: Option<String>,
+    /// Provider that gave the last response, which may be a fallback
+    pub last_provider: Option<String>,
+    /// One-off message shown in the status bar until the next key press
+    pub notice: Option<String>,
+    pub query_receiver: Option<mpsc::Receiver<QueryChunk>>,
+    /// Whether responses are shown as they arrive (`stream_responses`)
+    pub stream_responses: bool,
+    /// Set to stop the streaming query, which kills the provider
+    pub cancel_query: Option<Arc<AtomicBool>>,
+    /// The response shown is still arriving; actions wait until it's done
+    pub streaming: bool,
+    /// The response shown is what had arrived when the stream was stopped (Esc)
+    pub stopped_early: bool,
+    /// Why the last query failed, while the error is displayed
+    pub last_error: Option<QueryError>,
 }
 
 impl App {
@@ -114,35 +359,82 @@ impl App {
         Ok(Self {
             state: AppState::MainMenu,
             running: true,
-            menu_items: vec![
-                MenuItem::AskQuestion,
-                MenuItem::RecentPrompts,
-                MenuItem::ViewContext,
-                MenuItem::PrivacySettings,
-                MenuItem::ClearConversation,
-                MenuItem::Exit,
-            ],
+            menu_items: visible_menu_items(),
             selected_index: 0,
             input: String::new(),
             cursor_position: 0,
             result_actions: vec![
                 ResultAction::RunCommand,
+                ResultAction::EditAndRun,
                 ResultAction::CopyToClipboard,
+                ResultAction::PipeTo,
+                ResultAction::OpenManPage,
+                ResultAction::SaveAsAlias,
                 ResultAction::AskFollowUp,
                 ResultAction::BackToMenu,
             ],
             result_selected: 0,
             last_response: None,
+            last_query: None,
+            query_mode: QueryMode::from_settings(),
+            other_response: None,
+            pipe_command: None,
+            edited_command: None,
+            line_selection: None,
+            show_line_numbers: false,
+            last_command: None,
+            previous_command: None,
+            show_changes: false,
+            full_view: None,
+            full_view_max_scroll: Cell::new(0),
             settings_items: Vec::new(),
             settings_selected: 0,
             current_provider: provider::get_current_provider_name(),
+            provider_ready: None,
+            warmup_receiver: None,
             recent_prompts: Vec::new(),
             prompts_selected: 0,
+            cache_entries: Vec::new(),
+            cache_selected: 0,
+            confirming_clear_cache: false,
+            templates: Vec::new(),
+            templates_selected: 0,
+            template_values: Vec::new(),
+            confirming_delete: false,
+            confirming_project_save: None,
+            provider_pinned: false,
             context_display: String::new(),
+            include_context: true,
+            query_context: true,
+            task_preset: None,
+            query_preset: None,
+            context_bytes: None,
+            run_failure: None,
             session_turns,
+            show_cwd: settings::is_enabled("show_cwd_in_statusbar"),
+            pretty_wrap: settings::is_enabled("pretty_wrap_commands"),
+            highlight: settings::is_enabled("highlight_responses"),
+            input_label: settings::get_setting("input_prompt_label")?,
+            prompt_warn_bytes: settings::get_number("prompt_warn_bytes"),
             spinner_frame: 0,
             pending_query: None,
+            pending_stats: None,
+            pending_cache_key: None,
+            regenerate_count: 0,
+            cached_age: None,
+            last_reasoning: None,
+            reasoning_expanded: false,
+            selected_block: 0,
+            last_prompt: None,
+            last_provider: None,
+            notice: None,
             query_receiver: None,
+            stream_responses: settings::is_enabled("stream_responses"),
+            cancel_query: None,
+            streaming: false,
+            stopped_early: false,
+            derived_query: false,
+            last_error: None,
         })
     }
 
@@ -151,83 +443,363 @@ impl App {
         self.spinner_frame = (self.spinner_frame + 1) % 10;
     }
 
-    /// Start an async query
+    /// Start an async query in the configured default mode
     pub fn start_query(&mut self, query: &str) -> Result<()> {
+        self.start_query_with_mode(query, QueryMode::from_settings())
+    }
+
+    /// Start an async query asking for a particular kind of answer
+    pub fn start_query_with_mode(&mut self, query: &str, mode: QueryMode) -> Result<()> {
         // Save to prompt history
         session::add_to_prompt_history(query)?;
 
+        self.ask_with_mode(query, mode)
+    }
+
+    /// Start an async query without adding it to the prompt history
+    fn ask_with_mode(&mut self, query: &str, mode: QueryMode) -> Result<()> {
         // Get context
-        let ctx = context::gather_context()?;
+        let ctx = self.query_context_markdown()?;
 
         // Get session history
         let history = session::get_session_history()?;
 
         // Build full prompt
-        let full_prompt = provider::build_full_prompt(query, &ctx, history.as_deref());
+        let full_prompt = provider::build_full_prompt(query, &ctx, history.as_deref(), mode);
+
+        self.regenerate_count = 0;
+        self.spawn_query(query, full_prompt, mode);
+
+        Ok(())
+    }
+
+    /// Context for the current query, or nothing if it was left out with Ctrl+G.
+    /// A failed command being fixed is always included.
+    fn query_context_markdown(&self) -> Result<String> {
+        let mut ctx = if self.query_context {
+            gather_context_with(self.query_preset.map(|i| &presets::TASK_PRESETS[i]))?
+        } else {
+            context::TerminalContext::default()
+        };
+        if let Some(failure) = &self.run_failure {
+            ctx.push_failed_command(&failure.command, failure.code, &failure.stderr);
+        }
+        if !self.query_context && ctx.sections.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(ctx.to_markdown())
+    }
+
+    /// Go back into the TUI asking how to fix a command that failed after Run
+    pub fn ask_to_fix(&mut self, failure: RunFailure) -> Result<()> {
+        self.run_failure = Some(failure);
+        self.running = true;
+        self.submit_query(FIX_QUERY)
+    }
+
+    /// Open an empty question input, measuring the context once for the large prompt warning
+    fn open_prompt_input(&mut self) {
+        self.input.clear();
+        self.cursor_position = 0;
+        self.task_preset = None;
+        self.measure_context();
+        self.state = AppState::PromptInput;
+    }
+
+    /// Size of the context the next query would send, for the large prompt warning
+    fn measure_context(&mut self) {
+        self.context_bytes = if self.prompt_warn_bytes > 0 {
+            gather_context_with(self.active_preset()).ok().map(|ctx| ctx.total_size())
+        } else {
+            None
+        };
+    }
+
+    /// Task preset picked with Ctrl+T for the next query
+    pub fn active_preset(&self) -> Option<&'static TaskPreset> {
+        self.task_preset.map(|i| &presets::TASK_PRESETS[i])
+    }
+
+    /// Warning for a prompt over `prompt_warn_bytes`: the built prompt while
+    /// loading, or the context it will include while the question is typed
+    pub fn prompt_size_warning(&self) -> Option<String> {
+        let bytes = match self.state {
+            AppState::Loading => self.pending_stats.as_ref()?.prompt_bytes,
+            AppState::PromptInput if self.include_context => self.context_bytes?,
+            _ => return None,
+        };
+        (self.prompt_warn_bytes > 0 && bytes > self.prompt_warn_bytes).then(|| {
+            format!(
+                "⚠ large prompt ({}) — consider trimming context",
+                context::format_size(bytes)
+            )
+        })
+    }
 
-        // Store the query for session saving later
+    /// Run a built prompt on a background thread and switch to the loading state
+    pub fn spawn_query(&mut self, query: &str, full_prompt: String, mode: QueryMode) {
+        self.last_query = Some(query.to_string());
+        self.query_mode = mode;
+        self.other_response = None;
+        self.stopped_early = false;
+        self.derived_query = false;
+
+        // Store the query and its size for session saving later
         self.pending_query = Some(query.to_string());
+        self.pending_stats = Some(session::TurnStats {
+            provider: provider::get_available_provider()
+                .map(|p| p.id().to_string())
+                .unwrap_or_else(|_| "none".to_string()),
+            prompt_bytes: full_prompt.len(),
+        });
+        self.last_prompt = Some(full_prompt.clone());
 
         // Create channel for result
         let (tx, rx) = mpsc::channel();
         self.query_receiver = Some(rx);
 
-        // Run query in background thread
+        self.pending_cache_key = None;
+        // A regenerated answer must not come from the cache
+        if settings::is_enabled("response_cache") && mode != QueryMode::Rephrase && self.regenerate_count == 0 {
+            let provider = self.pending_stats.as_ref().map(|s| s.provider.as_str()).unwrap_or("");
+            let history = session::get_session_history().ok().flatten();
+            let key = cache::key(provider, mode, query, history.as_deref());
+            if let Some(entry) = cache::get(&key) {
+                let _ = tx.send(QueryChunk::Done(Ok(provider::Reply {
+                    response: entry.response.clone(),
+                    reasoning: None,
+                    from_cache: Some(entry.age()),
+                    provider: provider.to_string(),
+                })));
+                self.state = AppState::Loading;
+                return;
+            }
+            self.pending_cache_key = Some(key);
+        }
+
+        // Run query in background thread; a rephrased prompt goes to the input box, so it isn't streamed.
+        // Esc sets `cancel`, which kills the provider.
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.cancel_query = Some(cancel.clone());
+        // Each regenerate asks an HTTP provider for a hotter answer than the last
+        let temperature = (self.regenerate_count > 0)
+            .then(|| provider::regenerate_temperature(self.regenerate_count).to_string());
+        let stream = self.stream_responses && mode != QueryMode::Rephrase;
         thread::spawn(move || {
-            let result = provider::run_query(&full_prompt);
-            let _ = tx.send(result.map_err(|e| e.to_string()));
+            let overrides: Vec<_> = temperature.iter().map(|t| ("http_temperature", t.as_str())).collect();
+            settings::with_overrides(&overrides, || {
+                if stream {
+                    provider::run_query_streaming(&full_prompt, mode, &tx, &cancel);
+                } else {
+                    let _ = tx.send(QueryChunk::Done(provider::run_query_with_reasoning(
+                        &full_prompt,
+                        mode,
+                        Some(&cancel),
+                    )));
+                }
+            })
         });
 
         // Set loading state
         self.state = AppState::Loading;
+    }
+
+    /// Whether a query is running (or its response still streaming in)
+    pub fn query_in_flight(&self) -> bool {
+        self.query_receiver.is_some()
+    }
+
+    /// Show output of a streaming query as it arrives
+    fn append_partial(&mut self, text: &str) {
+        if !self.streaming {
+            self.streaming = true;
+            self.last_response = Some(String::new());
+            self.last_reasoning = None;
+            self.result_selected = 0;
+            self.line_selection = None;
+            self.full_view = None;
+            self.show_changes = false;
+        }
+        self.last_response.get_or_insert_with(String::new).push_str(text);
+        self.show_last_response();
+    }
+
+    /// Switch to the result view of `last_response`, made safe for the terminal.
+    /// Every way into the view goes through here, so lines and code blocks are
+    /// numbered the same whichever way it was reached.
+    fn show_last_response(&mut self) {
+        let response = self.last_response.as_deref().unwrap_or_default();
+        self.state = AppState::ShowingResult {
+            response: format_response(response, Destination::Display),
+        };
+    }
+
+    /// Stop a streaming response, keeping the text received so far as the answer
+    fn stop_streaming(&mut self) -> Result<()> {
+        if let Some(cancel) = self.cancel_query.take() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        self.query_receiver = None;
+        self.streaming = false;
+        self.stopped_early = true;
+        // A partial answer isn't one to serve again from the cache
+        self.pending_cache_key = None;
+        let query = self.pending_query.take().unwrap_or_default();
+        let stats = self.pending_stats.take().unwrap_or_default();
+        let response = self.last_response.clone().unwrap_or_default();
+        self.show_response(&query, response, &stats)
+    }
+
+    /// Keep a finished response: save the turn, offer the actions that fit it and show it
+    fn show_response(&mut self, query: &str, response: String, stats: &session::TurnStats) -> Result<()> {
+        if !self.derived_query {
+            session::append_to_session(query, &response, stats)?;
+            self.session_turns = session::get_session_turn_count();
+        }
 
+        self.last_response = Some(response.clone());
+        self.result_selected = 0;
+        self.line_selection = None;
+        self.full_view = None;
+        self.show_changes = false;
+        self.offer_regenerate();
+        if self.query_mode == QueryMode::Command {
+            self.record_command(&response);
+        }
+        self.show_last_response();
+        self.offer_command_actions();
+        self.auto_copy(&response);
         Ok(())
     }
 
+    /// Stop the running query: a streaming provider is killed and its partial output dropped
+    fn cancel_running_query(&mut self) {
+        if let Some(cancel) = self.cancel_query.take() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        self.query_receiver = None;
+        self.pending_query = None;
+        self.pending_stats = None;
+        self.pending_cache_key = None;
+        if self.streaming {
+            self.streaming = false;
+            self.last_response = None;
+        }
+        self.notice = Some(QueryError::Cancelled.to_string());
+        self.state = AppState::MainMenu;
+    }
+
     /// Check if query is complete and handle result
     pub fn check_query_complete(&mut self) -> Result<bool> {
-        if let Some(ref rx) = self.query_receiver {
+        loop {
+            let Some(rx) = &self.query_receiver else {
+                return Ok(false);
+            };
             match rx.try_recv() {
-                Ok(result) => {
+                Ok(QueryChunk::Partial(text)) => self.append_partial(&text),
+                Ok(QueryChunk::Done(result)) => {
                     let query = self.pending_query.take().unwrap_or_default();
+                    let mut stats = self.pending_stats.take().unwrap_or_default();
                     self.query_receiver = None;
+                    self.cancel_query = None;
+                    let streamed = std::mem::take(&mut self.streaming);
 
                     match result {
-                        Ok(response) => {
-                            // Save to session
-                            session::append_to_session(&query, &response)?;
-                            self.session_turns = session::get_session_turn_count();
-
-                            self.last_response = Some(response.clone());
-                            self.result_selected = 0;
-                            self.state = AppState::ShowingResult { response };
+                        Ok(reply) if self.query_mode == QueryMode::Rephrase => {
+                            // Offer the suggestion in the input box so it can be edited or run
+                            self.input = reply.response.lines().next().unwrap_or("").trim().to_string();
+                            self.cursor_position = self.input.len();
+                            self.state = AppState::PromptInput;
+                        }
+                        Ok(provider::Reply { response, reasoning, from_cache, provider }) => {
+                            stats.provider = provider.clone();
+                            self.last_provider = Some(provider);
+                            self.last_reasoning = reasoning;
+                            self.cached_age = from_cache;
+                            self.reasoning_expanded = false;
+                            self.selected_block = 0;
+
+                            if let Some(key) = self.pending_cache_key.take() {
+                                // A full cache directory shouldn't cost the answer
+                                cache::put(&key, &query, &response).ok();
+                            }
+                            self.show_response(&query, response, &stats)?;
                         }
                         Err(e) => {
-                            self.state = AppState::Error { message: e };
+                            // Output streamed before the failure isn't an answer
+                            if streamed {
+                                self.last_response = None;
+                            }
+                            let message = match e.hint() {
+                                "" => e.to_string(),
+                                hint => format!("{}\n\n{}", e, hint),
+                            };
+                            self.last_error = Some(e);
+                            self.state = AppState::Error { message };
                         }
                     }
-                    Ok(true)
+                    return Ok(true);
                 }
-                Err(mpsc::TryRecvError::Empty) => Ok(false),
+                Err(mpsc::TryRecvError::Empty) => return Ok(false),
                 Err(mpsc::TryRecvError::Disconnected) => {
                     self.query_receiver = None;
+                    self.cancel_query = None;
+                    if std::mem::take(&mut self.streaming) {
+                        self.last_response = None;
+                    }
                     self.pending_query = None;
+                    self.pending_stats = None;
+                    self.last_error = Some(QueryError::Other("Query thread disconnected".to_string()));
                     self.state = AppState::Error {
                         message: "Query thread disconnected".to_string(),
                     };
-                    Ok(true)
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Probe the newly selected provider in the background
+    fn start_warmup(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        self.warmup_receiver = Some(rx);
+        self.provider_ready = None;
+
+        thread::spawn(move || {
+            let _ = tx.send(provider::probe_provider().is_ok());
+        });
+    }
+
+    /// Pick up the warm-up result once the probe finishes
+    pub fn check_warmup(&mut self) {
+        if let Some(ref rx) = self.warmup_receiver {
+            match rx.try_recv() {
+                Ok(ready) => {
+                    self.provider_ready = Some(ready);
+                    self.warmup_receiver = None;
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.provider_ready = Some(false);
+                    self.warmup_receiver = None;
                 }
             }
-        } else {
-            Ok(false)
         }
     }
 
     /// Refresh settings menu items
     fn refresh_settings_items(&mut self) {
         self.current_provider = provider::get_current_provider_name();
+        self.provider_pinned = settings::project_override("ai_provider").is_some();
 
-        let mut items = vec![SettingsMenuItem::ChangeProvider, SettingsMenuItem::Separator];
+        let mode = settings::privacy_mode();
+        let ignored = mode != "full";
+        let mut items = vec![SettingsMenuItem::ChangeProvider];
+        if settings::directory_override("ai_provider").is_some() {
+            items.push(SettingsMenuItem::ClearProjectProvider);
+        }
+        items.push(SettingsMenuItem::Separator);
+        items.push(SettingsMenuItem::PrivacyMode { mode });
 
         for (key, label) in settings::PRIVACY_SETTINGS {
             let enabled = settings::is_enabled(key);
@@ -235,12 +807,17 @@ impl App {
                 key: key.to_string(),
                 label: label.to_string(),
                 enabled,
+                ignored,
             });
         }
 
         items.push(SettingsMenuItem::Separator2);
         items.push(SettingsMenuItem::EnableAll);
         items.push(SettingsMenuItem::DisableAll);
+        items.push(SettingsMenuItem::SwitchProfile {
+            current: settings::active_profile(),
+        });
+        items.push(SettingsMenuItem::SaveProfile);
         items.push(SettingsMenuItem::Back);
 
         self.settings_items = items;
@@ -249,14 +826,24 @@ impl App {
     /// Handle key events based on current state
     pub fn handle_key(&mut self, event: AppEvent) -> Result<()> {
         let AppEvent::Key(key) = event;
+        self.notice = None;
         match &self.state {
                 AppState::MainMenu => self.handle_main_menu_key(key_to_action(key))?,
                 AppState::PromptInput => self.handle_input_key(key_to_input_action(key))?,
-                AppState::Loading => {} // Ignore input during loading
+                AppState::PipeInput => self.handle_pipe_input_key(key_to_input_action(key))?,
+                AppState::EditCommandInput => self.handle_edit_command_key(key_to_input_action(key))?,
+                AppState::Loading => self.handle_loading_key(key_to_action(key)),
                 AppState::ShowingResult { .. } => self.handle_result_key(key_to_action(key))?,
                 AppState::ContextView => self.handle_context_key(key_to_action(key))?,
                 AppState::SettingsMenu => self.handle_settings_key(key_to_action(key))?,
                 AppState::RecentPrompts => self.handle_prompts_key(key_to_action(key))?,
+                AppState::Templates => self.handle_templates_key(key_to_action(key))?,
+                AppState::TemplateInput => self.handle_template_input_key(key_to_input_action(key))?,
+                AppState::ProfileNameInput => self.handle_profile_name_key(key_to_input_action(key))?,
+                AppState::ResponseCache => self.handle_cache_key(key_to_action(key))?,
+                AppState::TagInput => self.handle_tag_input_key(key_to_input_action(key))?,
+                AppState::AliasNameInput => self.handle_alias_name_key(key_to_input_action(key))?,
+                AppState::NoProvider { .. } => self.handle_no_provider_key(key_to_action(key)),
             AppState::Error { .. } => self.handle_error_key(key_to_action(key))?,
         }
         Ok(())
@@ -264,29 +851,29 @@ impl App {
 
     fn handle_main_menu_key(&mut self, action: KeyAction) -> Result<()> {
         match action {
-            KeyAction::Up => {
-                if self.selected_index > 0 {
-                    self.selected_index -= 1;
-                }
+            KeyAction::Up if self.selected_index > 0 => {
+                self.selected_index -= 1;
             }
-            KeyAction::Down => {
-                if self.selected_index < self.menu_items.len() - 1 {
-                    self.selected_index += 1;
-                }
+            KeyAction::Down if self.selected_index + 1 < self.menu_items.len() => {
+                self.selected_index += 1;
             }
             KeyAction::Select => {
                 let item = &self.menu_items[self.selected_index];
                 match item {
                     MenuItem::AskQuestion => {
-                        self.input.clear();
-                        self.cursor_position = 0;
-                        self.state = AppState::PromptInput;
+                        self.run_failure = None;
+                        self.open_prompt_input();
                     }
                     MenuItem::RecentPrompts => {
                         self.recent_prompts = session::get_recent_prompts(20)?;
                         self.prompts_selected = 0;
                         self.state = AppState::RecentPrompts;
                     }
+                    MenuItem::Templates => {
+                        self.templates = templates::load_templates()?;
+                        self.templates_selected = 0;
+                        self.state = AppState::Templates;
+                    }
                     MenuItem::ViewContext => {
                         self.context_display = context::gather_context_display()?;
                         self.state = AppState::ContextView;
@@ -296,6 +883,17 @@ impl App {
                         self.settings_selected = 0;
                         self.state = AppState::SettingsMenu;
                     }
+                    MenuItem::ResponseCache => {
+                        self.cache_entries = cache::entries();
+                        self.cache_selected = 0;
+                        self.state = AppState::ResponseCache;
+                    }
+                    MenuItem::TagSession => {
+                        // Edit the tags as a comma-separated list
+                        self.input = session::session_tags()?.join(", ");
+                        self.cursor_position = self.input.len();
+                        self.state = AppState::TagInput;
+                    }
                     MenuItem::ClearConversation => {
                         session::clear_session()?;
                         self.session_turns = 0;
@@ -305,6 +903,16 @@ impl App {
                     }
                 }
             }
+            KeyAction::Char('n') => {
+                // Start a new conversation, keeping the old session file
+                session::start_fresh_session();
+                self.session_turns = 0;
+            }
+            KeyAction::Char('N') => {
+                // New conversation that still knows the gist of the old one
+                session::start_fresh_session_with_recap()?;
+                self.session_turns = 0;
+            }
             KeyAction::Quit => {
                 self.running = false;
             }
@@ -313,46 +921,54 @@ impl App {
         Ok(())
     }
 
-    fn handle_input_key(&mut self, action: KeyAction) -> Result<()> {
-        match action {
-            KeyAction::Char(c) => {
-                self.input.insert(self.cursor_position, c);
+    /// Apply a text-editing key to the input line. Returns false if the key isn't an edit.
+    fn edit_input(&mut self, action: &KeyAction) -> bool {
+        edit_text(&mut self.input, &mut self.cursor_position, action)
+    }
+
+    /// Apply a key that only inputs spanning several lines take: a new line,
+    /// or moving between lines. Returns false for any other key.
+    fn edit_input_lines(&mut self, action: &KeyAction) -> bool {
+        match *action {
+            KeyAction::Newline => {
+                self.input.insert(self.cursor_position, '\n');
                 self.cursor_position += 1;
             }
-            KeyAction::Backspace => {
-                if self.cursor_position > 0 {
-                    self.cursor_position -= 1;
-                    self.input.remove(self.cursor_position);
-                }
-            }
-            KeyAction::Delete => {
-                if self.cursor_position < self.input.len() {
-                    self.input.remove(self.cursor_position);
+            KeyAction::Up | KeyAction::Down => {
+                // Keep the column when moving between lines, as far as the line allows
+                let (row, col) = cursor_row_col(&self.input, self.cursor_position);
+                let target = if *action == KeyAction::Up { row.checked_sub(1) } else { Some(row + 1) };
+                if let Some(position) = target.and_then(|row| row_col_offset(&self.input, row, col)) {
+                    self.cursor_position = position;
                 }
             }
-            KeyAction::Left => {
-                if self.cursor_position > 0 {
-                    self.cursor_position -= 1;
-                }
-            }
-            KeyAction::Right => {
-                if self.cursor_position < self.input.len() {
-                    self.cursor_position += 1;
-                }
-            }
-            KeyAction::Home => {
-                self.cursor_position = 0;
+            _ => return false,
+        }
+        true
+    }
+
+    fn handle_input_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) || self.edit_input_lines(&action) {
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Select if !self.input.trim().is_empty() => {
+                let query = self.input.clone();
+                self.submit_query(&query)?;
             }
-            KeyAction::End => {
-                self.cursor_position = self.input.len();
+            KeyAction::ToggleContext => {
+                self.include_context = !self.include_context;
             }
-            KeyAction::Select => {
-                if !self.input.trim().is_empty() {
-                    let query = self.input.clone();
-                    self.submit_query(&query)?;
-                }
+            KeyAction::CyclePreset => {
+                self.task_preset = presets::next(self.task_preset);
+                self.measure_context();
             }
             KeyAction::Back => {
+                // Declining a suggested rewrite leaves no rephrase pending
+                if self.query_mode == QueryMode::Rephrase {
+                    self.query_mode = QueryMode::from_settings();
+                }
                 self.state = AppState::MainMenu;
             }
             KeyAction::Quit => {
@@ -363,25 +979,18 @@ impl App {
         Ok(())
     }
 
-    fn handle_result_key(&mut self, action: KeyAction) -> Result<()> {
+    fn handle_edit_command_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) || self.edit_input_lines(&action) {
+            return Ok(());
+        }
+
         match action {
-            KeyAction::Up => {
-                if self.result_selected > 0 {
-                    self.result_selected -= 1;
-                }
-            }
-            KeyAction::Down => {
-                if self.result_selected < self.result_actions.len() - 1 {
-                    self.result_selected += 1;
-                }
-            }
-            KeyAction::Select => {
-                let action = &self.result_actions[self.result_selected].clone();
-                self.handle_result_action(action)?;
-            }
-            KeyAction::Back => {
-                self.state = AppState::MainMenu;
+            KeyAction::Select if !self.input.trim().is_empty() => {
+                // Exit the TUI; the command runs after the terminal is restored
+                self.edited_command = Some(self.input.trim().to_string());
+                self.running = false;
             }
+            KeyAction::Back => self.show_last_response(),
             KeyAction::Quit => {
                 self.running = false;
             }
@@ -390,114 +999,122 @@ impl App {
         Ok(())
     }
 
-    fn handle_result_action(&mut self, action: &ResultAction) -> Result<()> {
+    fn handle_pipe_input_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) {
+            return Ok(());
+        }
+
         match action {
-            ResultAction::RunCommand => {
-                if self.last_response.is_some() {
-                    // We need to exit the TUI to run the command
-                    self.running = false;
-                }
-            }
-            ResultAction::CopyToClipboard => {
-                if let Some(ref response) = self.last_response {
-                    if let Ok(mut clipboard) = arboard::Clipboard::new() {
-                        clipboard.set_text(response.clone()).ok();
-                    }
-                }
-                self.state = AppState::MainMenu;
-            }
-            ResultAction::AskFollowUp => {
-                self.input.clear();
-                self.cursor_position = 0;
-                self.state = AppState::PromptInput;
+            KeyAction::Select if !self.input.trim().is_empty() => {
+                // Exit the TUI; the response is piped after the terminal is restored
+                self.pipe_command = Some(self.input.trim().to_string());
+                self.running = false;
             }
-            ResultAction::BackToMenu => {
-                self.state = AppState::MainMenu;
+            KeyAction::Back => self.show_last_response(),
+            KeyAction::Quit => {
+                self.running = false;
             }
+            _ => {}
         }
         Ok(())
     }
 
-    fn handle_context_key(&mut self, action: KeyAction) -> Result<()> {
+    /// Esc stops the running query, q stops it and quits; other keys are ignored while loading
+    pub fn handle_loading_key(&mut self, action: KeyAction) {
+        if self.cancel_query.is_none() {
+            return;
+        }
         match action {
-            KeyAction::Back | KeyAction::Select => {
-                self.state = AppState::MainMenu;
-            }
+            KeyAction::Back => self.cancel_running_query(),
             KeyAction::Quit => {
+                self.cancel_running_query();
                 self.running = false;
             }
             _ => {}
         }
-        Ok(())
     }
 
-    fn handle_settings_key(&mut self, action: KeyAction) -> Result<()> {
+    fn handle_result_key(&mut self, action: KeyAction) -> Result<()> {
+        // The actions need the whole response: Esc stops the stream and keeps
+        // what has arrived, q stops it and quits
+        if self.streaming {
+            match action {
+                KeyAction::Back => self.stop_streaming()?,
+                _ => self.handle_loading_key(action),
+            }
+            return Ok(());
+        }
+
+        // Line numbers are display only, so they can be toggled while selecting too
+        if let KeyAction::Char('l') = action {
+            self.show_line_numbers = !self.show_line_numbers;
+            return Ok(());
+        }
+
+        if let Some(scroll) = self.full_view {
+            let max = self.full_view_max_scroll.get();
+            match action {
+                KeyAction::Up => self.full_view = Some(scroll.saturating_sub(1)),
+                KeyAction::Down => self.full_view = Some((scroll + 1).min(max)),
+                KeyAction::Home => self.full_view = Some(0),
+                KeyAction::End => self.full_view = Some(max),
+                KeyAction::Back | KeyAction::Char('f') => self.full_view = None,
+                KeyAction::Quit => self.running = false,
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        if self.show_changes {
+            match action {
+                KeyAction::Back | KeyAction::Select => self.show_changes = false,
+                KeyAction::Char('f') => self.full_view = Some(0),
+                KeyAction::Quit => self.running = false,
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        if self.line_selection.is_some() {
+            return self.handle_line_selection_key(action);
+        }
+
         match action {
-            KeyAction::Up => {
-                if self.settings_selected > 0 {
-                    self.settings_selected -= 1;
-                    // Skip separators
-                    while self.settings_selected > 0 {
-                        if let SettingsMenuItem::Separator | SettingsMenuItem::Separator2 =
-                            self.settings_items[self.settings_selected]
-                        {
-                            self.settings_selected -= 1;
-                        } else {
-                            break;
-                        }
-                    }
-                }
+            KeyAction::Up if self.result_selected > 0 => {
+                self.result_selected -= 1;
             }
-            KeyAction::Down => {
-                if self.settings_selected < self.settings_items.len() - 1 {
-                    self.settings_selected += 1;
-                    // Skip separators
-                    while self.settings_selected < self.settings_items.len() - 1 {
-                        if let SettingsMenuItem::Separator | SettingsMenuItem::Separator2 =
-                            self.settings_items[self.settings_selected]
-                        {
-                            self.settings_selected += 1;
-                        } else {
-                            break;
-                        }
-                    }
-                }
+            KeyAction::Down if self.result_selected < self.result_actions.len() - 1 => {
+                self.result_selected += 1;
             }
             KeyAction::Select => {
-                let item = self.settings_items[self.settings_selected].clone();
-                match item {
-                    SettingsMenuItem::ChangeProvider => {
-                        // Cycle through providers: auto -> claude -> codex -> auto
-                        let current = settings::get_setting("ai_provider")?;
-                        let next = match current.as_str() {
-                            "auto" => "claude",
-                            "claude" => "codex",
-                            "codex" => "auto",
-                            _ => "auto",
-                        };
-                        settings::set_setting("ai_provider", next)?;
-                        self.refresh_settings_items();
-                    }
-                    SettingsMenuItem::Toggle { key, .. } => {
-                        settings::toggle_setting(&key)?;
-                        self.refresh_settings_items();
-                    }
-                    SettingsMenuItem::EnableAll => {
-                        for (key, _) in settings::PRIVACY_SETTINGS {
-                            settings::set_setting(key, "true")?;
-                        }
-                        self.refresh_settings_items();
-                    }
-                    SettingsMenuItem::DisableAll => {
-                        for (key, _) in settings::PRIVACY_SETTINGS {
-                            settings::set_setting(key, "false")?;
-                        }
-                        self.refresh_settings_items();
-                    }
-                    SettingsMenuItem::Back => {
-                        self.state = AppState::MainMenu;
-                    }
-                    _ => {}
+                let action = &self.result_actions[self.result_selected].clone();
+                self.handle_result_action(action)?;
+            }
+            KeyAction::Char('e') => {
+                self.toggle_explanation()?;
+            }
+            KeyAction::Char('p') => {
+                self.rephrase_query()?;
+            }
+            KeyAction::Char('v') => {
+                self.line_selection = Some(LineSelection { anchor: 0, cursor: 0 });
+            }
+            KeyAction::Char('f') => {
+                self.full_view = Some(0);
+            }
+            KeyAction::Char('R') => {
+                self.copy_repro();
+            }
+            KeyAction::Char('g') => {
+                self.regenerate()?;
+            }
+            KeyAction::Char('t') if self.last_reasoning.is_some() => {
+                self.reasoning_expanded = !self.reasoning_expanded;
+            }
+            KeyAction::Tab => {
+                let count = self.code_blocks().len();
+                if count > 1 {
+                    self.selected_block = (self.selected_block + 1) % count;
                 }
             }
             KeyAction::Back => {
@@ -511,68 +1128,877 @@ impl App {
         Ok(())
     }
 
-    fn handle_prompts_key(&mut self, action: KeyAction) -> Result<()> {
+    /// Move the line cursor (j/k), extend the selection (J/K) and copy it (Enter)
+    fn handle_line_selection_key(&mut self, action: KeyAction) -> Result<()> {
+        let AppState::ShowingResult { ref response } = self.state else {
+            return Ok(());
+        };
+        let response = response.clone();
+        let last_line = response.lines().count().saturating_sub(1);
+        let Some(mut selection) = self.line_selection else {
+            return Ok(());
+        };
+
         match action {
             KeyAction::Up => {
-                if self.prompts_selected > 0 {
-                    self.prompts_selected -= 1;
-                }
+                selection.cursor = selection.cursor.saturating_sub(1);
+                selection.anchor = selection.cursor;
             }
             KeyAction::Down => {
-                if self.prompts_selected < self.recent_prompts.len().saturating_sub(1) {
-                    self.prompts_selected += 1;
-                }
+                selection.cursor = (selection.cursor + 1).min(last_line);
+                selection.anchor = selection.cursor;
+            }
+            KeyAction::Char('K') => {
+                selection.cursor = selection.cursor.saturating_sub(1);
+            }
+            KeyAction::Char('J') => {
+                selection.cursor = (selection.cursor + 1).min(last_line);
             }
             KeyAction::Select => {
-                if !self.recent_prompts.is_empty() {
-                    let query = self.recent_prompts[self.prompts_selected].clone();
-                    self.submit_query(&query)?;
+                let (start, end) = selection.range();
+                let text = response
+                    .lines()
+                    .skip(start)
+                    .take(end - start + 1)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if let Ok(mut clipboard) = arboard::Clipboard::new() {
+                    clipboard.set_text(text).ok();
                 }
+                self.line_selection = None;
+                return Ok(());
             }
-            KeyAction::Back => {
-                self.state = AppState::MainMenu;
+            KeyAction::Back | KeyAction::Char('v') => {
+                self.line_selection = None;
+                return Ok(());
             }
             KeyAction::Quit => {
                 self.running = false;
             }
             _ => {}
         }
+
+        self.line_selection = Some(selection);
         Ok(())
     }
 
-    fn handle_error_key(&mut self, action: KeyAction) -> Result<()> {
-        match action {
-            KeyAction::Select | KeyAction::Back => {
-                self.state = AppState::MainMenu;
-            }
-            KeyAction::Quit => {
-                self.running = false;
-            }
-            _ => {}
+    /// Switch the result between the command and its explanation, querying once if needed
+    fn toggle_explanation(&mut self) -> Result<()> {
+        if let Some((other_mode, other)) = self.other_response.take() {
+            self.other_response = self
+                .last_response
+                .replace(other)
+                .map(|current| (self.query_mode, current));
+            self.query_mode = other_mode;
+            self.selected_block = 0;
+            self.show_last_response();
+            self.offer_command_actions();
+            return Ok(());
         }
+
+        let Some(query) = self.last_query.clone() else {
+            return Ok(());
+        };
+        let mode = match self.query_mode {
+            QueryMode::Explain => QueryMode::Command,
+            _ => QueryMode::Explain,
+        };
+        let previous = self.last_response.clone().map(|r| (self.query_mode, r));
+        // The same question in another form: neither history nor the session gets it again
+        self.ask_with_mode(&query, mode)?;
+        self.derived_query = true;
+        self.other_response = previous;
         Ok(())
     }
 
-    /// Submit a query to the AI (starts async query)
-    fn submit_query(&mut self, query: &str) -> Result<()> {
+    /// Copy the last prompt and response as a redacted markdown reproduction.
+    /// Falls back to a file in the command-k directory if there is no clipboard.
+    fn copy_repro(&mut self) {
+        let (Some(prompt), Some(response)) = (&self.last_prompt, &self.last_response) else {
+            return;
+        };
+        let provider = self.last_provider.as_deref().unwrap_or("none");
+        let repro = session::format_repro(prompt, response, provider);
+
+        let copied = arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.set_text(repro.clone()))
+            .is_ok();
+        self.notice = Some(if copied {
+            "Reproduction copied to clipboard".to_string()
+        } else {
+            let path = settings::get_command_k_dir().join("last-repro.md");
+            match std::fs::write(&path, repro) {
+                Ok(()) => format!("Reproduction saved to {}", path.display()),
+                Err(e) => format!("Failed to save reproduction: {}", e),
+            }
+        });
+    }
+
+    /// Ask the last question again for a different answer
+    fn regenerate(&mut self) -> Result<()> {
+        let (Some(query), Some(previous)) = (self.last_query.clone(), self.last_response.clone()) else {
+            return Ok(());
+        };
+        let ctx = self.query_context_markdown()?;
+        let history = session::get_session_history()?;
+        let attempt = self.regenerate_count + 1;
+        let full_prompt = provider::build_regenerate_prompt(
+            &query,
+            &ctx,
+            history.as_deref(),
+            self.query_mode,
+            &previous,
+            attempt,
+        );
+        self.regenerate_count = attempt;
+        self.spawn_query(&query, full_prompt, self.query_mode);
+        Ok(())
+    }
+
+    /// Ask the provider to rewrite the last query into a clearer prompt
+    fn rephrase_query(&mut self) -> Result<()> {
+        let Some(query) = self.last_query.clone() else {
+            return Ok(());
+        };
+        let ctx = self.query_context_markdown()?;
+        let full_prompt = provider::build_full_prompt(&query, &ctx, None, QueryMode::Rephrase);
+        self.spawn_query(&query, full_prompt, QueryMode::Rephrase);
+        Ok(())
+    }
+
+    /// Fenced code blocks of the displayed response
+    pub fn code_blocks(&self) -> Vec<CodeBlock> {
+        match self.state {
+            AppState::ShowingResult { ref response } => format::code_blocks(response),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The chosen block, if the response has more than one
+    pub fn selected_code_block(&self) -> Option<CodeBlock> {
+        let blocks = self.code_blocks();
+        if blocks.len() < 2 {
+            return None;
+        }
+        blocks.get(self.selected_block.min(blocks.len() - 1)).cloned()
+    }
+
+    /// The command-mode response, even while its explanation is displayed.
+    /// With several code blocks, the selected one.
+    pub fn command_response(&self) -> Option<String> {
+        if let Some(block) = self.selected_code_block() {
+            return Some(block.code);
+        }
+        match self.other_response {
+            Some((QueryMode::Command, ref command)) if self.query_mode != QueryMode::Command => {
+                Some(command.clone())
+            }
+            _ => self.last_response.clone(),
+        }
+    }
+
+    /// Whether the response holds a command: a command-mode response, one kept
+    /// behind its explanation, or a fenced block in an explanation or answer
+    pub fn has_command(&self) -> bool {
+        self.query_mode == QueryMode::Command
+            || matches!(self.other_response, Some((QueryMode::Command, _)))
+            || !self.code_blocks().is_empty()
+    }
+
+    /// Offer running, editing and saving as an alias only when the response
+    /// holds a command, so prose from an explanation never reaches the shell
+    fn offer_command_actions(&mut self) {
+        const COMMAND_ACTIONS: [ResultAction; 3] =
+            [ResultAction::RunCommand, ResultAction::EditAndRun, ResultAction::SaveAsAlias];
+        if !self.has_command() {
+            self.result_actions.retain(|action| !COMMAND_ACTIONS.contains(action));
+            return;
+        }
+        if !self.result_actions.contains(&ResultAction::RunCommand) {
+            self.result_actions.splice(0..0, [ResultAction::RunCommand, ResultAction::EditAndRun]);
+            // Only the full menu has "Save as alias", just before "Ask follow-up"
+            if let Some(at) = self.result_actions.iter().position(|action| *action == ResultAction::AskFollowUp) {
+                self.result_actions.insert(at, ResultAction::SaveAsAlias);
+            }
+        }
+    }
+
+    /// Offer "Regenerate" after "Ask follow-up" only when there is a question to ask again
+    fn offer_regenerate(&mut self) {
+        self.result_actions.retain(|action| *action != ResultAction::Regenerate);
+        if self.last_query.is_some() {
+            let at = self
+                .result_actions
+                .iter()
+                .position(|action| *action == ResultAction::AskFollowUp)
+                .map_or(self.result_actions.len(), |i| i + 1);
+            self.result_actions.insert(at, ResultAction::Regenerate);
+        }
+    }
+
+    /// Keep a new command next to the one before it, offering "What changed"
+    /// when they differ
+    fn record_command(&mut self, command: &str) {
+        self.previous_command = self.last_command.replace(command.to_string());
+        self.result_actions.retain(|action| *action != ResultAction::WhatChanged);
+        if self.previous_command.as_deref().is_some_and(|previous| previous != command) {
+            let at = self
+                .result_actions
+                .iter()
+                .position(|action| *action == ResultAction::BackToMenu)
+                .unwrap_or(self.result_actions.len());
+            self.result_actions.insert(at, ResultAction::WhatChanged);
+        }
+    }
+
+    /// Copy a new response to the clipboard right away if `auto_copy` is on
+    fn auto_copy(&mut self, response: &str) {
+        if !settings::is_enabled("auto_copy") {
+            return;
+        }
+        let copied = arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.set_text(format_response(response, Destination::Clipboard)))
+            .is_ok();
+        self.notice = Some(if copied {
+            "Copied to clipboard".to_string()
+        } else {
+            "Couldn't copy to the clipboard".to_string()
+        });
+    }
+
+    /// The program to open the man page of: the first one in the command
+    pub fn man_page_program(&self) -> Option<String> {
+        let cmd = format_response(&self.command_response()?, Destination::Run);
+        man_page_program(&cmd).map(str::to_string)
+    }
+
+    /// Whether Run is disabled because the response doesn't parse as a shell
+    /// command (`validate_commands`). Key sequences are never checked.
+    pub fn run_disabled(&self) -> bool {
+        settings::is_enabled("validate_commands")
+            && self.command_response().is_some_and(|response| {
+                let cmd = format_response(&response, Destination::Run);
+                !contains_special_keys(&cmd) && !provider::parses_as_shell(&cmd)
+            })
+    }
+
+    fn handle_result_action(&mut self, action: &ResultAction) -> Result<()> {
+        match action {
+            ResultAction::RunCommand if self.run_disabled() => {
+                self.notice = Some("Response doesn't look like a runnable command".to_string());
+            }
+            ResultAction::RunCommand => {
+                if self.last_response.is_some() {
+                    // We need to exit the TUI to run the command
+                    self.running = false;
+                }
+            }
+            ResultAction::EditAndRun => {
+                self.input = self
+                    .command_response()
+                    .map(|response| format_response(&response, Destination::Run))
+                    .unwrap_or_default();
+                self.cursor_position = self.input.len();
+                self.state = AppState::EditCommandInput;
+            }
+            ResultAction::CopyToClipboard => {
+                let text = self.selected_code_block().map(|block| block.code).or(self.last_response.clone());
+                if let Some(ref response) = text {
+                    if let Ok(mut clipboard) = arboard::Clipboard::new() {
+                        clipboard
+                            .set_text(format_response(response, Destination::Clipboard))
+                            .ok();
+                    }
+                }
+                self.state = AppState::MainMenu;
+            }
+            ResultAction::PipeTo => {
+                self.input.clear();
+                self.cursor_position = 0;
+                self.state = AppState::PipeInput;
+            }
+            ResultAction::OpenManPage if self.man_page_program().is_none() => {
+                self.notice = Some("No program to look up in this response".to_string());
+            }
+            ResultAction::OpenManPage => {
+                // man needs the terminal, so it runs after the TUI exits
+                self.running = false;
+            }
+            ResultAction::SaveAsAlias => match self.command_response() {
+                Some(response) if !contains_special_keys(&response) => {
+                    self.input.clear();
+                    self.cursor_position = 0;
+                    self.state = AppState::AliasNameInput;
+                }
+                _ => self.notice = Some("Key sequences can't be saved as an alias".to_string()),
+            },
+            ResultAction::AskFollowUp => self.open_prompt_input(),
+            ResultAction::Regenerate => self.regenerate()?,
+            ResultAction::WhatChanged => {
+                self.show_changes = true;
+            }
+            ResultAction::BackToMenu => {
+                self.state = AppState::MainMenu;
+            }
+            ResultAction::Quit => {
+                self.running = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_context_key(&mut self, action: KeyAction) -> Result<()> {
+        match action {
+            KeyAction::Back | KeyAction::Select => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_settings_key(&mut self, action: KeyAction) -> Result<()> {
+        // `y` moves the provider just chosen into this directory's overrides;
+        // any other key keeps it global and is handled as usual
+        if let Some(previous) = self.confirming_project_save.take() {
+            if matches!(action, KeyAction::Char('y')) {
+                let provider = settings::get_global_setting("ai_provider")?;
+                settings::set_project_setting("ai_provider", &provider)?;
+                settings::set_setting("ai_provider", &previous)?;
+                self.refresh_settings_items();
+                self.notice = Some(format!(
+                    "Saved provider for this directory in {}",
+                    settings::get_directory_settings_file().display()
+                ));
+                return Ok(());
+            }
+        }
+
+        match action {
+            KeyAction::Up if self.settings_selected > 0 => {
+                self.settings_selected -= 1;
+                // Skip separators
+                while self.settings_selected > 0 {
+                    if let SettingsMenuItem::Separator | SettingsMenuItem::Separator2 =
+                        self.settings_items[self.settings_selected]
+                    {
+                        self.settings_selected -= 1;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            KeyAction::Down if self.settings_selected < self.settings_items.len() - 1 => {
+                self.settings_selected += 1;
+                // Skip separators
+                while self.settings_selected < self.settings_items.len() - 1 {
+                    if let SettingsMenuItem::Separator | SettingsMenuItem::Separator2 =
+                        self.settings_items[self.settings_selected]
+                    {
+                        self.settings_selected += 1;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            KeyAction::Select => {
+                let item = self.settings_items[self.settings_selected].clone();
+                match item {
+                    SettingsMenuItem::ChangeProvider => {
+                        // Cycle through providers: auto -> claude -> codex -> custom ones -> auto
+                        let current = settings::get_setting("ai_provider")?;
+                        let choices = provider::provider_choices();
+                        let next = choices
+                            .iter()
+                            .position(|choice| *choice == current)
+                            .and_then(|i| choices.get(i + 1))
+                            .map_or("auto", String::as_str);
+                        if self.provider_pinned {
+                            // This project already has its own provider; change it for this directory
+                            settings::set_project_setting("ai_provider", next)?;
+                        } else {
+                            let previous = settings::get_global_setting("ai_provider")?;
+                            settings::set_setting("ai_provider", next)?;
+                            self.confirming_project_save = Some(previous);
+                        }
+                        self.refresh_settings_items();
+                        if settings::is_enabled("warmup_on_change") {
+                            self.start_warmup();
+                        } else {
+                            self.provider_ready = None;
+                        }
+                    }
+                    SettingsMenuItem::ClearProjectProvider => {
+                        settings::clear_project_setting("ai_provider")?;
+                        self.refresh_settings_items();
+                        self.settings_selected = 0;
+                        let source = match settings::project_override("ai_provider") {
+                            Some(_) => ".command-k.conf",
+                            None => "global",
+                        };
+                        self.notice = Some(format!("Using the {} provider here again", source));
+                        if settings::is_enabled("warmup_on_change") {
+                            self.start_warmup();
+                        } else {
+                            self.provider_ready = None;
+                        }
+                    }
+                    SettingsMenuItem::PrivacyMode { mode } => {
+                        let modes = settings::PRIVACY_MODES;
+                        let next = modes
+                            .iter()
+                            .position(|m| *m == mode)
+                            .map_or(modes[0], |i| modes[(i + 1) % modes.len()]);
+                        settings::set_setting("privacy_mode", next)?;
+                        self.refresh_settings_items();
+                    }
+                    SettingsMenuItem::Toggle { key, .. } => {
+                        settings::toggle_setting(&key)?;
+                        self.refresh_settings_items();
+                    }
+                    SettingsMenuItem::EnableAll => {
+                        for (key, _) in settings::PRIVACY_SETTINGS {
+                            settings::set_setting(key, "true")?;
+                        }
+                        self.refresh_settings_items();
+                    }
+                    SettingsMenuItem::DisableAll => {
+                        for (key, _) in settings::PRIVACY_SETTINGS {
+                            settings::set_setting(key, "false")?;
+                        }
+                        self.refresh_settings_items();
+                    }
+                    SettingsMenuItem::SwitchProfile { current } => {
+                        // Next profile after the active one, wrapping around
+                        let names = settings::profile_names();
+                        let next = current
+                            .and_then(|current| names.iter().position(|name| *name == current))
+                            .and_then(|i| names.get(i + 1))
+                            .or(names.first());
+                        match next {
+                            Some(name) => {
+                                settings::apply_profile(name)?;
+                                self.notice = Some(format!("Switched to profile {}", name));
+                            }
+                            None => {
+                                self.notice = Some("No profiles yet: save the current settings first".to_string());
+                            }
+                        }
+                        self.refresh_settings_items();
+                    }
+                    SettingsMenuItem::SaveProfile => {
+                        self.input = settings::active_profile().unwrap_or_default();
+                        self.cursor_position = self.input.len();
+                        self.state = AppState::ProfileNameInput;
+                    }
+                    SettingsMenuItem::Back => {
+                        self.state = AppState::MainMenu;
+                    }
+                    _ => {}
+                }
+            }
+            KeyAction::Back => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_prompts_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.confirming_delete {
+            self.confirming_delete = false;
+            if matches!(action, KeyAction::Char('y' | 'Y')) {
+                let prompt = self.recent_prompts[self.prompts_selected].clone();
+                session::remove_from_prompt_history(&prompt)?;
+                self.recent_prompts = session::get_recent_prompts(20)?;
+                self.prompts_selected = self
+                    .prompts_selected
+                    .min(self.recent_prompts.len().saturating_sub(1));
+            }
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Up if self.prompts_selected > 0 => {
+                self.prompts_selected -= 1;
+            }
+            KeyAction::Down if self.prompts_selected < self.recent_prompts.len().saturating_sub(1) => {
+                self.prompts_selected += 1;
+            }
+            KeyAction::Select if !self.recent_prompts.is_empty() => {
+                let query = self.recent_prompts[self.prompts_selected].clone();
+                self.submit_query(&query)?;
+            }
+            KeyAction::Char('d') if !self.recent_prompts.is_empty() => {
+                self.confirming_delete = true;
+            }
+            KeyAction::Back => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_cache_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.confirming_clear_cache {
+            self.confirming_clear_cache = false;
+            if matches!(action, KeyAction::Char('y') | KeyAction::Select) {
+                let count = cache::clear()?;
+                self.cache_entries.clear();
+                self.cache_selected = 0;
+                self.notice = Some(format!("Removed {} cached responses", count));
+            }
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Up if self.cache_selected > 0 => {
+                self.cache_selected -= 1;
+            }
+            KeyAction::Down if self.cache_selected + 1 < self.cache_entries.len() => {
+                self.cache_selected += 1;
+            }
+            KeyAction::Char('d') if !self.cache_entries.is_empty() => {
+                let entry = self.cache_entries.remove(self.cache_selected);
+                cache::remove(&entry.key)?;
+                self.cache_selected = self.cache_selected.min(self.cache_entries.len().saturating_sub(1));
+            }
+            KeyAction::Char('c') if !self.cache_entries.is_empty() => {
+                self.confirming_clear_cache = true;
+            }
+            KeyAction::Back => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_templates_key(&mut self, action: KeyAction) -> Result<()> {
+        match action {
+            KeyAction::Up if self.templates_selected > 0 => {
+                self.templates_selected -= 1;
+            }
+            KeyAction::Down if self.templates_selected + 1 < self.templates.len() => {
+                self.templates_selected += 1;
+            }
+            KeyAction::Select if !self.templates.is_empty() => {
+                self.template_values.clear();
+                self.next_template_placeholder()?;
+            }
+            KeyAction::Back => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The selected template's next unfilled placeholder, if any
+    pub fn current_placeholder(&self) -> Option<String> {
+        let template = self.templates.get(self.templates_selected)?;
+        template.placeholders().into_iter().nth(self.template_values.len())
+    }
+
+    /// Ask for the next placeholder, or submit the filled template once all have values
+    fn next_template_placeholder(&mut self) -> Result<()> {
+        self.input.clear();
+        self.cursor_position = 0;
+        if self.current_placeholder().is_some() {
+            self.state = AppState::TemplateInput;
+        } else {
+            let query = self.templates[self.templates_selected].fill(&self.template_values);
+            self.submit_query(&query)?;
+        }
+        Ok(())
+    }
+
+    fn handle_template_input_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) {
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Select if !self.input.trim().is_empty() => {
+                self.template_values.push(self.input.trim().to_string());
+                self.next_template_placeholder()?;
+            }
+            KeyAction::Back => {
+                self.state = AppState::Templates;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_profile_name_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) {
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Select if !self.input.trim().is_empty() => {
+                let name = self.input.trim().to_string();
+                match settings::save_profile(&name) {
+                    Ok(()) => {
+                        self.notice = Some(format!("Saved profile {}", name));
+                        self.refresh_settings_items();
+                        self.state = AppState::SettingsMenu;
+                    }
+                    Err(e) => self.notice = Some(e.to_string()),
+                }
+            }
+            KeyAction::Back => {
+                self.state = AppState::SettingsMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_alias_name_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) {
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Select if !self.input.trim().is_empty() => {
+                let name = self.input.trim().to_string();
+                let command = self.command_response().unwrap_or_default();
+                match aliases::save(&name, &format_response(&command, Destination::Run)) {
+                    Ok(_) => {
+                        self.notice = Some(format!("Saved alias {}; load it with: {}", name, aliases::source_line()));
+                        self.show_last_response();
+                    }
+                    Err(e) => self.notice = Some(e.to_string()),
+                }
+            }
+            KeyAction::Back => self.show_last_response(),
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_tag_input_key(&mut self, action: KeyAction) -> Result<()> {
+        if self.edit_input(&action) {
+            return Ok(());
+        }
+
+        match action {
+            KeyAction::Select => {
+                let tags = session::parse_tag_list(&self.input);
+                session::set_session_tags(&tags)?;
+                self.notice = Some(if tags.is_empty() {
+                    "Removed the session's tags".to_string()
+                } else {
+                    format!("Tagged session: {}", tags.join(", "))
+                });
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Back => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether the displayed error can be retried with `r`
+    pub fn can_retry(&self) -> bool {
+        self.last_query.is_some() && self.last_error.as_ref().is_none_or(QueryError::is_retryable)
+    }
+
+    /// Open on the setup screen instead of the main menu if no provider is available
+    pub fn check_provider_at_startup(&mut self) {
+        if let Err(e) = provider::get_available_provider() {
+            self.state = AppState::NoProvider { reason: format!("{:#}", e) };
+        }
+    }
+
+    fn handle_no_provider_key(&mut self, action: KeyAction) {
+        match action {
+            KeyAction::Char('s') => {
+                self.refresh_settings_items();
+                self.settings_selected = 0;
+                self.state = AppState::SettingsMenu;
+            }
+            KeyAction::Select | KeyAction::Back => {
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_error_key(&mut self, action: KeyAction) -> Result<()> {
+        match action {
+            KeyAction::Select | KeyAction::Back => {
+                self.last_error = None;
+                self.state = AppState::MainMenu;
+            }
+            KeyAction::Char('s') if matches!(self.last_error, Some(QueryError::ProviderMissing(_))) => {
+                self.last_error = None;
+                self.refresh_settings_items();
+                self.settings_selected = 0;
+                self.state = AppState::SettingsMenu;
+            }
+            KeyAction::Char('r') if self.can_retry() => {
+                // Retry the query that failed
+                if let Some(query) = self.last_query.clone() {
+                    if self.query_mode == QueryMode::Rephrase {
+                        self.rephrase_query()?;
+                    } else {
+                        // It's already in the prompt history, and a retried e toggle
+                        // still isn't a new session turn
+                        let derived = self.derived_query;
+                        self.ask_with_mode(&query, self.query_mode)?;
+                        self.derived_query = derived;
+                    }
+                }
+            }
+            KeyAction::Char('p') => {
+                self.rephrase_query()?;
+            }
+            KeyAction::Quit => {
+                self.running = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Submit a query to the AI (starts async query)
+    fn submit_query(&mut self, query: &str) -> Result<()> {
+        // The Ctrl+G and Ctrl+T choices only apply to this query
+        self.query_context = std::mem::replace(&mut self.include_context, true);
+        self.query_preset = self.task_preset.take();
         self.start_query(query)
     }
 
     /// Check if we should run a command on exit
     pub fn should_run_command(&self) -> bool {
+        self.chosen_on_exit(ResultAction::RunCommand)
+    }
+
+    /// Whether the TUI was left to read a man page
+    pub fn should_open_man_page(&self) -> bool {
+        self.chosen_on_exit(ResultAction::OpenManPage)
+    }
+
+    fn chosen_on_exit(&self, action: ResultAction) -> bool {
         if let AppState::ShowingResult { .. } = &self.state {
-            self.result_actions.get(self.result_selected) == Some(&ResultAction::RunCommand)
+            self.result_actions.get(self.result_selected) == Some(&action)
         } else {
             false
         }
     }
 }
 
+/// Apply an editing key to `input` at byte offset `cursor`, which stays on a
+/// char boundary so multibyte characters are inserted and removed whole
+fn edit_text(input: &mut String, cursor: &mut usize, action: &KeyAction) -> bool {
+    let before = input[..*cursor].chars().next_back().map_or(0, char::len_utf8);
+    let after = input[*cursor..].chars().next().map_or(0, char::len_utf8);
+    match *action {
+        KeyAction::Char(c) => {
+            input.insert(*cursor, c);
+            *cursor += c.len_utf8();
+        }
+        KeyAction::Backspace if before > 0 => {
+            *cursor -= before;
+            input.remove(*cursor);
+        }
+        KeyAction::Delete if after > 0 => {
+            input.remove(*cursor);
+        }
+        KeyAction::Left if before > 0 => *cursor -= before,
+        KeyAction::Right if after > 0 => *cursor += after,
+        KeyAction::Home => {
+            *cursor = input[..*cursor].rfind('\n').map_or(0, |i| i + 1);
+        }
+        KeyAction::End => {
+            let rest = &input[*cursor..];
+            *cursor += rest.find('\n').unwrap_or(rest.len());
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Row and display column of byte offset `position` in an input that may span
+/// several lines, counting wide characters like CJK as two columns
+pub fn cursor_row_col(input: &str, position: usize) -> (usize, usize) {
+    let before = &input[..position];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count(), before[line_start..].width())
+}
+
+/// Byte offset of display column `col` on line `row`, clamped to the end of
+/// that line and never inside a character
+fn row_col_offset(input: &str, row: usize, col: usize) -> Option<usize> {
+    let mut start = 0;
+    for (i, line) in input.split('\n').enumerate() {
+        if i == row {
+            let mut width = 0;
+            for (offset, c) in line.char_indices() {
+                width += c.width().unwrap_or(0);
+                if width > col {
+                    return Some(start + offset);
+                }
+            }
+            return Some(start + line.len());
+        }
+        start += line.len() + 1;
+    }
+    None
+}
+
+/// Gather context, with a task preset's toggles in place of the privacy settings they cover
+fn gather_context_with(preset: Option<&TaskPreset>) -> Result<context::TerminalContext> {
+    match preset {
+        Some(preset) => settings::with_overrides(preset.settings, context::gather_context),
+        None => context::gather_context(),
+    }
+}
+
 /// Setup terminal for TUI
 fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    // Lets terminals with the kitty keyboard protocol report Shift+Enter; others ignore it
+    execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES))?;
     let backend = CrosstermBackend::new(stdout);
     let terminal = Terminal::new(backend)?;
     Ok(terminal)
@@ -583,6 +2009,7 @@ fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
+        PopKeyboardEnhancementFlags,
         LeaveAlternateScreen,
         DisableMouseCapture
     )?;
@@ -590,53 +2017,130 @@ fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result
     Ok(())
 }
 
+/// Teardown after SIGTERM/SIGHUP: keep a response that already arrived, put
+/// the terminal back (it may be gone already) and exit with 128 + signal
+pub(crate) fn exit_on_signal(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    app: &mut App,
+    signal: i32,
+) -> ! {
+    if app.query_in_flight() {
+        app.check_query_complete().ok();
+    }
+    restore_terminal(terminal).ok();
+    std::process::exit(128 + signal);
+}
+
 /// Run the interactive TUI mode
 pub fn run_interactive_mode() -> Result<()> {
     let mut terminal = setup_terminal()?;
     let mut app = App::new()?;
-    let event_handler = EventHandler::new(100);
+    app.check_provider_at_startup();
 
     // Clean up stale sessions
     session::cleanup_stale_session()?;
 
-    while app.running {
-        // Check if async query is complete
-        if matches!(app.state, AppState::Loading) {
-            app.check_query_complete()?;
-            app.tick_spinner();
-        }
+    // Answer a preset question right away (demos, fixed-purpose setups);
+    // Esc on the answer leads to the menu as usual
+    let autorun = settings::get_setting("autorun_query")?;
+    if matches!(app.state, AppState::MainMenu) && !autorun.trim().is_empty() {
+        app.submit_query(autorun.trim())?;
+    }
 
-        // Draw UI
-        terminal.draw(|f| ui::render(f, &app))?;
+    loop {
+        let event_handler = EventHandler::new(100);
 
-        // Handle events (but not during loading - just animate)
-        if let Some(event) = event_handler.next()? {
-            if !matches!(app.state, AppState::Loading) {
+        while app.running {
+            if let Some(signal) = event_handler.terminate_signal() {
+                exit_on_signal(&mut terminal, &mut app, signal);
+            }
+
+            // Check if async query is complete
+            if app.query_in_flight() {
+                app.check_query_complete()?;
+                app.tick_spinner();
+            }
+
+            app.check_warmup();
+
+            // Draw UI
+            terminal.draw(|f| ui::render(f, &app))?;
+
+            // Handle events (while loading, only Esc to stop a streaming query)
+            if let Some(event) = event_handler.next()? {
                 app.handle_key(event)?;
             }
         }
+
+        // Restore terminal
+        restore_terminal(&mut terminal)?;
+        // The command runs with the default signal handling
+        drop(event_handler);
+
+        // A command that failed can come straight back as a question about fixing it
+        match run_selected_action(&mut app, true)? {
+            Some(failure) => {
+                app.ask_to_fix(failure)?;
+                terminal = setup_terminal()?;
+            }
+            None => return Ok(()),
+        }
     }
+}
 
-    // Check if we need to run a command
-    let should_run = app.should_run_command();
-    let command_to_run = if should_run {
-        app.last_response.clone()
+/// After the TUI exits, run or pipe the response if that action was chosen.
+/// With `offer_fix`, a command that fails can be handed back to ask for a fix.
+fn run_selected_action(app: &mut App, offer_fix: bool) -> Result<Option<RunFailure>> {
+    // Check if we need to run a command, as edited or as it came
+    let command_to_run = if let Some(edited) = app.edited_command.take() {
+        Some(edited)
+    } else if app.should_run_command() {
+        app.command_response()
+            .map(|response| format_response(&response, Destination::Run))
     } else {
         None
     };
 
-    // Restore terminal
-    restore_terminal(&mut terminal)?;
+    if app.should_open_man_page() {
+        if let Some(program) = app.man_page_program() {
+            open_man_page(&program);
+        }
+        return Ok(None);
+    }
+
+    // Pipe the response into a command if requested
+    if let Some(pipe_cmd) = app.pipe_command.take() {
+        if let Some(response) = app.command_response() {
+            pipe_response(&format_response(&response, Destination::Clipboard), &pipe_cmd)?;
+            return Ok(None);
+        }
+    }
 
     // Run command if requested (after exiting TUI)
     if let Some(cmd) = command_to_run {
+        if settings::is_enabled("show_response_before_run") {
+            print_response_record(app);
+        }
+
+        if provider::safe_mode() && provider::is_destructive(&cmd) {
+            println!("{}", banner(Banner::Failure, "Safe mode: not running a destructive command"));
+            println!();
+            println!("  {}", cmd);
+            println!();
+            println!("{}", banner(Banner::Dim, "Run it yourself if you are sure, or turn off safe_mode."));
+            return Ok(None);
+        }
+
         // Check if command contains special key notation
         if contains_special_keys(&cmd) {
-            println!("\x1b[1;36m📋 Key Sequence:\x1b[0m");
+            println!("{}", banner(Banner::Heading, "Key Sequence:"));
             println!();
             println!("  {}", format_key_sequence(&cmd));
             println!();
-            println!("\x1b[1;33mThis contains special keys that must be pressed manually.\x1b[0m");
+            println!(
+                "{}",
+                banner(Banner::Warning, "This contains special keys that must be pressed manually.")
+            );
             println!();
             print_key_legend(&cmd);
             
@@ -646,37 +2150,292 @@ pub fn run_interactive_mode() -> Result<()> {
                 let plain = strip_special_keys(&cmd);
                 if !plain.is_empty() {
                     clipboard.set_text(&plain).ok();
-                    println!("\x1b[32m✓ Text parts copied to clipboard\x1b[0m");
+                    println!("{}", banner(Banner::Success, "Text parts copied to clipboard"));
                 }
             }
         } else {
-            println!("\x1b[1;33m▶ Running:\x1b[0m {}", cmd);
+            if let Some(problem) = leading_program(&cmd).and_then(program_problem) {
+                println!("{}", banner(Banner::Warning, &problem));
+                if !confirm("Run anyway?") {
+                    println!("{}", banner(Banner::Dim, "Not run."));
+                    return Ok(None);
+                }
+                println!();
+            }
+
+            println!("{} {}", banner(Banner::Action, "Running:"), cmd);
             println!();
-            let status = Command::new("sh").arg("-c").arg(&cmd).status();
-            match status {
-                Ok(s) => {
+            let offer_fix = offer_fix && settings::is_enabled("offer_fix_on_failure") && io::stdin().is_terminal();
+            match run_shell_command(&cmd, offer_fix) {
+                Ok((s, stderr)) => {
                     println!();
-                    if s.success() {
-                        println!("\x1b[1;32m✓ Command completed successfully\x1b[0m");
-                    } else {
-                        println!(
-                            "\x1b[1;31m✗ Command exited with code {}\x1b[0m",
-                            s.code().unwrap_or(-1)
-                        );
+                    print_exit_status(s);
+                    // sh's codes for "found but can't execute" and "not found"
+                    let hint = match s.code() {
+                        Some(126) => Some("Permission denied: the file may need chmod +x, or the command may need sudo"),
+                        Some(127) => Some("Command not found: check the name, or install it first"),
+                        _ => None,
+                    };
+                    if let Some(hint) = hint {
+                        println!("{}", banner(Banner::Dim, hint));
+                    }
+                    // Not after Ctrl+C or another signal: the user stopped it
+                    if let Some(code) = s.code().filter(|&code| code != 0 && offer_fix) {
+                        println!();
+                        if confirm("Ask cmdk-rs to fix it?") {
+                            return Ok(Some(RunFailure { command: cmd, code, stderr }));
+                        }
                     }
                 }
                 Err(e) => {
-                    eprintln!("\x1b[1;31m✗ Failed to run command: {}\x1b[0m", e);
+                    let message = format!("Failed to run command: {}", e);
+                    eprintln!("{}", banner(Banner::Failure, &message));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Run a command with `sh -c`. With `capture_stderr`, its stderr is still shown
+/// as it is written, and the end of it is returned too.
+fn run_shell_command(cmd: &str, capture_stderr: bool) -> io::Result<(std::process::ExitStatus, String)> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    if !capture_stderr {
+        return command.status().map(|status| (status, String::new()));
+    }
+
+    let mut child = command.stderr(std::process::Stdio::piped()).spawn()?;
+    let reader = child.stderr.take().map(|mut err| {
+        thread::spawn(move || {
+            let mut captured = Vec::new();
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = err.read(&mut buf) {
+                if n == 0 {
+                    break;
                 }
+                let mut stderr = io::stderr();
+                stderr.write_all(&buf[..n]).ok();
+                stderr.flush().ok();
+                captured.extend_from_slice(&buf[..n]);
             }
+            captured
+        })
+    });
+    let status = child.wait()?;
+    let captured = reader.and_then(|h| h.join().ok()).unwrap_or_default();
+    let stderr = context::cap_bytes_tail(&String::from_utf8_lossy(&captured), FAILURE_STDERR_BYTES);
+    Ok((status, stderr))
+}
+
+/// Show `man program`, or `program --help` when there is no man page
+fn open_man_page(program: &str) {
+    if Command::new("man").arg(program).status().is_ok_and(|s| s.success()) {
+        return;
+    }
+    println!(
+        "{}",
+        banner(Banner::Dim, &format!("No man page for {}; showing {} --help", program, program))
+    );
+    println!();
+    if let Err(e) = Command::new(program).arg("--help").status() {
+        let message = format!("Failed to run {} --help: {}", program, e);
+        eprintln!("{}", banner(Banner::Failure, &message));
+    }
+}
+
+/// Run `pipe_cmd` in a shell with the response on its stdin, showing its output
+fn pipe_response(response: &str, pipe_cmd: &str) -> Result<()> {
+    println!("{} {}", banner(Banner::Action, "Piping to:"), pipe_cmd);
+    println!();
+
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(pipe_cmd)
+        .stdin(std::process::Stdio::piped())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            let message = format!("Failed to run command: {}", e);
+            eprintln!("{}", banner(Banner::Failure, &message));
+            return Ok(());
         }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that exits without reading stdin closes the pipe; that's fine
+        let _ = writeln!(stdin, "{}", response);
     }
 
+    let status = child.wait()?;
+    println!();
+    print_exit_status(status);
+
     Ok(())
 }
 
+/// Print whether a command run after the TUI succeeded
+fn print_exit_status(status: std::process::ExitStatus) {
+    if status.success() {
+        println!("{}", banner(Banner::Success, "Command completed successfully"));
+    } else {
+        let message = format!("Command exited with code {}", status.code().unwrap_or(-1));
+        println!("{}", banner(Banner::Failure, &message));
+    }
+}
+
+/// How much the CLI modes write to stderr (`-Q` / `-v`)
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Only the response on stdout and the exit code
+    Quiet,
+    Normal,
+    /// Also the provider, context size and timing
+    Verbose,
+}
+
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+/// Set the verbosity for this process
+pub fn set_verbosity(verbosity: Verbosity) {
+    let _ = VERBOSITY.set(verbosity);
+}
+
+pub fn verbosity() -> Verbosity {
+    VERBOSITY.get().copied().unwrap_or(Verbosity::Normal)
+}
+
+/// Print a diagnostic line on stderr with `-v`
+fn log_verbose(message: &str) {
+    if verbosity() == Verbosity::Verbose {
+        eprintln!("cmdk-rs: {}", message);
+    }
+}
+
+/// Whether output after the TUI exits should avoid ANSI colors and emoji,
+/// for logs and screen readers (`NO_COLOR` or the `plain_output` setting)
+fn plain_output() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+        || settings::is_enabled("plain_output")
+}
+
+/// Print the query and response so scrollback shows what the run came from
+fn print_response_record(app: &App) {
+    if let Some(query) = &app.last_query {
+        println!("{} {}", banner(Banner::Heading, "Asked:"), query);
+        println!();
+    }
+    if let Some(response) = &app.last_response {
+        for line in format_response(response, Destination::Display).lines() {
+            println!("  {}", line);
+        }
+        println!();
+    }
+}
+
+/// Kinds of status line printed around a run or pipe
+#[derive(Clone, Copy)]
+enum Banner {
+    Heading,
+    Action,
+    Success,
+    Failure,
+    Warning,
+    Dim,
+}
+
+/// Style a status line, with ASCII markers instead of colors and emoji in plain mode
+fn banner(kind: Banner, text: &str) -> String {
+    if plain_output() {
+        let marker = match kind {
+            Banner::Action => "> ",
+            Banner::Success => "[ok] ",
+            Banner::Failure => "[fail] ",
+            Banner::Heading | Banner::Warning | Banner::Dim => "",
+        };
+        return format!("{}{}", marker, text);
+    }
+    match kind {
+        Banner::Heading => format!("\x1b[1;36m📋 {}\x1b[0m", text),
+        Banner::Action => format!("\x1b[1;33m▶ {}\x1b[0m", text),
+        Banner::Success => format!("\x1b[1;32m✓ {}\x1b[0m", text),
+        Banner::Failure => format!("\x1b[1;31m✗ {}\x1b[0m", text),
+        Banner::Warning => format!("\x1b[1;33m{}\x1b[0m", text),
+        Banner::Dim => format!("\x1b[90m{}\x1b[0m", text),
+    }
+}
+
+/// Shell builtins and keywords, which are never looked up in PATH
+const SHELL_BUILTINS: &[&str] = &[
+    "cd", "export", "unset", "set", "source", ".", "alias", "eval", "exec", "exit", "read",
+    "test", "[", "[[", "if", "for", "while", "until", "case", "function", "time", "umask",
+    "ulimit", "type", "command", "builtin", "local", "declare", "pushd", "popd", "history",
+];
+
+/// The program a command starts with, skipping `VAR=value` assignments and `sudo`.
+/// None when it can't be told without a shell (builtins, variables, subshells).
+fn leading_program(cmd: &str) -> Option<&str> {
+    let line = cmd.lines().map(str::trim).find(|l| !l.is_empty() && !l.starts_with('#'))?;
+    let is_assignment = |word: &str| {
+        word.split_once('=').is_some_and(|(name, _)| {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+    };
+    line.split_whitespace()
+        .find(|word| !is_assignment(word) && *word != "sudo")
+        .filter(|word| word.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+/".contains(c)))
+        .filter(|word| !SHELL_BUILTINS.contains(word))
+}
+
+/// The first program in a command that has a man page to look for: builtins
+/// like the `cd` of `cd src && make` are skipped, and `/usr/bin/tar` is `tar`
+fn man_page_program(cmd: &str) -> Option<&str> {
+    let line = cmd.lines().map(str::trim).find(|l| !l.is_empty() && !l.starts_with('#'))?;
+    let program = split_at_operators(line)
+        .into_iter()
+        .find_map(|part| leading_program(part.trim_start_matches(['&', '|', ' '])))?;
+    Some(program.rsplit('/').next().unwrap_or(program))
+}
+
+/// Why `program` can't be run, if it isn't in PATH or isn't executable
+fn program_problem(program: &str) -> Option<String> {
+    if !program.contains('/') {
+        return capabilities::find_in_path(program)
+            .is_none()
+            .then(|| format!("Command `{}` not found in PATH", program));
+    }
+
+    let path = std::path::Path::new(program);
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Some(format!("`{}` does not exist", program)),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.is_file() && metadata.permissions().mode() & 0o111 == 0 {
+            return Some(format!("`{}` is not executable (chmod +x {})", program, program));
+        }
+    }
+    metadata.is_dir().then(|| format!("`{}` is a directory", program))
+}
+
+/// Ask a yes/no question on the terminal after the TUI has exited; no by default
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
 /// Check if a string contains special key notation
-fn contains_special_keys(s: &str) -> bool {
+pub fn contains_special_keys(s: &str) -> bool {
     let patterns = [
         "<Esc>", "<Enter>", "<CR>", "<Tab>", "<BS>", "<Del>",
         "<Up>", "<Down>", "<Left>", "<Right>", "<Space>",
@@ -689,6 +2448,9 @@ fn contains_special_keys(s: &str) -> bool {
 /// Format a key sequence for display with colors
 fn format_key_sequence(s: &str) -> String {
     let mut result = s.to_string();
+    if plain_output() {
+        return result;
+    }
     
     // Highlight special keys in cyan
     let keys = [
@@ -735,9 +2497,9 @@ fn print_key_legend(s: &str) {
     }
     
     if !legend.is_empty() {
-        println!("\x1b[90mKey Legend:\x1b[0m");
+        println!("{}", banner(Banner::Dim, "Key Legend:"));
         for item in legend {
-            println!("\x1b[90m{}\x1b[0m", item);
+            println!("{}", banner(Banner::Dim, item));
         }
         println!();
     }
@@ -786,6 +2548,11 @@ pub fn run_settings_mode() -> Result<()> {
     let event_handler = EventHandler::new(100);
 
     while app.running {
+        if let Some(signal) = event_handler.terminate_signal() {
+            exit_on_signal(&mut terminal, &mut app, signal);
+        }
+
+        app.check_warmup();
         terminal.draw(|f| ui::render(f, &app))?;
 
         if let Some(event) = event_handler.next()? {
@@ -802,19 +2569,253 @@ pub fn run_settings_mode() -> Result<()> {
     Ok(())
 }
 
-/// Run direct query mode (non-interactive)
-pub fn run_query_mode(query: &str) -> Result<()> {
+/// Prompt for a direct query, and how many characters of context it carries
+fn build_query_prompt(
+    query: &str,
+    piped_context: Option<&str>,
+    attachments: &[Attachment],
+) -> Result<(String, usize)> {
     // Get context
-    let ctx = context::gather_context()?;
+    let mut terminal_ctx = context::gather_context()?;
+    if let Some(input) = piped_context {
+        terminal_ctx.push_piped_input(input);
+    }
+
+    // Images go to providers that support attachments with the prompt; the
+    // rest are inlined (or referenced) in context
+    if !attachments.is_empty() {
+        let provider = provider::get_available_provider()?;
+        for attachment in attachments {
+            if provider.supports_attachments() && attachment.is_image() {
+                continue;
+            }
+            if attachment.text().is_none() && verbosity() > Verbosity::Quiet {
+                eprintln!(
+                    "Warning: {} can't receive {} files; sending only the path of {}",
+                    provider, attachment.mime, attachment.path
+                );
+            }
+            terminal_ctx.push_attachment(attachment);
+        }
+    }
+    let ctx = terminal_ctx.to_markdown();
+    log_verbose(&format!(
+        "context: {} in {} section(s)",
+        context::format_size(ctx.len()),
+        terminal_ctx.sections.len()
+    ));
 
     // Build prompt
-    let full_prompt = provider::build_full_prompt(query, &ctx, None);
+    // Direct queries don't continue the session, but do use a --replay recap
+    let recap = session::fresh_session_history();
+    let prompt = provider::build_full_prompt(query, &ctx, recap.as_deref(), QueryMode::from_settings());
+    Ok((prompt, ctx.chars().count()))
+}
+
+/// What `--json` prints for a direct query. Fields may be added but are never
+/// renamed or removed, so scripts can rely on them.
+#[derive(Debug, Serialize)]
+struct QueryOutput<'a> {
+    query: &'a str,
+    response: &'a str,
+    /// Provider that answered, e.g. "claude" or "custom"; a fallback if the first one failed
+    provider: &'a str,
+    context_chars: usize,
+    duration_ms: u128,
+}
+
+/// Run direct query mode (non-interactive); `json` prints the response with
+/// its metadata as a JSON object instead of as plain text
+pub fn run_query_mode(
+    query: &str,
+    piped_context: Option<&str>,
+    attachments: &[Attachment],
+    repro_file: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let (full_prompt, context_chars) = build_query_prompt(query, piped_context, attachments)?;
+
+    if verbosity() == Verbosity::Verbose {
+        let provider = provider::get_available_provider()?;
+        match provider::provider_command(&provider) {
+            Ok(command) => log_verbose(&format!("provider: {} ({})", provider, command)),
+            // HTTP providers have no command line
+            Err(_) => log_verbose(&format!("provider: {}", provider)),
+        }
+        log_verbose(&format!("prompt: {}", context::format_size(full_prompt.len())));
+    }
 
     // Run query
-    let response = provider::run_query(&full_prompt)?;
+    let started = Instant::now();
+    let (response, provider) =
+        provider::run_query_with_attachments(&full_prompt, QueryMode::from_settings(), attachments)?;
+    log_verbose(&format!("response in {:.1}s", started.elapsed().as_secs_f64()));
+
+    if let Some(path) = repro_file {
+        let repro = session::format_repro(&full_prompt, &response, provider);
+        std::fs::write(path, repro)
+            .with_context(|| format!("Failed to write reproduction: {}", path))?;
+    }
+
+    if json {
+        let output = QueryOutput {
+            query,
+            response: &response,
+            provider,
+            context_chars,
+            duration_ms: started.elapsed().as_millis(),
+        };
+        return print_stdout(&serde_json::to_string(&output)?);
+    }
 
     // Print response
-    println!("{}", response);
+    print_stdout(&response)
+}
 
-    Ok(())
+/// Run a direct query, then show just the response and its actions in a small TUI
+pub fn run_interactive_result_mode(
+    query: &str,
+    piped_context: Option<&str>,
+    attachments: &[Attachment],
+) -> Result<()> {
+    let (full_prompt, _) = build_query_prompt(query, piped_context, attachments)?;
+
+    if verbosity() > Verbosity::Quiet {
+        eprintln!("Thinking...");
+    }
+    let (response, _) = provider::run_query_with_attachments(&full_prompt, QueryMode::from_settings(), attachments)?;
+
+    let mut app = App::new()?;
+    app.last_query = Some(query.to_string());
+    app.last_response = Some(response.clone());
+    app.result_actions = vec![
+        ResultAction::RunCommand,
+        ResultAction::EditAndRun,
+        ResultAction::CopyToClipboard,
+        ResultAction::Quit,
+    ];
+    app.show_last_response();
+    app.offer_command_actions();
+    app.auto_copy(&response);
+
+    let mut terminal = setup_terminal()?;
+    let event_handler = EventHandler::new(100);
+
+    while app.running {
+        if let Some(signal) = event_handler.terminate_signal() {
+            exit_on_signal(&mut terminal, &mut app, signal);
+        }
+
+        if app.query_in_flight() {
+            app.check_query_complete()?;
+            app.tick_spinner();
+        }
+
+        terminal.draw(|f| ui::render(f, &app))?;
+
+        if let Some(event) = event_handler.next()? {
+            app.handle_key(event)?;
+        }
+
+        // There is no menu to go back to
+        if let AppState::MainMenu = app.state {
+            break;
+        }
+    }
+
+    restore_terminal(&mut terminal)?;
+    // The command runs with the default signal handling
+    drop(event_handler);
+
+    run_selected_action(&mut app, false).map(|_| ())
+}
+
+/// Print text to stdout, treating a closed pipe (e.g. `| head`) as success
+pub fn print_stdout(text: &str) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    match writeln!(stdout, "{}", text).and_then(|_| stdout.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leading_program() {
+        assert_eq!(leading_program("ls -la | grep foo"), Some("ls"));
+        assert_eq!(leading_program("RUST_LOG=debug sudo cargo run"), Some("cargo"));
+        assert_eq!(leading_program("# list\n./deploy.sh --dry-run"), Some("./deploy.sh"));
+        assert_eq!(leading_program("cd /tmp && make"), None);
+        assert_eq!(leading_program("$EDITOR notes.txt"), None);
+        assert_eq!(leading_program("(cd src; ls)"), None);
+        assert_eq!(man_page_program("cd /tmp && sudo /usr/bin/tar -xzvf a.tgz | less"), Some("tar"));
+        assert_eq!(man_page_program("export X=1; cd $X"), None);
+    }
+
+    #[test]
+    fn test_edit_text_multibyte() {
+        let mut input = String::new();
+        let mut cursor = 0;
+        for c in "café".chars() {
+            edit_text(&mut input, &mut cursor, &KeyAction::Char(c));
+        }
+        edit_text(&mut input, &mut cursor, &KeyAction::Char('!'));
+        assert_eq!((input.as_str(), cursor), ("café!", 6));
+        edit_text(&mut input, &mut cursor, &KeyAction::Left);
+        edit_text(&mut input, &mut cursor, &KeyAction::Left);
+        assert_eq!(cursor, 3);
+        edit_text(&mut input, &mut cursor, &KeyAction::Delete);
+        assert_eq!(input, "caf!");
+        edit_text(&mut input, &mut cursor, &KeyAction::Char('ü'));
+        edit_text(&mut input, &mut cursor, &KeyAction::Right);
+        assert_eq!((input.as_str(), cursor), ("cafü!", 6));
+        edit_text(&mut input, &mut cursor, &KeyAction::Left);
+        edit_text(&mut input, &mut cursor, &KeyAction::Backspace);
+        assert_eq!((input.as_str(), cursor), ("caf!", 3));
+
+        // A pre-filled input puts the cursor at its end, in bytes
+        let mut input = "日本".to_string();
+        let mut cursor = input.len();
+        edit_text(&mut input, &mut cursor, &KeyAction::Backspace);
+        edit_text(&mut input, &mut cursor, &KeyAction::Left);
+        edit_text(&mut input, &mut cursor, &KeyAction::Left);
+        assert_eq!((input.as_str(), cursor), ("日", 0));
+    }
+
+    #[test]
+    fn test_multiline_cursor() {
+        let input = "explain this:\nfn main() {}\n";
+        assert_eq!(cursor_row_col(input, 0), (0, 0));
+        assert_eq!(cursor_row_col(input, 16), (1, 2));
+        assert_eq!(cursor_row_col(input, input.len()), (2, 0));
+        // Moving up from a long line lands at the end of a shorter one
+        assert_eq!(row_col_offset("ab\nlonger", 0, 5), Some(2));
+        assert_eq!(row_col_offset("ab\nlonger", 1, 3), Some(6));
+        assert_eq!(row_col_offset("ab\nlonger", 2, 0), None);
+        // Columns count what the terminal shows, so wide and multibyte characters line up
+        let input = "日本語\ncafé au lait";
+        assert_eq!(cursor_row_col(input, 6), (0, 4));
+        assert_eq!(cursor_row_col(input, input.len()), (1, 12));
+        assert_eq!(row_col_offset(input, 1, 4), Some(15));
+        assert_eq!(row_col_offset(input, 0, 3), Some(3));
+        assert_eq!(row_col_offset(input, 0, 12), Some(9));
+    }
+
+    #[test]
+    fn test_query_output_schema() {
+        let output = QueryOutput {
+            query: "list files",
+            response: "ls -la",
+            provider: "claude",
+            context_chars: 120,
+            duration_ms: 900,
+        };
+        assert_eq!(
+            serde_json::to_string(&output).unwrap(),
+            r#"{"query":"list files","response":"ls -la","provider":"claude","context_chars":120,"duration_ms":900}"#
+        );
+    }
 }
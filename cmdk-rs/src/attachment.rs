@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::fs;
+use std::path::Path;
+
+/// A file passed with `--attach`
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub path: String,
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    /// Read a file and guess its type from the extension and contents
+    pub fn load(path: &str) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read attachment: {}", path))?;
+        let mime = guess_mime(path, &data);
+        Ok(Self {
+            path: path.to_string(),
+            mime,
+            data,
+        })
+    }
+
+    /// File contents if this is a text file
+    pub fn text(&self) -> Option<&str> {
+        if self.mime == "text/plain" {
+            std::str::from_utf8(&self.data).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Whether this is an image, which vision models can be sent directly
+    pub fn is_image(&self) -> bool {
+        self.mime.starts_with("image/")
+    }
+
+    /// The file as a `data:` URL, the way OpenAI-compatible APIs take images
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime, BASE64_STANDARD.encode(&self.data))
+    }
+}
+
+/// Image types by extension; anything else is text if it decodes as UTF-8
+fn guess_mime(path: &str, data: &[u8]) -> &'static str {
+    let ext = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ if std::str::from_utf8(data).is_ok() => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
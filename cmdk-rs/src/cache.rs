@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::provider::QueryMode;
+use crate::settings;
+
+/// A cached response (`response_cache=true`), one JSON file per entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    #[serde(skip)]
+    pub key: String,
+    pub query: String,
+    pub response: String,
+    /// When the response was stored, in Unix seconds
+    pub created: u64,
+}
+
+impl CacheEntry {
+    /// Seconds since the response was stored
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.created)
+    }
+
+    /// Size of the response in bytes
+    pub fn size(&self) -> usize {
+        self.response.len()
+    }
+}
+
+/// Cache directory inside the command-k directory
+pub fn get_cache_dir() -> PathBuf {
+    settings::get_command_k_dir().join("cache")
+}
+
+/// Cache key for a question. The terminal context is deliberately left out,
+/// since it changes with every command run; the conversation so far is not.
+pub fn key(provider: &str, mode: QueryMode, query: &str, history: Option<&str>) -> String {
+    let mut hasher = Md5::new();
+    for part in [provider, &format!("{:?}", mode), query.trim(), history.unwrap_or("")] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Cached entry for a key, unless it is older than `response_cache_ttl_secs`
+pub fn get(key: &str) -> Option<CacheEntry> {
+    let entry = read_entry(key)?;
+    let ttl = settings::get_number("response_cache_ttl_secs") as u64;
+    (ttl == 0 || entry.age() < ttl).then_some(entry)
+}
+
+pub fn put(key: &str, query: &str, response: &str) -> Result<()> {
+    let dir = get_cache_dir();
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    let entry = CacheEntry {
+        key: key.to_string(),
+        query: query.to_string(),
+        response: response.to_string(),
+        created: now(),
+    };
+    let path = dir.join(format!("{}.json", key));
+    fs::write(&path, serde_json::to_string(&entry)?)
+        .with_context(|| format!("Failed to write cache entry: {:?}", path))
+}
+
+/// All cached responses, newest first
+pub fn entries() -> Vec<CacheEntry> {
+    let Ok(dir) = fs::read_dir(get_cache_dir()) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<CacheEntry> = dir
+        .filter_map(|e| e.ok())
+        .filter_map(|e| read_entry(e.file_name().to_str()?.strip_suffix(".json")?))
+        .collect();
+    entries.sort_by_key(|e| std::cmp::Reverse(e.created));
+    entries
+}
+
+pub fn remove(key: &str) -> Result<()> {
+    let path = get_cache_dir().join(format!("{}.json", key));
+    fs::remove_file(&path).with_context(|| format!("Failed to remove cache entry: {:?}", path))
+}
+
+/// Remove every cached response; returns how many there were
+pub fn clear() -> Result<usize> {
+    let entries = entries();
+    for entry in &entries {
+        remove(&entry.key)?;
+    }
+    Ok(entries.len())
+}
+
+fn read_entry(key: &str) -> Option<CacheEntry> {
+    let content = fs::read_to_string(get_cache_dir().join(format!("{}.json", key))).ok()?;
+    let mut entry: CacheEntry = serde_json::from_str(&content).ok()?;
+    entry.key = key.to_string();
+    Some(entry)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let a = key("claude", QueryMode::Command, "list files", None);
+        assert_eq!(a, key("claude", QueryMode::Command, " list files\n", None));
+        assert_ne!(a, key("claude", QueryMode::Explain, "list files", None));
+        assert_ne!(a, key("codex", QueryMode::Command, "list files", None));
+        assert_ne!(a, key("claude", QueryMode::Command, "list files", Some("## User: hi\n")));
+    }
+}
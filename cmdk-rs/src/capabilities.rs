@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use crate::settings;
+
+/// File caching which flags each provider CLI supports
+fn get_cache_file() -> PathBuf {
+    settings::get_command_k_dir().join("provider_caps")
+}
+
+/// Find an executable in PATH
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Which of `wanted` the CLI's help output mentions.
+/// Returns `None` if the probe was inconclusive (the command failed or printed no usage text),
+/// in which case callers should use their usual invocation.
+pub fn supported_flags(program: &str, help_args: &[&str], wanted: &[&str]) -> Option<Vec<String>> {
+    let path = find_in_path(program)?;
+    let stamp = binary_stamp(&path)?;
+    let key = format!("{} {} {}", program, help_args.join(" "), wanted.join(","));
+
+    if let Some(flags) = read_cached(&key, &path, stamp) {
+        return Some(flags);
+    }
+
+    let output = Command::new(&path)
+        .args(help_args)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    let help = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let lower = help.to_lowercase();
+    if !output.status.success() || !(lower.contains("usage") || lower.contains("options")) {
+        return None;
+    }
+
+    let flags: Vec<String> = wanted
+        .iter()
+        .filter(|flag| mentions_flag(&help, flag))
+        .map(|flag| flag.to_string())
+        .collect();
+
+    write_cached(&key, &path, stamp, &flags);
+    Some(flags)
+}
+
+/// Pick the first supported flag from `candidates`, or explain that the CLI needs updating
+pub fn require_flag(program: &str, help_args: &[&str], candidates: &[&str]) -> Result<String> {
+    match supported_flags(program, help_args, candidates) {
+        None => Ok(candidates[0].to_string()),
+        Some(flags) => flags.into_iter().next().ok_or_else(|| {
+            anyhow!(
+                "your {} version doesn't support {}; please update it",
+                program,
+                candidates[0]
+            )
+        }),
+    }
+}
+
+/// Check for a flag as a whole word, so `-o` doesn't match inside `--output`
+fn mentions_flag(help: &str, flag: &str) -> bool {
+    help.match_indices(flag).any(|(i, _)| {
+        let before = help[..i].chars().last();
+        let after = help[i + flag.len()..].chars().next();
+        !matches!(before, Some(c) if c == '-' || c.is_alphanumeric())
+            && !matches!(after, Some(c) if c == '-' || c.is_alphanumeric())
+    })
+}
+
+/// Modification time of the binary, so an upgrade invalidates the cache
+fn binary_stamp(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Cache lines look like `<key>\t<binary path>\t<mtime>\t<flag,flag>`
+fn read_cached(key: &str, path: &Path, stamp: u64) -> Option<Vec<String>> {
+    let content = fs::read_to_string(get_cache_file()).ok()?;
+    content.lines().find_map(|line| {
+        let mut fields = line.split('\t');
+        let (k, p, s, flags) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+        if k == key && p == path.to_string_lossy() && s.parse() == Ok(stamp) {
+            Some(flags.split(',').filter(|f| !f.is_empty()).map(String::from).collect())
+        } else {
+            None
+        }
+    })
+}
+
+fn write_cached(key: &str, path: &Path, stamp: u64, flags: &[String]) {
+    let file = get_cache_file();
+    let mut lines: Vec<String> = fs::read_to_string(&file)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| line.split('\t').next() != Some(key))
+        .map(String::from)
+        .collect();
+    lines.push(format!(
+        "{}\t{}\t{}\t{}",
+        key,
+        path.to_string_lossy(),
+        stamp,
+        flags.join(",")
+    ));
+
+    let _ = fs::create_dir_all(settings::get_command_k_dir());
+    let _ = fs::write(&file, lines.join("\n") + "\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mentions_flag() {
+        let help = "Usage: codex exec [OPTIONS]\n  -o, --output-last-message <FILE>\n  --skip-git-repo-check";
+        assert!(mentions_flag(help, "-o"));
+        assert!(mentions_flag(help, "--skip-git-repo-check"));
+        assert!(!mentions_flag(help, "--output"));
+        assert!(!mentions_flag(help, "--print"));
+    }
+}
@@ -1,66 +1,423 @@
 use anyhow::Result;
 use std::env;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use crate::attachment::Attachment;
+use crate::ignore::IgnoreRules;
+use crate::infra;
+use crate::manifest::{self, ManifestEntry};
 use crate::settings;
 
+/// A named part of the gathered context
+#[derive(Debug, Clone)]
+pub struct ContextSection {
+    pub name: &'static str,
+    /// The raw value, used for display
+    pub raw: String,
+    /// The markdown sent to the AI
+    pub markdown: String,
+}
+
+impl ContextSection {
+    /// Size of this section in the prompt, in bytes
+    pub fn size(&self) -> usize {
+        self.markdown.len()
+    }
+}
+
+/// Terminal context split into sections so each one's contribution can be inspected
+#[derive(Debug, Clone, Default)]
+pub struct TerminalContext {
+    pub sections: Vec<ContextSection>,
+}
+
+impl TerminalContext {
+    /// Render the context as markdown for the AI prompt
+    pub fn to_markdown(&self) -> String {
+        let mut context = String::from("## Terminal Context\n\n");
+        for section in &self.sections {
+            context.push_str(&section.markdown);
+        }
+        context
+    }
+
+    /// Total size of the rendered context in bytes
+    pub fn total_size(&self) -> usize {
+        self.to_markdown().len()
+    }
+
+    /// Add data piped in on stdin as its own section
+    pub fn push_piped_input(&mut self, input: &str) {
+        let markdown = format!("\n### Piped Input\n```\n{}\n```\n", input);
+        self.push("Piped Input", input.to_string(), markdown);
+    }
+
+    /// Add a command that failed when it was run, with its exit code and error output
+    pub fn push_failed_command(&mut self, command: &str, code: i32, stderr: &str) {
+        let mut markdown = format!("\n### Failed Command (exit code {})\n```\n{}\n```\n", code, command);
+        if !stderr.trim().is_empty() {
+            markdown.push_str(&format!("Error output:\n```\n{}\n```\n", stderr.trim_end()));
+        }
+        self.push("Failed Command", command.to_string(), markdown);
+    }
+
+    /// Add an attached file inline, for providers that can't receive files directly.
+    /// Text files are included in full; other files are referenced by path only.
+    pub fn push_attachment(&mut self, attachment: &Attachment) {
+        let markdown = match attachment.text() {
+            Some(text) => format!("\n### Attached File: {}\n```\n{}\n```\n", attachment.path, text),
+            None => format!(
+                "\n### Attached File: {}\n({}, {}; contents not included)\n",
+                attachment.path,
+                attachment.mime,
+                format_size(attachment.data.len())
+            ),
+        };
+        self.push("Attachment", attachment.path.clone(), markdown);
+    }
+
+    fn push(&mut self, name: &'static str, raw: String, markdown: String) {
+        self.sections.push(ContextSection { name, raw, markdown });
+    }
+}
+
+impl fmt::Display for TerminalContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_markdown())
+    }
+}
+
+/// Format a byte count for display (e.g. "88 B", "4.1 KB")
+pub fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    }
+}
+
+/// Cap text at `max` bytes (0 = no limit), keeping the start
+pub fn cap_bytes(text: &str, max: usize) -> String {
+    if max == 0 || text.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...\n(truncated)", &text[..end])
+}
+
+/// Cap text at `max` bytes (0 = no limit), keeping the end
+pub fn cap_bytes_tail(text: &str, max: usize) -> String {
+    if max == 0 || text.len() <= max {
+        return text.to_string();
+    }
+    let mut start = text.len() - max;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("(truncated)\n...{}", &text[start..])
+}
+
+/// Everything context is read from, so it can be gathered from fixed inputs in tests
+pub trait ContextSources {
+    /// Environment variables
+    fn vars(&self) -> Vec<(String, String)>;
+    fn cwd(&self) -> Option<PathBuf>;
+    fn home(&self) -> Option<PathBuf>;
+    fn terminal_size(&self) -> Option<(u16, u16)>;
+    /// Stdout of `git <args>` in the working directory, if it succeeded
+    fn git(&self, args: &[&str]) -> Option<String>;
+    /// Contents of the shell history files, most preferred first
+    fn history_files(&self) -> Vec<String>;
+    /// Rules of the working directory's `.command-k-ignore`
+    fn ignore_rules(&self) -> IgnoreRules;
+    /// Outline of the working directory's Terraform/Kustomize/Helm files
+    fn infra_summary(&self, ignore: &IgnoreRules) -> Option<String>;
+    /// Entries of the working directory's `.command-k-context`
+    fn manifest(&self) -> Vec<ManifestEntry>;
+    /// What a manifest entry reads or prints; `None` for an include outside the working directory
+    fn manifest_output(&self, entry: &ManifestEntry) -> Option<String>;
+
+    fn var(&self, key: &str) -> Option<String> {
+        self.vars().into_iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+/// The environment, git and history files of this process
+pub struct ProcessSources;
+
+impl ContextSources for ProcessSources {
+    fn vars(&self) -> Vec<(String, String)> {
+        env::vars().collect()
+    }
+
+    fn cwd(&self) -> Option<PathBuf> {
+        env::current_dir().ok()
+    }
+
+    fn home(&self) -> Option<PathBuf> {
+        dirs::home_dir()
+    }
+
+    fn terminal_size(&self) -> Option<(u16, u16)> {
+        crossterm::terminal::size().ok()
+    }
+
+    fn git(&self, args: &[&str]) -> Option<String> {
+        let output = Command::new("git")
+            .args(args)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn history_files(&self) -> Vec<String> {
+        let Some(home) = self.home() else {
+            return Vec::new();
+        };
+        let fish = self
+            .var("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".local/share"))
+            .join("fish/fish_history");
+        // zsh history first, then bash, then fish; fish first when it is the shell
+        let mut paths = vec![home.join(".zsh_history"), home.join(".bash_history")];
+        if self.var("SHELL").is_some_and(|shell| shell.ends_with("fish")) {
+            paths.insert(0, fish);
+        } else {
+            paths.push(fish);
+        }
+        paths.iter().filter_map(|path| fs::read_to_string(path).ok()).collect()
+    }
+
+    fn ignore_rules(&self) -> IgnoreRules {
+        IgnoreRules::load_in(&self.cwd().unwrap_or_else(|| PathBuf::from(".")))
+    }
+
+    fn infra_summary(&self, ignore: &IgnoreRules) -> Option<String> {
+        infra::summarize(&self.cwd()?, ignore)
+    }
+
+    fn manifest(&self) -> Vec<ManifestEntry> {
+        self.cwd().map(|cwd| manifest::load_in(&cwd)).unwrap_or_default()
+    }
+
+    fn manifest_output(&self, entry: &ManifestEntry) -> Option<String> {
+        entry.output(&self.cwd().unwrap_or_else(|| PathBuf::from(".")))
+    }
+}
+
+/// Fixed context inputs for tests; git commands answer from `git`, keyed by
+/// their joined arguments, and fail if missing. Nothing is read from `cwd`.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct FakeSources {
+    pub vars: Vec<(String, String)>,
+    pub cwd: Option<PathBuf>,
+    pub home: Option<PathBuf>,
+    pub terminal_size: Option<(u16, u16)>,
+    pub git: Vec<(String, String)>,
+    pub history: Vec<String>,
+    /// Contents of the ignore file
+    pub ignore: String,
+    pub infra: Option<String>,
+    /// Manifest entries with what each reads or prints
+    pub manifest: Vec<(ManifestEntry, Option<String>)>,
+}
+
+#[cfg(test)]
+impl ContextSources for FakeSources {
+    fn vars(&self) -> Vec<(String, String)> {
+        self.vars.clone()
+    }
+
+    fn cwd(&self) -> Option<PathBuf> {
+        self.cwd.clone()
+    }
+
+    fn home(&self) -> Option<PathBuf> {
+        self.home.clone()
+    }
+
+    fn terminal_size(&self) -> Option<(u16, u16)> {
+        self.terminal_size
+    }
+
+    fn git(&self, args: &[&str]) -> Option<String> {
+        let args = args.join(" ");
+        self.git.iter().find(|(a, _)| *a == args).map(|(_, out)| out.clone())
+    }
+
+    fn history_files(&self) -> Vec<String> {
+        self.history.clone()
+    }
+
+    fn ignore_rules(&self) -> IgnoreRules {
+        IgnoreRules::parse(&self.ignore)
+    }
+
+    fn infra_summary(&self, _ignore: &IgnoreRules) -> Option<String> {
+        self.infra.clone()
+    }
+
+    fn manifest(&self) -> Vec<ManifestEntry> {
+        self.manifest.iter().map(|(entry, _)| entry.clone()).collect()
+    }
+
+    fn manifest_output(&self, entry: &ManifestEntry) -> Option<String> {
+        self.manifest.iter().find(|(e, _)| e == entry).and_then(|(_, output)| output.clone())
+    }
+}
+
 /// Gather terminal context based on privacy settings
-pub fn gather_context() -> Result<String> {
+pub fn gather_context() -> Result<TerminalContext> {
+    gather_context_from(&ProcessSources)
+}
+
+/// Gather context from the given sources based on privacy settings
+pub fn gather_context_from(sources: &dyn ContextSources) -> Result<TerminalContext> {
     settings::init_settings()?;
 
-    let mut context = String::new();
-    context.push_str("## Terminal Context\n\n");
+    let mut context = TerminalContext::default();
+    let paths = PathDisplay::new(sources);
+    let privacy_mode = settings::privacy_mode();
 
     // Shell type
-    if settings::is_enabled("send_shell_type") {
-        if let Ok(shell) = env::var("SHELL") {
+    if settings::sends("send_shell_type") {
+        if let Some(shell) = sources.var("SHELL") {
             let shell_name = PathBuf::from(&shell)
                 .file_name()
                 .map(|s| s.to_string_lossy().to_string())
                 .unwrap_or_else(|| shell.clone());
-            context.push_str(&format!("**Shell:** {}\n", shell_name));
+            let markdown = format!("**Shell:** {}\n", shell_name);
+            context.push("Shell", shell_name, markdown);
         }
     }
 
+    // `privacy_mode=minimal` sends the OS in place of everything else
+    if privacy_mode == "minimal" {
+        let os = env::consts::OS.to_string();
+        let markdown = format!("**OS:** {}\n", os);
+        context.push("OS", os, markdown);
+    }
+
     // Working directory
-    if settings::is_enabled("send_working_dir") {
-        if let Ok(cwd) = env::current_dir() {
-            context.push_str(&format!("**Working Directory:** {}\n", cwd.display()));
+    if settings::sends("send_working_dir") {
+        if let Some(cwd) = sources.cwd() {
+            let cwd = paths.show(&cwd.display().to_string());
+            let markdown = format!("**Working Directory:** {}\n", cwd);
+            context.push("Working Directory", cwd, markdown);
         }
     }
 
     // Terminal size
-    if settings::is_enabled("send_terminal_size") {
-        if let Ok((cols, rows)) = crossterm::terminal::size() {
-            context.push_str(&format!("**Terminal Size:** {}x{}\n", cols, rows));
+    if settings::sends("send_terminal_size") {
+        if let Some((cols, rows)) = sources.terminal_size() {
+            let size = format!("{}x{}", cols, rows);
+            let markdown = format!("**Terminal Size:** {}\n", size);
+            context.push("Terminal Size", size, markdown);
+        }
+    }
+
+    // Exit code of the previous command, exported by a shell hook as $CMDK_LAST_EXIT
+    if settings::sends("send_last_exit") {
+        if let Some(code) = get_last_exit_code(sources) {
+            let markdown = format!("**Last Command:** exited with code {}\n", code);
+            context.push("Last Exit Code", code.to_string(), markdown);
+        }
+    }
+
+    // Previous directory and directory stack
+    if settings::sends("send_working_dir") {
+        if let Some(dirs) = get_directory_history(sources, &paths) {
+            let markdown = format!("\n### Directory History\n{}\n", dirs);
+            context.push("Directory History", dirs, markdown);
         }
     }
 
     // Environment variable names (not values)
-    if settings::is_enabled("send_env_var_names") {
-        let mut env_names: Vec<String> = env::vars().map(|(k, _)| k).collect();
+    if settings::sends("send_env_var_names") {
+        let mut env_names: Vec<String> = sources.vars().into_iter().map(|(k, _)| k).collect();
         env_names.sort();
-        context.push_str("\n### Environment Variables (names only)\n```\n");
-        context.push_str(&env_names.join(" "));
-        context.push_str("\n```\n");
+        let names = env_names.join(" ");
+        let markdown = format!(
+            "\n### Environment Variables (names only)\n```\n{}\n```\n",
+            names
+        );
+        context.push("Environment Variables", names, markdown);
     }
 
     // Git status
-    if settings::is_enabled("send_git_status") {
-        if let Some(git_info) = get_git_status() {
-            context.push_str("\n### Git Status\n");
-            context.push_str(&git_info);
+    if settings::sends("send_git_status") {
+        if let Some(git_info) = get_git_status(sources) {
+            let git_info = cap_bytes(&git_info, settings::get_number("max_git_bytes"));
+            let markdown = format!("\n### Git Status\n{}", git_info);
+            context.push("Git Status", git_info, markdown);
+        }
+    }
+
+    // Git diff, for commit messages and reviews
+    if settings::sends("send_git_diff") {
+        if let Some(diff) = get_git_diff(sources) {
+            let diff = cap_bytes(&diff, settings::get_number("max_git_diff_bytes"));
+            let markdown = format!("\n### Git Diff\n```diff\n{}\n```\n", diff);
+            context.push("Git Diff", diff, markdown);
         }
     }
 
     // Shell history
-    if settings::is_enabled("send_shell_history") {
-        if let Some(history) = get_shell_history() {
-            context.push_str("\n### Recent Shell History\n```\n");
-            context.push_str(&history);
-            context.push_str("\n```\n");
+    if settings::sends("send_shell_history") {
+        if let Some(history) = get_shell_history(sources) {
+            let history = cap_bytes_tail(&history, settings::get_number("max_history_bytes"));
+            let markdown = format!("\n### Recent Shell History\n```\n{}\n```\n", history);
+            context.push("Shell History", history, markdown);
+        }
+    }
+
+    // Outline of Terraform/Kustomize/Helm files in the working directory
+    if settings::sends("send_infra_summary") {
+        if let Some(summary) = sources.infra_summary(&sources.ignore_rules()) {
+            let summary = cap_bytes(summary.trim_end(), settings::get_number("max_infra_bytes"));
+            let markdown = format!("\n### Infrastructure Files\n```\n{}\n```\n", summary);
+            context.push("Infrastructure Files", summary, markdown);
+        }
+    }
+
+    // Files and command output listed in .command-k-context
+    let manifest = if privacy_mode == "full" { sources.manifest() } else { Vec::new() };
+    if !manifest.is_empty() {
+        let ignore = sources.ignore_rules();
+        let allow_commands = settings::is_enabled("allow_manifest_commands");
+        let allow_includes = settings::is_enabled("allow_manifest_includes");
+        let max_bytes = settings::get_number("max_manifest_bytes");
+        for entry in &manifest {
+            let label = entry.label().to_string();
+            // Skipped entries still show in the context view, but add nothing to the prompt
+            let output = match entry {
+                _ if entry.touches_ignored(&ignore) => Err("redacted"),
+                ManifestEntry::Command(_) if !allow_commands => Err("skipped: allow_manifest_commands is off"),
+                ManifestEntry::Include(_) if !allow_includes => Err("skipped: allow_manifest_includes is off"),
+                _ => sources.manifest_output(entry).ok_or("skipped: outside the project"),
+            };
+            let output = match output {
+                Ok(output) => output,
+                Err(reason) => {
+                    context.push("Project Context", format!("{} [{}]", label, reason), String::new());
+                    continue;
+                }
+            };
+            let output = cap_bytes(output.trim_end(), max_bytes);
+            let markdown = format!("\n### {}\n```\n{}\n```\n", label, output);
+            context.push("Project Context", label, markdown);
         }
     }
 
@@ -68,43 +425,30 @@ pub fn gather_context() -> Result<String> {
 }
 
 /// Get git status if in a git repository
-fn get_git_status() -> Option<String> {
+fn get_git_status(sources: &dyn ContextSources) -> Option<String> {
     // Check if we're in a git repo
-    let git_dir = Command::new("git")
-        .args(["rev-parse", "--git-dir"])
-        .output()
-        .ok()?;
-
-    if !git_dir.status.success() {
-        return None;
-    }
+    sources.git(&["rev-parse", "--git-dir"])?;
 
     let mut result = String::new();
 
     // Get current branch
-    if let Ok(output) = Command::new("git")
-        .args(["branch", "--show-current"])
-        .output()
-    {
-        if output.status.success() {
-            let branch = String::from_utf8_lossy(&output.stdout).trim().to_string();
-            if !branch.is_empty() {
-                result.push_str(&format!("Branch: {}\n", branch));
-            }
+    if let Some(branch) = sources.git(&["branch", "--show-current"]) {
+        let branch = branch.trim();
+        if !branch.is_empty() {
+            result.push_str(&format!("Branch: {}\n", branch));
         }
     }
 
     // Get modified files (short status)
-    if let Ok(output) = Command::new("git").args(["status", "--short"]).output() {
-        if output.status.success() {
-            let status = String::from_utf8_lossy(&output.stdout);
-            let lines: Vec<&str> = status.lines().take(10).collect();
-            if !lines.is_empty() {
-                result.push_str("Modified files:\n");
-                for line in lines {
-                    result.push_str(line);
-                    result.push('\n');
-                }
+    if let Some(status) = sources.git(&["status", "--short"]) {
+        let max_lines = settings::get_number("max_git_lines");
+        let lines: Vec<&str> = status.lines().take(max_lines).collect();
+        if !lines.is_empty() {
+            let ignore = sources.ignore_rules();
+            result.push_str("Modified files:\n");
+            for line in lines {
+                result.push_str(&redact_status_line(line, &ignore));
+                result.push('\n');
             }
         }
     }
@@ -116,99 +460,436 @@ fn get_git_status() -> Option<String> {
     }
 }
 
-/// Get recent shell history
-fn get_shell_history() -> Option<String> {
-    let home = dirs::home_dir()?;
-
-    // Try zsh history first, then bash
-    let history_files = [
-        home.join(".zsh_history"),
-        home.join(".bash_history"),
-    ];
-
-    for history_file in &history_files {
-        if history_file.exists() {
-            if let Ok(content) = fs::read_to_string(history_file) {
-                let lines: Vec<&str> = content.lines().collect();
-                let recent: Vec<String> = lines
-                    .iter()
-                    .rev()
-                    .take(20)
-                    .rev()
-                    .map(|line| {
-                        // Handle zsh history format (: timestamp:0;command)
-                        if line.starts_with(": ") {
-                            line.split_once(';')
-                                .map(|(_, cmd)| cmd.to_string())
-                                .unwrap_or_else(|| line.to_string())
-                        } else {
-                            line.to_string()
-                        }
-                    })
-                    .collect();
-
-                if !recent.is_empty() {
-                    return Some(recent.join("\n"));
-                }
-            }
+/// Get `git diff --staged` (or the unstaged diff with `git_diff_source=unstaged`),
+/// with each file's part capped at `max_git_diff_file_bytes`
+fn get_git_diff(sources: &dyn ContextSources) -> Option<String> {
+    let mut args = vec!["diff", "--no-color"];
+    if settings::get_setting("git_diff_source").unwrap_or_default() != "unstaged" {
+        args.push("--staged");
+    }
+
+    let diff = sources.git(&args)?;
+    let diff = cap_diff_per_file(
+        &diff,
+        &sources.ignore_rules(),
+        settings::get_number("max_git_diff_file_bytes"),
+    );
+    if diff.trim().is_empty() {
+        None
+    } else {
+        Some(diff.trim_end().to_string())
+    }
+}
+
+/// Cap each file's section of a diff at `max` bytes, replacing sections for
+/// ignored paths with their header line and `[redacted]`
+fn cap_diff_per_file(diff: &str, ignore: &IgnoreRules, max: usize) -> String {
+    let mut starts: Vec<usize> = diff
+        .match_indices("diff --git ")
+        .map(|(i, _)| i)
+        .filter(|&i| i == 0 || diff[..i].ends_with('\n'))
+        .collect();
+    starts.push(diff.len());
+
+    let mut result = String::new();
+    for bounds in starts.windows(2) {
+        let file = &diff[bounds[0]..bounds[1]];
+        let header = file.lines().next().unwrap_or("");
+        let path = header.rsplit_once(" b/").map_or("", |(_, path)| path);
+        if ignore.is_ignored(path) {
+            result.push_str(&format!("{}\n[redacted]\n", header));
+        } else {
+            result.push_str(&cap_bytes(file.trim_end(), max));
+            result.push('\n');
         }
     }
+    result
+}
 
-    None
+/// Hide the path in a `git status --short` line if it matches the ignore file
+fn redact_status_line(line: &str, ignore: &IgnoreRules) -> String {
+    if ignore.is_empty() || line.len() <= 3 {
+        return line.to_string();
+    }
+
+    let (status, paths) = line.split_at(3);
+    let ignored = paths
+        .split(" -> ")
+        .any(|path| ignore.is_ignored(path.trim_matches('"')));
+
+    if ignored {
+        format!("{}[redacted]", status)
+    } else {
+        line.to_string()
+    }
 }
 
-/// Get a formatted context string for display (without markdown)
-pub fn gather_context_display() -> Result<String> {
-    settings::init_settings()?;
+/// Shows paths as they should be sent: anonymized if `anonymize_paths` is on
+struct PathDisplay {
+    anonymize: bool,
+    home: Option<String>,
+    git_root: Option<String>,
+}
 
-    let mut lines = Vec::new();
+impl PathDisplay {
+    fn new(sources: &dyn ContextSources) -> Self {
+        if !settings::is_enabled("anonymize_paths") {
+            return PathDisplay { anonymize: false, home: None, git_root: None };
+        }
+        let git_root = sources
+            .git(&["rev-parse", "--show-toplevel"])
+            .map(|root| root.trim().to_string())
+            .filter(|root| !root.is_empty());
+        PathDisplay {
+            anonymize: true,
+            home: sources.home().map(|h| h.display().to_string()),
+            git_root,
+        }
+    }
 
-    // Shell type
-    if settings::is_enabled("send_shell_type") {
-        if let Ok(shell) = env::var("SHELL") {
-            let shell_name = PathBuf::from(&shell)
-                .file_name()
-                .map(|s| s.to_string_lossy().to_string())
-                .unwrap_or_else(|| shell.clone());
-            lines.push(format!("Shell: {}", shell_name));
+    fn show(&self, path: &str) -> String {
+        if !self.anonymize {
+            return path.to_string();
         }
+        anonymize_path(path, self.home.as_deref(), self.git_root.as_deref())
     }
+}
 
-    // Working directory
-    if settings::is_enabled("send_working_dir") {
-        if let Ok(cwd) = env::current_dir() {
-            lines.push(format!("Working Directory: {}", cwd.display()));
+/// Hide the parts of a path that identify the user or organization: everything
+/// above the git root becomes `…` (keeping the repository's own name), and
+/// otherwise the home directory becomes `~`
+fn anonymize_path(path: &str, home: Option<&str>, git_root: Option<&str>) -> String {
+    let path_ref = Path::new(path);
+    if let Some(root) = git_root.map(Path::new) {
+        if let (Ok(rest), Some(name)) = (path_ref.strip_prefix(root), root.file_name()) {
+            let mut anonymized = PathBuf::from("…").join(name);
+            if !rest.as_os_str().is_empty() {
+                anonymized.push(rest);
+            }
+            return anonymized.display().to_string();
         }
     }
+    if let Some(rest) = home.and_then(|home| path_ref.strip_prefix(home).ok()) {
+        return if rest.as_os_str().is_empty() {
+            "~".to_string()
+        } else {
+            format!("~/{}", rest.display())
+        };
+    }
+    path.to_string()
+}
 
-    // Terminal size
-    if settings::is_enabled("send_terminal_size") {
-        if let Ok((cols, rows)) = crossterm::terminal::size() {
-            lines.push(format!("Terminal Size: {}x{}", cols, rows));
+/// Previous directory ($OLDPWD) and the pushd stack, if a shell hook exports it
+/// as $CMDK_DIRSTACK (one directory per line, e.g. `dirs -p`)
+fn get_directory_history(sources: &dyn ContextSources, paths: &PathDisplay) -> Option<String> {
+    let mut lines = Vec::new();
+
+    if let Some(oldpwd) = sources.var("OLDPWD") {
+        if !oldpwd.is_empty() {
+            lines.push(format!("Previous directory: {}", paths.show(&oldpwd)));
         }
     }
 
-    // Git status
-    if settings::is_enabled("send_git_status") {
-        if let Some(git_info) = get_git_status() {
-            lines.push(String::new());
-            lines.push("Git Status:".to_string());
-            for line in git_info.lines() {
-                lines.push(format!("  {}", line));
+    if let Some(stack) = sources.var("CMDK_DIRSTACK") {
+        let dirs: Vec<&str> = stack
+            .lines()
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .collect();
+        // The first entry is the current directory, which is already in context
+        if dirs.len() > 1 {
+            lines.push("Directory stack:".to_string());
+            for (i, dir) in dirs.iter().enumerate() {
+                lines.push(format!("  {} {}", i, paths.show(dir)));
             }
         }
     }
 
-    // Environment variable count
-    if settings::is_enabled("send_env_var_names") {
-        let count = env::vars().count();
-        lines.push(format!("Environment Variables: {} names", count));
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
     }
+}
 
-    // Shell history
-    if settings::is_enabled("send_shell_history") && get_shell_history().is_some() {
-        lines.push("Shell History: last 20 commands".to_string());
+/// Nonzero exit code of the user's last command, if the shell exports one
+fn get_last_exit_code(sources: &dyn ContextSources) -> Option<i32> {
+    sources
+        .var("CMDK_LAST_EXIT")?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&code| code != 0)
+}
+
+/// Get recent shell history
+fn get_shell_history(sources: &dyn ContextSources) -> Option<String> {
+    let max_lines = settings::get_number("max_history_lines");
+    let clean = settings::is_enabled("clean_shell_history");
+
+    sources.history_files().iter().find_map(|content| {
+        let recent = parse_shell_history(content, max_lines, clean);
+        (!recent.is_empty()).then(|| recent.join("\n"))
+    })
+}
+
+/// Extract the last `max_lines` commands from a zsh, bash or fish history file.
+/// With `clean`, blank lines, comments and consecutive repeats are dropped first.
+fn parse_shell_history(content: &str, max_lines: usize, clean: bool) -> Vec<String> {
+    let mut commands: Vec<String> = if content.trim_start().starts_with("- cmd:") {
+        parse_fish_history(content)
+    } else {
+        parse_line_history(content)
+    };
+
+    if clean {
+        commands.retain(|cmd| {
+            let cmd = cmd.trim();
+            !cmd.is_empty() && !cmd.starts_with('#')
+        });
+        commands.dedup();
     }
 
+    let skip = commands.len().saturating_sub(max_lines);
+    commands.split_off(skip)
+}
+
+/// Commands of a zsh or bash history file, one per line
+fn parse_line_history(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| {
+            // Handle zsh history format (: timestamp:0;command)
+            if line.starts_with(": ") {
+                line.split_once(';')
+                    .map(|(_, cmd)| cmd.to_string())
+                    .unwrap_or_else(|| line.to_string())
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Commands of a fish history file: the `- cmd:` entries, skipping the
+/// indented `when:` and `paths:` lines, with `\n` and `\\` unescaped
+fn parse_fish_history(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| line.strip_prefix("- cmd: "))
+        .map(|cmd| {
+            let mut unescaped = String::with_capacity(cmd.len());
+            let mut chars = cmd.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some('\\') => unescaped.push('\\'),
+                    Some(other) => {
+                        unescaped.push('\\');
+                        unescaped.push(other);
+                    }
+                    None => unescaped.push('\\'),
+                }
+            }
+            unescaped
+        })
+        .collect()
+}
+
+/// Get a formatted context string for display (without markdown)
+pub fn gather_context_display() -> Result<String> {
+    let context = gather_context()?;
+
+    let mut lines = Vec::new();
+
+    for section in &context.sections {
+        let size = format_size(section.size());
+        match section.name {
+            "Environment Variables" => {
+                let count = section.raw.split_whitespace().count();
+                lines.push(format!("Environment Variables: {} names ({})", count, size));
+            }
+            "Git Status" => {
+                lines.push(String::new());
+                lines.push(format!("Git Status: ({})", size));
+                for line in section.raw.lines() {
+                    lines.push(format!("  {}", line));
+                }
+            }
+            "Infrastructure Files" => {
+                let count = section.raw.lines().count();
+                lines.push(format!("Infrastructure Files: {} files ({})", count, size));
+            }
+            "Shell History" => {
+                let count = section.raw.lines().count();
+                lines.push(format!("Shell History: last {} commands ({})", count, size));
+            }
+            name => {
+                lines.push(format!("{}: {} ({})", name, section.raw, size));
+            }
+        }
+    }
+
+    lines.push(String::new());
+    lines.push(format!("Total: {}", format_size(context.total_size())));
+
     Ok(lines.join("\n"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::TestDir;
+
+    #[test]
+    fn test_anonymize_path() {
+        let home = Some("/home/alice");
+        let root = Some("/home/alice/work/acme/app");
+        assert_eq!(anonymize_path("/home/alice/work/acme/app/src", home, root), "…/app/src");
+        assert_eq!(anonymize_path("/home/alice/work/acme/app", home, root), "…/app");
+        assert_eq!(anonymize_path("/home/alice/notes", home, root), "~/notes");
+        assert_eq!(anonymize_path("/home/alice", home, None), "~");
+        assert_eq!(anonymize_path("/home/alicebob", home, None), "/home/alicebob");
+        assert_eq!(anonymize_path("/etc", home, root), "/etc");
+    }
+
+    #[test]
+    fn test_gather_context_from_fake_sources() {
+        let dir = TestDir::new("context");
+        let mut sources = FakeSources {
+            vars: vec![
+                ("SHELL".into(), "/usr/bin/fish".into()),
+                ("OLDPWD".into(), "/home/alice/work/app/src".into()),
+            ],
+            cwd: Some(PathBuf::from("/home/alice/work/app")),
+            home: Some(PathBuf::from("/home/alice")),
+            terminal_size: Some((80, 24)),
+            git: vec![
+                ("rev-parse --git-dir".into(), ".git\n".into()),
+                ("rev-parse --show-toplevel".into(), "/home/alice/work/app\n".into()),
+                ("branch --show-current".into(), "main\n".into()),
+                ("status --short".into(), " M src/main.rs\n".into()),
+            ],
+            history: vec![String::new(), "ls\ncargo test\n".into()],
+            ..Default::default()
+        };
+        let gather = |sources: &FakeSources, conf: &str| {
+            dir.write_settings(conf);
+            gather_context_from(sources).unwrap().to_markdown()
+        };
+
+        let all = gather(&sources, "send_last_exit=true\n");
+        let none = gather(
+            &sources,
+            "send_shell_type=false\nsend_working_dir=false\n\
+             send_terminal_size=false\nsend_env_var_names=false\nsend_git_status=false\n\
+             send_shell_history=false\n",
+        );
+        let anonymized = gather(&sources, "anonymize_paths=true\n");
+        let minimal = gather(&sources, "privacy_mode=minimal\nsend_git_status=true\n");
+        let off = gather(&sources, "privacy_mode=off\n");
+
+        // The ignore file, infra outline and manifest come from the sources too
+        sources.ignore = "src/main.rs\n".into();
+        sources.infra = Some("main.tf: terraform".into());
+        sources.manifest = vec![
+            (ManifestEntry::Include("config.toml".into()), Some("port = 80\n".into())),
+            (ManifestEntry::Include("/etc/passwd".into()), None),
+            (ManifestEntry::Command("cat src/main.rs".into()), Some("fn main() {}".into())),
+        ];
+        let project = gather(
+            &sources,
+            "send_infra_summary=true\nallow_manifest_includes=true\nallow_manifest_commands=true\n",
+        );
+
+        assert_eq!(
+            all,
+            "## Terminal Context\n\n\
+             **Shell:** fish\n\
+             **Working Directory:** /home/alice/work/app\n\
+             **Terminal Size:** 80x24\n\
+             \n### Directory History\nPrevious directory: /home/alice/work/app/src\n\
+             \n### Environment Variables (names only)\n```\nOLDPWD SHELL\n```\n\
+             \n### Git Status\nBranch: main\nModified files:\n M src/main.rs\n\
+             \n### Recent Shell History\n```\nls\ncargo test\n```\n"
+        );
+        assert_eq!(none, "## Terminal Context\n\n");
+        assert!(anonymized.contains("**Working Directory:** …/app\n"));
+        assert!(anonymized.contains("Previous directory: …/app/src\n"));
+        assert_eq!(
+            minimal,
+            format!("## Terminal Context\n\n**Shell:** fish\n**OS:** {}\n", env::consts::OS)
+        );
+        assert_eq!(off, "## Terminal Context\n\n");
+        assert!(project.contains(" M [redacted]\n"));
+        assert!(project.contains("### Infrastructure Files\n```\nmain.tf: terraform\n```\n"));
+        assert!(project.contains("### config.toml\n```\nport = 80\n```\n"));
+        assert!(!project.contains("passwd") && !project.contains("fn main"));
+    }
+
+    #[test]
+    fn test_cap_diff_per_file() {
+        let diff = "diff --git a/src/a.rs b/src/a.rs\n+fn a() {}\n+fn aa() {}\n\
+                    diff --git a/.env b/.env\n+TOKEN=x\n";
+        let ignore = IgnoreRules::parse(".env\n");
+        assert_eq!(
+            cap_diff_per_file(diff, &ignore, 43),
+            "diff --git a/src/a.rs b/src/a.rs\n+fn a() {}...\n(truncated)\n\
+             diff --git a/.env b/.env\n[redacted]\n"
+        );
+    }
+
+    #[test]
+    fn test_cap_bytes_multibyte() {
+        // Every limit that falls inside `é` (2 bytes) or `🦀` (4 bytes), from either end
+        let text = "aé🦀b";
+        for max in 1..text.len() {
+            let head = cap_bytes(text, max);
+            let kept = head.strip_suffix("...\n(truncated)").unwrap();
+            assert!(kept.len() <= max && text.is_char_boundary(kept.len()) && text.starts_with(kept));
+
+            let tail = cap_bytes_tail(text, max);
+            let kept = tail.strip_prefix("(truncated)\n...").unwrap();
+            assert!(kept.len() <= max && text.is_char_boundary(text.len() - kept.len()) && text.ends_with(kept));
+        }
+        assert_eq!(cap_bytes(text, 4), "aé...\n(truncated)");
+        assert_eq!(cap_bytes_tail(text, 4), "(truncated)\n...b");
+    }
+
+    #[test]
+    fn test_parse_shell_history() {
+        let history = ": 1700000000:0;cd project\n\
+                       : 1700000001:0;# fix the build\n\
+                       \n\
+                       : 1700000002:0;cargo build\n\
+                       : 1700000003:0;cargo build\n\
+                       #1700000004\n\
+                       git status\n\
+                       : 1700000005:0;   \n\
+                       cargo build\n";
+
+        assert_eq!(
+            parse_shell_history(history, 20, true),
+            vec!["cd project", "cargo build", "git status", "cargo build"]
+        );
+        assert_eq!(
+            parse_shell_history(history, 2, true),
+            vec!["git status", "cargo build"]
+        );
+        assert_eq!(parse_shell_history(history, 20, false).len(), 9);
+
+        let fish = "- cmd: cd project\n  when: 1700000000\n\
+                    - cmd: cat notes.txt\n  when: 1700000001\n  paths:\n    - notes.txt\n\
+                    - cmd: echo \"a\\\\b\"\n  when: 1700000002\n\
+                    - cmd: for f in *\\n    echo $f\\nend\n  when: 1700000003\n";
+        assert_eq!(
+            parse_shell_history(fish, 20, true),
+            vec!["cd project", "cat notes.txt", "echo \"a\\b\"", "for f in *\n    echo $f\nend"]
+        );
+        assert_eq!(parse_shell_history(fish, 1, true), vec!["for f in *\n    echo $f\nend"]);
+    }
+}
@@ -1,16 +1,65 @@
 use anyhow::Result;
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{cursor, execute, terminal};
+use signal_hook::consts::{SIGHUP, SIGTERM};
+use signal_hook::iterator::{Handle, Signals};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-/// Event handling for the TUI
+/// How long the TUI loop gets to shut down by itself after SIGTERM/SIGHUP
+const SIGNAL_GRACE: Duration = Duration::from_secs(1);
+
+/// Set while no EventHandler is catching SIGTERM and SIGHUP, so they end the
+/// process as usual. Once signal-hook has registered a signal its handler
+/// stays installed, so the default action is run from there instead.
+fn default_action() -> &'static Arc<AtomicBool> {
+    static DEFAULT_ACTION: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    DEFAULT_ACTION.get_or_init(|| {
+        let flag = Arc::new(AtomicBool::new(true));
+        for sig in [SIGTERM, SIGHUP] {
+            signal_hook::flag::register_conditional_default(sig, flag.clone()).ok();
+        }
+        flag
+    })
+}
+
+/// Event handling for the TUI. While it exists, SIGTERM and SIGHUP are caught
+/// so the loop can save what it can and restore the terminal before exiting.
 pub struct EventHandler {
     tick_rate: Duration,
+    /// Number of the terminating signal received, 0 if none
+    signal: Arc<AtomicI32>,
+    signals: Option<(Handle, JoinHandle<()>)>,
 }
 
 impl EventHandler {
     pub fn new(tick_rate_ms: u64) -> Self {
+        let signal = Arc::new(AtomicI32::new(0));
+        default_action().store(false, Ordering::Relaxed);
+        let signals = Signals::new([SIGTERM, SIGHUP]).ok().map(|mut signals| {
+            let handle = signals.handle();
+            let received = signal.clone();
+            let thread = thread::spawn(move || {
+                if let Some(sig) = signals.forever().next() {
+                    received.store(sig, Ordering::Relaxed);
+                    // The loop normally exits within a tick. It can't when the
+                    // terminal is gone (crossterm keeps polling a hung-up tty),
+                    // so don't wait on it forever.
+                    thread::sleep(SIGNAL_GRACE);
+                    terminal::disable_raw_mode().ok();
+                    execute!(io::stdout(), terminal::LeaveAlternateScreen, event::DisableMouseCapture, cursor::Show).ok();
+                    std::process::exit(128 + sig);
+                }
+            });
+            (handle, thread)
+        });
         Self {
             tick_rate: Duration::from_millis(tick_rate_ms),
+            signal,
+            signals,
         }
     }
 
@@ -23,6 +72,25 @@ impl EventHandler {
         }
         Ok(None)
     }
+
+    /// SIGTERM or SIGHUP, if one arrived: the TUI should save what it can and exit
+    pub fn terminate_signal(&self) -> Option<i32> {
+        match self.signal.load(Ordering::Relaxed) {
+            0 => None,
+            sig => Some(sig),
+        }
+    }
+}
+
+impl Drop for EventHandler {
+    fn drop(&mut self) {
+        // Back to the default behavior, e.g. for a command run after the TUI exits
+        if let Some((handle, thread)) = self.signals.take() {
+            handle.close();
+            thread.join().ok();
+        }
+        default_action().store(true, Ordering::Relaxed);
+    }
 }
 
 /// Application events
@@ -46,6 +114,14 @@ pub enum KeyAction {
     End,
     Left,
     Right,
+    /// Tab in the result view: select the next code block
+    Tab,
+    /// Ctrl+G in the prompt: include or leave out context for this query
+    ToggleContext,
+    /// Ctrl+T in the prompt: pick the next task preset for this query
+    CyclePreset,
+    /// Shift+Enter or Alt+Enter in the prompt: start a new line
+    Newline,
     None,
 }
 
@@ -69,6 +145,7 @@ pub fn key_to_action(key: KeyEvent) -> KeyAction {
         KeyCode::End => KeyAction::End,
         KeyCode::Left => KeyAction::Left,
         KeyCode::Right => KeyAction::Right,
+        KeyCode::Tab => KeyAction::Tab,
         _ => KeyAction::None,
     }
 }
@@ -79,8 +156,17 @@ pub fn key_to_input_action(key: KeyEvent) -> KeyAction {
     if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
         return KeyAction::Quit;
     }
+    if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('g') {
+        return KeyAction::ToggleContext;
+    }
+    if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('t') {
+        return KeyAction::CyclePreset;
+    }
 
     match key.code {
+        KeyCode::Enter if key.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) => {
+            KeyAction::Newline
+        }
         KeyCode::Enter => KeyAction::Select,
         KeyCode::Esc => KeyAction::Back,
         KeyCode::Char(c) => KeyAction::Char(c),
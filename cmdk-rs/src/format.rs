@@ -0,0 +1,275 @@
+/// Where a response is headed, which decides how it is cleaned up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    /// Raw text for the clipboard or another program's stdin
+    Clipboard,
+    /// A shell command about to be executed
+    Run,
+    /// Markdown for a file or bug report
+    Export,
+    /// Text shown in the TUI
+    Display,
+}
+
+/// Apply the trimming/fencing/sanitizing rules for a destination to a response
+pub fn format_response(response: &str, destination: Destination) -> String {
+    match destination {
+        Destination::Clipboard => response.trim().to_string(),
+        Destination::Run => sanitize_command(response),
+        Destination::Export => {
+            let response = response.trim();
+            if response.starts_with("```") {
+                response.to_string()
+            } else {
+                // Four backticks so a response containing ``` still renders
+                format!("````\n{}\n````", response)
+            }
+        }
+        Destination::Display => response
+            .trim_end()
+            .replace('\t', "    ")
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\n')
+            .collect(),
+    }
+}
+
+/// A fenced code block in a response
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    pub code: String,
+    /// Lines of the opening and closing fence
+    pub lines: (usize, usize),
+}
+
+/// The fenced code blocks of a response, in order; an unclosed block runs to the end
+pub fn code_blocks(response: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut open: Option<(usize, Vec<&str>)> = None;
+    for (i, line) in response.lines().enumerate() {
+        let is_fence = line.trim_start().starts_with("```");
+        match open.take() {
+            None if is_fence => open = Some((i, Vec::new())),
+            None => {}
+            Some((start, code)) if is_fence => blocks.push(CodeBlock {
+                code: code.join("\n"),
+                lines: (start, i),
+            }),
+            Some((start, mut code)) => {
+                code.push(line);
+                open = Some((start, code));
+            }
+        }
+    }
+    if let Some((start, code)) = open {
+        let last = response.lines().count().saturating_sub(1);
+        blocks.push(CodeBlock {
+            code: code.join("\n"),
+            lines: (start, last),
+        });
+    }
+    blocks
+}
+
+/// Short age like "45s", "12m", "3h" or "2d"
+pub fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// The first `n` characters of `s` (all of it if shorter). Cuts on a char
+/// boundary, unlike slicing by byte index.
+pub fn truncate_chars(s: &str, n: usize) -> &str {
+    match s.char_indices().nth(n) {
+        Some((end, _)) => &s[..end],
+        None => s,
+    }
+}
+
+/// The last `n` characters of `s` (all of it if shorter)
+pub fn last_chars(s: &str, n: usize) -> &str {
+    let skip = s.chars().count().saturating_sub(n);
+    match s.char_indices().nth(skip) {
+        Some((start, _)) => &s[start..],
+        None => s,
+    }
+}
+
+/// `s` in at most `max` characters, ending in "..." if it had to be cut
+pub fn ellipsize(s: &str, max: usize) -> String {
+    if s.chars().count() > max {
+        format!("{}...", truncate_chars(s, max.saturating_sub(3)))
+    } else {
+        s.to_string()
+    }
+}
+
+/// `s` in at most `max` characters, keeping the end and starting with "…" if it
+/// had to be cut (for paths)
+pub fn ellipsize_start(s: &str, max: usize) -> String {
+    if s.chars().count() > max {
+        format!("…{}", last_chars(s, max.saturating_sub(1)))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Break lines wider than `width` before `&&`, `||` and `|` and after `;`,
+/// outside quotes and parentheses, indenting the continuation lines. For the
+/// screen only: copying and running use the response as it came.
+pub fn wrap_at_operators(text: &str, width: usize) -> String {
+    text.lines()
+        .map(|line| {
+            if line.chars().count() <= width {
+                return line.to_string();
+            }
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let mut wrapped = String::new();
+            let mut current = String::new();
+            for part in split_at_operators(line.trim_start()) {
+                let part = part.trim();
+                if current.is_empty() {
+                    current = format!("{}{}", indent, part);
+                } else if current.chars().count() + 1 + part.chars().count() <= width {
+                    current.push(' ');
+                    current.push_str(part);
+                } else {
+                    wrapped.push_str(&current);
+                    wrapped.push('\n');
+                    current = format!("{}  {}", indent, part);
+                }
+            }
+            wrapped + &current
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Split a shell line into pieces: each starts with its `&&`, `||` or `|`,
+/// or ends with its `;`
+pub fn split_at_operators(line: &str) -> Vec<&str> {
+    let bytes = line.as_bytes();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote: Option<u8> = None;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(q), b) if b == q => quote = None,
+            (Some(b'"'), b'\\') | (None, b'\\') => i += 1,
+            (Some(_), _) => {}
+            (None, b @ (b'\'' | b'"')) => quote = Some(b),
+            (None, b'(') => depth += 1,
+            (None, b')') => depth = depth.saturating_sub(1),
+            (None, b';') if depth == 0 => {
+                parts.push(&line[start..=i]);
+                start = i + 1;
+            }
+            (None, b'&') if depth == 0 && bytes.get(i + 1) == Some(&b'&') => {
+                parts.push(&line[start..i]);
+                start = i;
+                i += 1;
+            }
+            (None, b'|') if depth == 0 => {
+                parts.push(&line[start..i]);
+                start = i;
+                if bytes.get(i + 1) == Some(&b'|') {
+                    i += 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    parts.push(&line[start..]);
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
+
+/// Reduce a response to the command inside it: the first fenced block if
+/// the model used one, without `$ ` prompts
+fn sanitize_command(response: &str) -> String {
+    let response = response.trim();
+    let body = match response.find("```") {
+        Some(start) => {
+            let rest = &response[start + 3..];
+            // Skip the language tag on the opening fence
+            let rest = rest.split_once('\n').map_or("", |(_, code)| code);
+            rest.split("```").next().unwrap_or(rest)
+        }
+        None => response,
+    };
+
+    body.lines()
+        .map(|line| line.strip_prefix("$ ").unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_chars() {
+        assert_eq!(truncate_chars("héllo", 2), "hé");
+        assert_eq!(truncate_chars("日本語", 5), "日本語");
+        assert_eq!(last_chars("naïve", 3), "ïve");
+        assert_eq!(last_chars("", 3), "");
+
+        // Recent prompts and the response cache list
+        let prompt = "日本語のファイルを".repeat(8);
+        assert_eq!(ellipsize(&prompt, 60), format!("{}...", prompt.chars().take(57).collect::<String>()));
+        assert_eq!(ellipsize("short", 60), "short");
+        // Working directory in the status bar
+        assert_eq!(ellipsize_start("/home/zoë/projets/café-crème", 20), "…/projets/café-crème");
+        assert_eq!(ellipsize_start("/srv/🦀", 20), "/srv/🦀");
+    }
+
+    #[test]
+    fn test_format_response() {
+        let fenced = "Here you go:\n```bash\n$ ls -la\n```\n";
+        assert_eq!(format_response(fenced, Destination::Run), "ls -la");
+        assert_eq!(format_response("  $ pwd \n", Destination::Run), "pwd");
+        assert_eq!(format_response(" ls\n", Destination::Clipboard), "ls");
+        assert_eq!(format_response("ls", Destination::Export), "````\nls\n````");
+        assert_eq!(format_response("a\tb\x1b[0m\n", Destination::Display), "a    b[0m");
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        let response = "Save this:\n```bash\n#!/bin/sh\necho hi\n```\nthen run:\n```\nsh hi.sh\n```\n```\nunclosed";
+        let blocks = code_blocks(response);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0], CodeBlock { code: "#!/bin/sh\necho hi".to_string(), lines: (1, 4) });
+        assert_eq!(blocks[1], CodeBlock { code: "sh hi.sh".to_string(), lines: (6, 8) });
+        assert_eq!(blocks[2], CodeBlock { code: "unclosed".to_string(), lines: (9, 10) });
+        assert!(code_blocks("ls -la").is_empty());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(59), "59s");
+        assert_eq!(format_age(600), "10m");
+        assert_eq!(format_age(7200), "2h");
+        assert_eq!(format_age(200000), "2d");
+    }
+
+    #[test]
+    fn test_wrap_at_operators() {
+        let command = "cd build && cmake .. && make -j8 | tee log; echo 'a && b' || (x | y)";
+        assert_eq!(wrap_at_operators(command, 100), command);
+        assert_eq!(
+            wrap_at_operators(command, 30),
+            "cd build && cmake ..\n  && make -j8 | tee log;\n  echo 'a && b' || (x | y)"
+        );
+        assert_eq!(wrap_at_operators("  a && b", 4), "  a\n    && b");
+    }
+}
@@ -0,0 +1,303 @@
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+
+const PROGRAM: Style = Style::new().add_modifier(Modifier::BOLD);
+const SUBCOMMAND: Style = Style::new().fg(Color::LightGreen);
+const FLAG: Style = Style::new().fg(Color::Cyan);
+const STRING: Style = Style::new().fg(Color::Yellow);
+const VARIABLE: Style = Style::new().fg(Color::LightMagenta);
+const OPERATOR: Style = Style::new().fg(Color::Gray);
+const COMMENT: Style = Style::new().fg(Color::DarkGray);
+const NUMBER: Style = Style::new().fg(Color::Cyan);
+
+/// Programs whose first argument is a subcommand, like `git commit`
+const SUBCOMMAND_PROGRAMS: &[&str] = &[
+    "git", "docker", "podman", "kubectl", "helm", "cargo", "npm", "pnpm", "yarn", "go", "systemctl",
+    "apt", "apt-get", "brew", "pip", "gh", "terraform", "dnf", "yum",
+];
+
+/// Words that run the command after them, which is then highlighted as the program
+const COMMAND_PREFIXES: &[&str] = &["sudo", "env", "time", "nohup", "exec", "xargs", "watch", "nice", "command"];
+
+/// How the lines of a fenced block, or a response outside any block, are highlighted
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Language {
+    Shell,
+    Diff,
+    /// Strings, comments starting with `comment` and numbers
+    Code {
+        comment: Option<&'static str>,
+        quotes: &'static str,
+    },
+    Plain,
+}
+
+/// Language for a fence's tag, e.g. `bash` in "```bash"; unknown tags stay plain
+fn language(tag: &str) -> Language {
+    let tag = tag.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+    let code = |comment, quotes| Language::Code { comment, quotes };
+    match tag.as_str() {
+        "" | "sh" | "bash" | "zsh" | "fish" | "shell" | "console" => Language::Shell,
+        "diff" | "patch" => Language::Diff,
+        "python" | "py" | "ruby" | "rb" | "yaml" | "yml" | "toml" | "perl" | "dockerfile" | "makefile" => {
+            code(Some("#"), "\"'")
+        }
+        // A single quote in Rust is usually a lifetime, not a string
+        "rust" | "rs" => code(Some("//"), "\""),
+        "c" | "cpp" | "java" | "javascript" | "js" | "typescript" | "ts" | "go" | "kotlin" | "swift" => {
+            code(Some("//"), "\"'`")
+        }
+        "sql" | "lua" => code(Some("--"), "\"'"),
+        "json" => code(None, "\""),
+        _ => Language::Plain,
+    }
+}
+
+/// A response as styled lines: fenced blocks by their language tag, and the
+/// rest as a shell command when `command` is set, otherwise plain
+pub fn response_lines(response: &str, command: bool) -> Vec<Line<'static>> {
+    let outside = if command { Language::Shell } else { Language::Plain };
+    let mut block = None;
+    let mut shell = ShellState::default();
+    let mut lines = Vec::new();
+    for line in response.lines() {
+        if let Some(tag) = line.trim_start().strip_prefix("```") {
+            block = match block {
+                None => Some(language(tag)),
+                Some(_) => None,
+            };
+            shell = ShellState::default();
+            lines.push(Line::styled(line.to_string(), COMMENT));
+            continue;
+        }
+        lines.push(match block.unwrap_or(outside) {
+            Language::Shell => shell_line(line, &mut shell),
+            Language::Diff => diff_line(line),
+            Language::Code { comment, quotes } => code_line(line, comment, quotes),
+            Language::Plain => Line::from(line.to_string()),
+        });
+    }
+    lines
+}
+
+/// Where a shell command is, carried across `\` line continuations
+#[derive(Default)]
+struct ShellState {
+    continued: bool,
+    expect_command: bool,
+}
+
+fn shell_line(line: &str, state: &mut ShellState) -> Line<'static> {
+    if !state.continued {
+        state.expect_command = true;
+    }
+    state.continued = line.trim_end().ends_with('\\');
+
+    let mut spans = Vec::new();
+    let mut expect_subcommand = false;
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        let rest = &line[i..];
+        let (len, style) = if c.is_whitespace() {
+            (rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len()), Style::new())
+        } else if c == '#' && line[..i].chars().next_back().is_none_or(char::is_whitespace) {
+            (rest.len(), COMMENT)
+        } else if c == '\'' || c == '"' {
+            (quoted_len(rest), STRING)
+        } else if rest.starts_with("$(") || is_shell_operator(c) {
+            let len = if c == '$' { 2 } else { rest.find(|c| !is_shell_operator(c)).unwrap_or(rest.len()) };
+            // A redirection is followed by a file, anything else by a command
+            if !rest[..len].contains(['<', '>']) {
+                state.expect_command = true;
+            }
+            (len, OPERATOR)
+        } else if c == '$' {
+            (variable_len(rest), VARIABLE)
+        } else {
+            let len = word_len(rest);
+            (len, word_style(&rest[..len], state, &mut expect_subcommand))
+        };
+        push_span(&mut spans, &rest[..len], style);
+        i += len;
+    }
+    Line::from(spans)
+}
+
+fn is_shell_operator(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '(' | ')' | '<' | '>')
+}
+
+/// Style of a bare word, given what the command so far expects next
+fn word_style(word: &str, state: &mut ShellState, expect_subcommand: &mut bool) -> Style {
+    let subcommand = std::mem::take(expect_subcommand);
+    if word.starts_with('-') {
+        FLAG
+    } else if state.expect_command {
+        if is_assignment(word) {
+            return VARIABLE;
+        }
+        if !COMMAND_PREFIXES.contains(&word) {
+            state.expect_command = false;
+            *expect_subcommand = SUBCOMMAND_PROGRAMS.contains(&word.rsplit('/').next().unwrap_or(word));
+        }
+        PROGRAM
+    } else if subcommand && word.chars().all(|c| c.is_ascii_lowercase() || c == '-') {
+        SUBCOMMAND
+    } else {
+        Style::new()
+    }
+}
+
+/// `NAME=value`, as in `RUST_LOG=debug cargo run`
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// Length of a word up to whitespace or an operator, including any quoted
+/// parts like the value of `--format='{{.Names}}'`
+fn word_len(text: &str) -> usize {
+    let mut len = 0;
+    while let Some(c) = text[len..].chars().next() {
+        if c.is_whitespace() || is_shell_operator(c) {
+            break;
+        }
+        len += if c == '\'' || c == '"' { quoted_len(&text[len..]) } else { c.len_utf8() };
+    }
+    len
+}
+
+/// Length of the string `text` starts with, through its closing quote or to
+/// the end of the line if it isn't closed
+fn quoted_len(text: &str) -> usize {
+    let mut chars = text.char_indices();
+    let Some((_, quote)) = chars.next() else {
+        return 0;
+    };
+    let mut escaped = false;
+    for (i, c) in chars {
+        if c == quote && !escaped {
+            return i + c.len_utf8();
+        }
+        escaped = quote != '\'' && c == '\\' && !escaped;
+    }
+    text.len()
+}
+
+/// Length of `$NAME`, `${...}` or a special parameter like `$?` at the start of `text`
+fn variable_len(text: &str) -> usize {
+    let rest = &text[1..];
+    if rest.starts_with('{') {
+        return rest.find('}').map_or(text.len(), |end| end + 2);
+    }
+    match rest.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            1 + rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len())
+        }
+        Some(c) if c.is_ascii_digit() || "?@#*!$-".contains(c) => 2,
+        _ => 1,
+    }
+}
+
+fn diff_line(line: &str) -> Line<'static> {
+    let style = if line.starts_with("+++") || line.starts_with("---") {
+        PROGRAM
+    } else if line.starts_with('+') {
+        Style::new().fg(Color::LightGreen)
+    } else if line.starts_with('-') {
+        Style::new().fg(Color::Red)
+    } else if line.starts_with("@@") {
+        Style::new().fg(Color::Cyan)
+    } else {
+        Style::new()
+    };
+    Line::styled(line.to_string(), style)
+}
+
+fn code_line(line: &str, comment: Option<&str>, quotes: &str) -> Line<'static> {
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        let rest = &line[i..];
+        let after_word = line[..i].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+        let (len, style) = if comment.is_some_and(|marker| rest.starts_with(marker)) {
+            (rest.len(), COMMENT)
+        } else if quotes.contains(c) {
+            (quoted_len(rest), STRING)
+        } else if c.is_ascii_digit() && !after_word {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '_');
+            (len.unwrap_or(rest.len()), NUMBER)
+        } else {
+            (c.len_utf8(), Style::new())
+        };
+        push_span(&mut spans, &rest[..len], style);
+        i += len;
+    }
+    Line::from(spans)
+}
+
+/// Add `text` to the line, joining it to the last span when the style matches
+fn push_span(spans: &mut Vec<Span<'static>>, text: &str, style: Style) {
+    match spans.last_mut() {
+        Some(last) if last.style == style => last.content.to_mut().push_str(text),
+        _ => spans.push(Span::styled(text.to_string(), style)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styled(line: &Line) -> Vec<(String, Style)> {
+        line.spans
+            .iter()
+            .filter(|span| !span.content.trim().is_empty())
+            .map(|span| (span.content.trim().to_string(), span.style))
+            .collect()
+    }
+
+    #[test]
+    fn test_response_lines() {
+        let lines = response_lines("sudo git commit -m \"fix it\" && echo $HOME # done", true);
+        assert_eq!(
+            styled(&lines[0]),
+            [
+                ("sudo".to_string(), PROGRAM),
+                ("git".to_string(), PROGRAM),
+                ("commit".to_string(), SUBCOMMAND),
+                ("-m".to_string(), FLAG),
+                ("\"fix it\"".to_string(), STRING),
+                ("&&".to_string(), OPERATOR),
+                ("echo".to_string(), PROGRAM),
+                ("$HOME".to_string(), VARIABLE),
+                ("# done".to_string(), COMMENT),
+            ]
+        );
+
+        let answer = "Try this:\n```python\nprint('hi', 42)  # greet\n```\n```text\nls -la\n```";
+        let lines = response_lines(answer, false);
+        assert_eq!(styled(&lines[0]), [("Try this:".to_string(), Style::new())]);
+        assert_eq!(lines[1].style, COMMENT);
+        assert_eq!(
+            styled(&lines[2]),
+            [
+                ("print(".to_string(), Style::new()),
+                ("'hi'".to_string(), STRING),
+                (",".to_string(), Style::new()),
+                ("42".to_string(), NUMBER),
+                (")".to_string(), Style::new()),
+                ("# greet".to_string(), COMMENT),
+            ]
+        );
+        assert_eq!(styled(&lines[5]), [("ls -la".to_string(), Style::new())]);
+
+        // Unclosed quotes and stray sigils run to the end of the line instead of panicking
+        for text in ["echo 'abc", "echo \"a\\\"", "x=${", "$", "ls >"] {
+            let line = &response_lines(text, true)[0];
+            assert_eq!(line.spans.iter().map(|s| s.content.as_ref()).collect::<String>(), text);
+        }
+    }
+}
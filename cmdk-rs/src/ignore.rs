@@ -0,0 +1,122 @@
+use regex_lite::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Name of the per-directory ignore file
+pub const IGNORE_FILE: &str = ".command-k-ignore";
+
+/// A single compiled ignore pattern
+#[derive(Debug)]
+struct Rule {
+    regex: Regex,
+    negated: bool,
+}
+
+/// Gitignore-style rules for paths that must never be sent as context
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Load rules from `.command-k-ignore` in `dir` (empty if missing)
+    pub fn load_in(dir: &Path) -> Self {
+        match fs::read_to_string(dir.join(IGNORE_FILE)) {
+            Ok(content) => Self::parse(&content),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Parse rules from the contents of an ignore file
+    pub fn parse(content: &str) -> Self {
+        let rules = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (negated, pattern) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                Regex::new(&pattern_to_regex(pattern))
+                    .ok()
+                    .map(|regex| Rule { regex, negated })
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Check if a path (relative to the current directory) is ignored.
+    /// Later rules override earlier ones, so `!pattern` can re-include a path.
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let path = path.trim().trim_start_matches("./");
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.regex.is_match(path) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// Convert a gitignore-style glob into an anchored regex.
+/// Patterns without a slash match at any depth; matching a directory matches everything in it.
+fn pattern_to_regex(pattern: &str) -> String {
+    let pattern = pattern.trim_end_matches('/');
+    let anchored = pattern.contains('/');
+    let pattern = pattern.trim_start_matches('/');
+
+    let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c if "\\.+()|[]{}^$".contains(c) => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+
+    regex.push_str("(?:/.*)?$");
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_patterns() {
+        let rules = IgnoreRules::parse(
+            "# secrets\n*.pem\nsecrets/\n/config/local.toml\ndocs/**/draft-*.md\n!public.pem\n",
+        );
+        assert!(rules.is_ignored("key.pem"));
+        assert!(rules.is_ignored("certs/key.pem"));
+        assert!(!rules.is_ignored("public.pem"));
+        assert!(rules.is_ignored("secrets/api.txt"));
+        assert!(rules.is_ignored("app/secrets/api.txt"));
+        assert!(rules.is_ignored("config/local.toml"));
+        assert!(!rules.is_ignored("app/config/local.toml"));
+        assert!(rules.is_ignored("docs/a/b/draft-1.md"));
+        assert!(!rules.is_ignored("src/main.rs"));
+    }
+}
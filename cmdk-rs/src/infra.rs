@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::Path;
+
+use crate::ignore::IgnoreRules;
+
+/// Files whose presence marks the working directory as an infra project
+const MARKER_FILES: &[&str] = &[
+    "main.tf",
+    "kustomization.yaml",
+    "kustomization.yml",
+    "Chart.yaml",
+];
+
+/// At most this many files are listed and summarized
+const MAX_FILES: usize = 20;
+
+/// Summarize Terraform/Kustomize/Helm files in `dir`: the file list plus the
+/// top-level blocks or keys of each, never full contents.
+/// None if the directory has none of the marker files.
+pub fn summarize(dir: &Path, ignore: &IgnoreRules) -> Option<String> {
+    if !MARKER_FILES.iter().any(|f| dir.join(f).is_file() && !ignore.is_ignored(f)) {
+        return None;
+    }
+
+    let mut files: Vec<String> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| is_infra_file(name) && !ignore.is_ignored(name))
+        .collect();
+    files.sort();
+
+    let mut summary = String::new();
+    for name in files.iter().take(MAX_FILES) {
+        let content = fs::read_to_string(dir.join(name)).unwrap_or_default();
+        let outline = if name.ends_with(".tf") {
+            terraform_blocks(&content)
+        } else {
+            yaml_top_level_keys(&content)
+        };
+        if outline.is_empty() {
+            summary.push_str(&format!("{}\n", name));
+        } else {
+            summary.push_str(&format!("{}: {}\n", name, outline.join(", ")));
+        }
+    }
+    if files.len() > MAX_FILES {
+        summary.push_str(&format!("... and {} more\n", files.len() - MAX_FILES));
+    }
+
+    Some(summary)
+}
+
+fn is_infra_file(name: &str) -> bool {
+    name.ends_with(".tf") || name.ends_with(".tfvars") || name.ends_with(".yaml") || name.ends_with(".yml")
+}
+
+/// Top-level Terraform blocks, e.g. `resource "aws_s3_bucket" "logs"`
+fn terraform_blocks(content: &str) -> Vec<String> {
+    const KINDS: &[&str] = &[
+        "terraform", "provider", "resource", "data", "module", "variable", "output", "locals",
+    ];
+    content
+        .lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let header = line.split('{').next()?.trim();
+            let kind = header.split_whitespace().next()?;
+            KINDS.contains(&kind).then(|| header.to_string())
+        })
+        .collect()
+}
+
+/// Keys at the start of a line in a YAML file (not indented, not comments)
+fn yaml_top_level_keys(content: &str) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::new();
+    for line in content.lines() {
+        if line.starts_with(|c: char| c.is_whitespace() || c == '#' || c == '-') {
+            continue;
+        }
+        if let Some((key, _)) = line.split_once(':') {
+            let key = key.trim().to_string();
+            if !key.is_empty() && !key.contains(' ') && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outlines() {
+        let tf = "terraform {\n  required_version = \">= 1.5\"\n}\n\n\
+                  resource \"aws_s3_bucket\" \"logs\" {\n  bucket = \"x\"\n}\n\
+                  variable \"region\" {}\n";
+        assert_eq!(
+            terraform_blocks(tf),
+            vec!["terraform", "resource \"aws_s3_bucket\" \"logs\"", "variable \"region\""]
+        );
+
+        let yaml = "# chart\napiVersion: v2\nname: web\ndependencies:\n  - name: redis\n---\nname: again\n";
+        assert_eq!(yaml_top_level_keys(yaml), vec!["apiVersion", "name", "dependencies"]);
+    }
+}
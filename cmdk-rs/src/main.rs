@@ -1,23 +1,51 @@
+mod aliases;
 mod app;
+mod attachment;
+mod cache;
+mod capabilities;
 mod context;
+mod diff;
 mod events;
+mod format;
+mod highlight;
+mod ignore;
+mod infra;
+mod manifest;
 mod nvim;
+mod presets;
+#[cfg(test)]
+mod prompt_golden;
 mod provider;
+mod redact;
+mod server;
 mod session;
 mod settings;
+mod shell_init;
+#[cfg(feature = "sqlite")]
+mod storage;
+mod templates;
 mod ui;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use std::io::{self, Read};
+use std::io::{self, BufRead, Read};
+
+use app::Verbosity;
+use provider::QueryError;
+
+/// Exit code when a query times out (matches coreutils `timeout`)
+const EXIT_TIMEOUT: i32 = 124;
+
+/// Exit code when no AI provider is available
+const EXIT_NO_PROVIDER: i32 = 2;
 
 #[derive(Parser, Debug)]
 #[command(name = "cmdk-rs")]
 #[command(about = "AI-powered command assistant for the terminal")]
 #[command(version)]
 struct Args {
-    /// Direct query mode (non-interactive)
-    #[arg(short, long)]
+    /// Direct query mode (non-interactive); $CMDK_QUERY is used when not given
+    #[arg(short, long, env = "CMDK_QUERY")]
     query: Option<String>,
 
     /// Show current context
@@ -31,10 +59,119 @@ struct Args {
     /// Neovim integration mode (path to context file)
     #[arg(long)]
     nvim: Option<String>,
+
+    /// Read the Neovim context as JSON from stdin instead of the context file
+    #[arg(long, requires = "nvim")]
+    nvim_json: bool,
+
+    /// Attach a file to a --query (images for providers that support them,
+    /// otherwise the file is included in context); can be repeated
+    #[arg(long, value_name = "PATH", requires = "query")]
+    attach: Vec<String>,
+
+    /// After a --query, show the response with Run/Copy actions instead of printing it
+    #[arg(long, requires = "query")]
+    interactive_result: bool,
+
+    /// Print the response of a --query or piped query as a JSON object with
+    /// the query, provider and context size (ignored in the TUI)
+    #[arg(long)]
+    json: bool,
+
+    /// Also write the full prompt and response of a --query, with secrets redacted, to FILE
+    #[arg(long, value_name = "FILE", requires = "query")]
+    repro: Option<String>,
+
+    /// Treat piped stdin as context for --query instead of as the query
+    #[arg(long, requires = "query")]
+    stdin_context: bool,
+
+    /// Start a new conversation instead of continuing this directory's session
+    #[arg(long)]
+    fresh: bool,
+
+    /// Like --fresh, but keep a short recap of the last turns as context
+    #[arg(long, conflicts_with = "fresh")]
+    replay: bool,
+
+    /// Run a local HTTP server that answers queries as JSON
+    #[arg(long)]
+    serve: bool,
+
+    /// Port for --serve (listens on 127.0.0.1 only)
+    #[arg(long, requires = "serve", default_value_t = server::DEFAULT_PORT)]
+    port: u16,
+
+    /// Kill the provider after this many seconds (overrides provider_timeout_secs)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Import prompts (one per line) from a file into the prompt history
+    #[arg(long, value_name = "FILE")]
+    import_history: Option<String>,
+
+    /// Append a markdown conversation from another tool (`## User:` /
+    /// `## Assistant:` messages) to this directory's session, to continue it
+    #[arg(long, value_name = "FILE")]
+    append_to_session: Option<String>,
+
+    /// List stored sessions with their turns, age and tags
+    #[arg(long)]
+    sessions: bool,
+
+    /// With --sessions, only list sessions with this tag
+    #[arg(long, requires = "sessions")]
+    tag: Option<String>,
+
+    /// Print the command that would be run for the current provider, without running it
+    #[arg(long)]
+    print_provider_command: bool,
+
+    /// Print the shell hook that exports $CMDK_LAST_EXIT and $CMDK_DIRSTACK
+    /// (bash, zsh or fish), for eval in your shell's rc file
+    #[arg(long, value_name = "SHELL")]
+    init_shell: Option<String>,
+
+    /// Show the provider, context size and timing on stderr
+    #[arg(short, long, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Print nothing on stderr, not even errors (the exit code still reports failure)
+    #[arg(short = 'Q', long)]
+    quiet: bool,
+}
+
+fn main() {
+    let mut args = Args::parse();
+    // An empty $CMDK_QUERY counts as unset, so wrappers can always export it
+    if args.query.as_deref() == Some("") && std::env::var_os("CMDK_QUERY").is_some_and(|v| v.is_empty()) {
+        args.query = None;
+    }
+    app::set_verbosity(if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    });
+
+    if let Err(e) = run(args) {
+        match app::verbosity() {
+            Verbosity::Quiet => {}
+            Verbosity::Normal => eprintln!("Error: {:#}", e),
+            Verbosity::Verbose => eprintln!("Error: {:?}", e),
+        }
+        std::process::exit(1);
+    }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn run(args: Args) -> Result<()> {
+
+    // Runs from rc files, so it must not read stdin or create the settings dir
+    if let Some(ref shell) = args.init_shell {
+        print!("{}", shell_init::hook(shell)?);
+        return Ok(());
+    }
 
     // Check for piped input (but not in nvim mode)
     let piped_input = if args.nvim.is_none() && !atty::is(atty::Stream::Stdin) {
@@ -47,22 +184,92 @@ fn main() -> Result<()> {
 
     // Initialize settings
     settings::init_settings()?;
+    warn_storage_unavailable();
+
+    if let Some(secs) = args.timeout {
+        provider::set_timeout_override(secs);
+    }
+
+    if args.fresh {
+        session::start_fresh_session();
+    }
+    if args.replay {
+        session::start_fresh_session_with_recap()?;
+    }
 
     // Neovim mode
     if let Some(ref context_file) = args.nvim {
+        let json_context = if args.nvim_json {
+            let mut json = String::new();
+            io::stdin().read_to_string(&mut json)?;
+            Some(json)
+        } else {
+            None
+        };
+
         if let Some(ref query) = args.query {
             // Quick query mode for Neovim
-            return nvim::run_nvim_query_mode(context_file, query);
+            return exit_on_timeout(nvim::run_nvim_query_mode(
+                context_file,
+                json_context.as_deref(),
+                query,
+            ));
         }
         // Interactive Neovim mode
-        return nvim::run_nvim_mode(context_file);
+        return nvim::run_nvim_mode(context_file, json_context.as_deref());
+    }
+
+    if args.serve {
+        return server::run_server(args.port);
+    }
+
+    if args.print_provider_command {
+        let provider = require_provider();
+        println!("{}", provider::provider_command(&provider)?);
+        return Ok(());
+    }
+
+    if let Some(ref path) = args.import_history {
+        let (imported, duplicates) = session::import_prompt_history(path)?;
+        println!(
+            "Imported {} prompt(s), skipped {} duplicate(s)",
+            imported, duplicates
+        );
+        return Ok(());
+    }
+
+    if let Some(ref path) = args.append_to_session {
+        let turns = session::import_conversation(path)?;
+        println!("Imported {} turn(s) into this directory's session", turns);
+        return Ok(());
+    }
+
+    if args.sessions {
+        for summary in session::list_sessions()? {
+            if args.tag.as_ref().is_some_and(|tag| !summary.tags.contains(tag)) {
+                continue;
+            }
+            let tags = if summary.tags.is_empty() {
+                String::new()
+            } else {
+                format!("  [{}]", summary.tags.join(", "))
+            };
+            println!(
+                "{}  {} turn(s), {} ago{}  {}",
+                summary.name,
+                summary.turns,
+                format::format_age(summary.age),
+                tags,
+                summary.first_question
+            );
+        }
+        return Ok(());
     }
 
     if args.context {
         // Show context mode
         let ctx = context::gather_context()?;
-        println!("{}", ctx);
-        return Ok(());
+        return app::print_stdout(&ctx.to_string());
     }
 
     if args.settings {
@@ -71,16 +278,117 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    // The query comes from --query, then $CMDK_QUERY (both via clap), then piped stdin
     if let Some(query) = args.query {
-        // Direct query mode
-        return app::run_query_mode(&query);
+        // Direct query mode, optionally with piped input as extra context
+        require_provider();
+        let piped_as_context = args.stdin_context
+            || piped_input.is_some() && settings::get_setting("piped_input_mode")? != "query";
+        let piped_context = if piped_as_context {
+            piped_input.as_deref().filter(|s| !s.is_empty())
+        } else {
+            None
+        };
+        let attachments = args
+            .attach
+            .iter()
+            .map(|path| attachment::Attachment::load(path))
+            .collect::<Result<Vec<_>>>()?;
+        if args.interactive_result {
+            warn_json_ignored(args.json);
+            return app::run_interactive_result_mode(&query, piped_context, &attachments);
+        }
+        return exit_on_timeout(app::run_query_mode(
+            &query,
+            piped_context,
+            &attachments,
+            args.repro.as_deref(),
+            args.json,
+        ));
     }
 
     if let Some(input) = piped_input {
-        // Piped input mode
-        return app::run_query_mode(&input);
+        // Piped input mode: the input is the query, or context for a query typed at the terminal
+        require_provider();
+        if piped_input_is_context()? {
+            let query = ask_terminal("What should I do with the piped input? ")?;
+            if query.is_empty() {
+                return Ok(());
+            }
+            return exit_on_timeout(app::run_query_mode(&query, Some(&input), &[], None, args.json));
+        }
+        return exit_on_timeout(app::run_query_mode(&input, None, &[], None, args.json));
     }
 
     // Interactive TUI mode
+    warn_json_ignored(args.json);
     app::run_interactive_mode()
 }
+
+/// `--json` only changes how direct queries print; say so when it has no effect
+fn warn_json_ignored(json: bool) {
+    if json && app::verbosity() > Verbosity::Quiet {
+        eprintln!("Warning: --json only applies to -q and piped queries; ignoring it in the TUI");
+    }
+}
+
+/// `storage=sqlite` needs a `--features sqlite` build; say so rather than
+/// quietly keeping the files
+fn warn_storage_unavailable() {
+    if !cfg!(feature = "sqlite")
+        && settings::get_setting("storage").is_ok_and(|storage| storage == "sqlite")
+        && app::verbosity() > Verbosity::Quiet
+    {
+        eprintln!("Warning: storage=sqlite needs a build with --features sqlite; using the files in the command-k directory");
+    }
+}
+
+/// Whether piped input without `-q` is context rather than the query
+/// (`piped_input_mode`; `ask` asks when stderr is a terminal)
+fn piped_input_is_context() -> Result<bool> {
+    match settings::get_setting("piped_input_mode")?.as_str() {
+        "context" => Ok(true),
+        "ask" if atty::is(atty::Stream::Stderr) => {
+            let answer = ask_terminal("Use the piped input as the (q)uery or as (c)ontext? [q/c] ")?;
+            Ok(answer.to_lowercase().starts_with('c'))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Ask a question on stderr and read the answer from the terminal, since stdin is the pipe
+fn ask_terminal(question: &str) -> Result<String> {
+    let tty = std::fs::File::open("/dev/tty")
+        .context("No terminal to ask on; use -q \"...\" to give the query")?;
+    eprint!("{}", question);
+    let mut answer = String::new();
+    io::BufReader::new(tty).read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+/// In non-interactive modes, the first available provider of the fallback chain;
+/// if there is none, explain how to set one up on stderr and exit with `EXIT_NO_PROVIDER`
+fn require_provider() -> provider::Provider {
+    match provider::get_available_provider() {
+        Ok(provider) => provider,
+        Err(e) => {
+            if app::verbosity() > Verbosity::Quiet {
+                eprintln!("Error: {:#}\n\n{}", e, provider::setup_help());
+            }
+            std::process::exit(EXIT_NO_PROVIDER);
+        }
+    }
+}
+
+/// In non-interactive modes, report a timeout on stderr and exit with `EXIT_TIMEOUT`
+fn exit_on_timeout(result: Result<()>) -> Result<()> {
+    if let Err(ref e) = result {
+        if let Some(timeout @ QueryError::Timeout(_)) = e.downcast_ref::<QueryError>() {
+            if app::verbosity() > Verbosity::Quiet {
+                eprintln!("Error: {}", timeout);
+            }
+            std::process::exit(EXIT_TIMEOUT);
+        }
+    }
+    result
+}
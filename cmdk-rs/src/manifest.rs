@@ -0,0 +1,223 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ignore::IgnoreRules;
+
+/// Name of the per-directory context manifest
+pub const MANIFEST_FILE: &str = ".command-k-context";
+
+/// How long a manifest command may run before it is killed
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One line of a `.command-k-context` manifest
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestEntry {
+    /// `include: path` - a file whose contents are always sent
+    Include(String),
+    /// Any other line - a shell command whose output is always sent
+    Command(String),
+}
+
+impl ManifestEntry {
+    /// Label shown in the context, e.g. `src/config.rs` or `cat Dockerfile`
+    pub fn label(&self) -> &str {
+        match self {
+            ManifestEntry::Include(path) | ManifestEntry::Command(path) => path,
+        }
+    }
+
+    /// Whether this entry reads a path listed in the ignore file.
+    /// For commands, any word naming an ignored path counts, quoted or after
+    /// an `=` as in `--config=.env`.
+    pub fn touches_ignored(&self, ignore: &IgnoreRules) -> bool {
+        match self {
+            ManifestEntry::Include(path) => ignore.is_ignored(path),
+            ManifestEntry::Command(cmd) => shell_words(cmd)
+                .iter()
+                .flat_map(|word| word.split('='))
+                .any(|arg| !arg.is_empty() && ignore.is_ignored(arg)),
+        }
+    }
+
+    /// Read the file or run the command in `dir`, the manifest's directory;
+    /// `None` for an include of a file outside it. Errors are returned as text
+    /// so the AI (and the context view) can see why an entry is empty.
+    pub fn output(&self, dir: &Path) -> Option<String> {
+        match self {
+            ManifestEntry::Include(path) => {
+                let content = include_path(dir, path).and_then(|file| file.map(fs::read_to_string).transpose());
+                match content {
+                    Ok(content) => content,
+                    Err(e) => Some(format!("(could not read {}: {})", path, e)),
+                }
+            }
+            ManifestEntry::Command(cmd) => Some(run_command(cmd, dir)),
+        }
+    }
+}
+
+/// The file an `include:` names, resolved from `dir` through any `..` and
+/// symlinks; `None` if it lies outside `dir`, since a manifest from a cloned
+/// repository could otherwise name `~/.ssh/id_rsa`
+fn include_path(dir: &Path, path: &str) -> std::io::Result<Option<PathBuf>> {
+    let dir = dir.canonicalize()?;
+    let file = dir.join(path).canonicalize()?;
+    Ok(file.starts_with(&dir).then_some(file))
+}
+
+/// Words of a shell command with quotes and backslashes removed; operators
+/// like `|`, `;` and `<` separate words
+fn shell_words(cmd: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => word.extend(chars.next()),
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() || "|&;<>()".contains(c) => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            (None, c) => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Load entries from `.command-k-context` in `dir` (empty if missing)
+pub fn load_in(dir: &Path) -> Vec<ManifestEntry> {
+    match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(content) => parse(&content),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Parse the contents of a manifest
+pub fn parse(content: &str) -> Vec<ManifestEntry> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.strip_prefix("include:") {
+            Some(path) => ManifestEntry::Include(path.trim().to_string()),
+            None => ManifestEntry::Command(line.to_string()),
+        })
+        .collect()
+}
+
+/// Run a manifest command in a shell in `dir`, returning stdout and stderr combined
+fn run_command(cmd: &str, dir: &Path) -> String {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(format!("{} 2>&1", cmd))
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    // Its own process group, so a timeout kills what the shell started too
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let child = command.spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => return format!("(failed to run: {})", e),
+    };
+
+    // Read on a thread so a chatty command can't fill the pipe and stall
+    let mut stdout = child.stdout.take();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(ref mut stdout) = stdout {
+            let _ = stdout.read_to_string(&mut output);
+        }
+        output
+    });
+
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+            _ => {
+                kill_group(&mut child);
+                let _ = child.wait();
+                return format!("(timed out after {}s)", COMMAND_TIMEOUT.as_secs());
+            }
+        }
+    }
+
+    reader.join().unwrap_or_default()
+}
+
+/// Kill a command started in its own process group, with everything it started
+fn kill_group(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        let killed = Command::new("kill")
+            .args(["-KILL", "--", &format!("-{}", child.id())])
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if killed {
+            return;
+        }
+    }
+    let _ = child.kill();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::TestDir;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = "# always relevant\ninclude: src/config.rs\n\ncat Dockerfile\n";
+        let entries = parse(manifest);
+        assert_eq!(
+            entries,
+            vec![
+                ManifestEntry::Include("src/config.rs".to_string()),
+                ManifestEntry::Command("cat Dockerfile".to_string()),
+            ]
+        );
+
+        let ignore = IgnoreRules::parse("*.rs\n");
+        assert!(entries[0].touches_ignored(&ignore));
+        assert!(!entries[1].touches_ignored(&ignore));
+        assert!(ManifestEntry::Command("cat 'main.rs'".to_string()).touches_ignored(&ignore));
+        assert!(ManifestEntry::Command("cat \"my main.rs\"".to_string()).touches_ignored(&ignore));
+        assert!(ManifestEntry::Command("tool --config=main.rs".to_string()).touches_ignored(&ignore));
+        assert!(ManifestEntry::Command("wc -l<main.rs".to_string()).touches_ignored(&ignore));
+    }
+
+    #[test]
+    fn test_include_outside() {
+        let dir = TestDir::new("manifest");
+        let project = dir.path.join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(project.join("config.toml"), "port = 80\n").unwrap();
+        fs::write(dir.path.join("secret"), "hunter2\n").unwrap();
+
+        let include = |path: &str| ManifestEntry::Include(path.to_string()).output(&project);
+        assert_eq!(include("./config.toml").as_deref(), Some("port = 80\n"));
+        assert_eq!(include("../secret"), None);
+        assert_eq!(include(&dir.path.join("secret").display().to_string()), None);
+    }
+}
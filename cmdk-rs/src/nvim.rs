@@ -1,24 +1,32 @@
 use anyhow::{Context, Result};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
 use crate::app::{App, AppState};
-use crate::context;
+use crate::context::{self, ContextSection};
 use crate::events::{key_to_action, AppEvent, EventHandler, KeyAction};
-use crate::provider;
+use crate::format;
+use crate::provider::{self, QueryMode};
 use crate::session;
+use crate::settings;
+use crate::ui;
 
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    event::{
+        DisableMouseCapture, EnableMouseCapture, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
 use std::io::{self, Stdout};
 
-/// Neovim context parsed from the context file
-#[derive(Debug, Default)]
+/// Neovim context parsed from the context file (or JSON on stdin with `--nvim-json`)
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct NvimContext {
     pub filepath: Option<String>,
     pub filename: Option<String>,
@@ -29,6 +37,10 @@ pub struct NvimContext {
     pub visual_selection: Option<String>,
     pub lsp_diagnostics: Option<String>,
     pub buffer_content: Option<String>,
+    /// Extra instructions from the plugin config, added to the built-in rules
+    pub system_prompt: Option<String>,
+    /// Replaces the `persona` setting for this invocation
+    pub persona: Option<String>,
 }
 
 impl NvimContext {
@@ -52,6 +64,8 @@ impl NvimContext {
         ctx.current_line = env_map.get("CMDK_NVIM_CURRENT_LINE").cloned().filter(|s| !s.is_empty());
         ctx.visual_selection = env_map.get("CMDK_NVIM_VISUAL_SELECTION").cloned().filter(|s| !s.is_empty());
         ctx.lsp_diagnostics = env_map.get("CMDK_NVIM_LSP_DIAGNOSTICS").cloned().filter(|s| !s.is_empty());
+        ctx.system_prompt = env_map.get("CMDK_NVIM_SYSTEM_PROMPT").cloned().filter(|s| !s.trim().is_empty());
+        ctx.persona = env_map.get("CMDK_NVIM_PERSONA").cloned().filter(|s| !s.trim().is_empty());
 
         if let Some(line) = env_map.get("CMDK_NVIM_CURSOR_LINE") {
             ctx.cursor_line = line.parse().ok();
@@ -70,49 +84,126 @@ impl NvimContext {
         Ok(ctx)
     }
 
-    /// Format as markdown context for the AI prompt
-    pub fn to_markdown(&self) -> String {
-        let mut ctx = String::new();
-        ctx.push_str("## Neovim Context\n\n");
+    /// Parse context passed as a JSON object. Field names match the struct,
+    /// e.g. `{"filepath": "src/main.rs", "cursor_line": 12, "visual_selection": "..."}`.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let mut ctx: NvimContext =
+            serde_json::from_str(json).context("Failed to parse nvim context JSON")?;
+
+        // Treat empty strings like missing fields, as the file format does
+        for field in [
+            &mut ctx.filepath,
+            &mut ctx.filename,
+            &mut ctx.filetype,
+            &mut ctx.current_line,
+            &mut ctx.visual_selection,
+            &mut ctx.lsp_diagnostics,
+            &mut ctx.buffer_content,
+            &mut ctx.system_prompt,
+            &mut ctx.persona,
+        ] {
+            if field.as_deref() == Some("") {
+                *field = None;
+            }
+        }
+
+        Ok(ctx)
+    }
+
+    /// Load context from JSON if given, otherwise from the context file
+    pub fn load(context_file: &str, json: Option<&str>) -> Result<Self> {
+        match json {
+            Some(json) => Self::from_json(json),
+            None => Self::from_file(context_file),
+        }
+    }
+
+    /// Use the plugin's persona and system prompt, if it sent any, for every
+    /// prompt this process builds
+    pub fn apply_prompt_override(&self) {
+        if self.persona.is_some() || self.system_prompt.is_some() {
+            provider::set_prompt_override(provider::PromptOverride {
+                persona: self.persona.clone(),
+                system_prompt: self.system_prompt.clone(),
+            });
+        }
+    }
+
+    /// Split the context into named sections for the AI prompt
+    pub fn sections(&self) -> Vec<ContextSection> {
+        let mut sections = Vec::new();
+        let mut push = |name: &'static str, raw: &str, markdown: String| {
+            sections.push(ContextSection {
+                name,
+                raw: raw.to_string(),
+                markdown,
+            });
+        };
 
         if let Some(ref filepath) = self.filepath {
-            ctx.push_str(&format!("**File:** {}\n", filepath));
+            push("File", filepath, format!("**File:** {}\n", filepath));
         }
 
         if let Some(ref filetype) = self.filetype {
-            ctx.push_str(&format!("**Filetype:** {}\n", filetype));
+            push("Filetype", filetype, format!("**Filetype:** {}\n", filetype));
         }
 
         if let (Some(line), Some(col)) = (self.cursor_line, self.cursor_col) {
-            ctx.push_str(&format!("**Cursor Position:** Line {}, Column {}\n", line, col));
+            let position = format!("Line {}, Column {}", line, col);
+            let markdown = format!("**Cursor Position:** {}\n", position);
+            push("Cursor", &position, markdown);
         }
 
         if let Some(ref current_line) = self.current_line {
-            ctx.push_str(&format!("\n**Current Line:**\n```\n{}\n```\n", current_line));
+            let markdown = format!("\n**Current Line:**\n```\n{}\n```\n", current_line);
+            push("Current Line", current_line, markdown);
         }
 
         if let Some(ref selection) = self.visual_selection {
-            ctx.push_str(&format!("\n**Selected Text:**\n```\n{}\n```\n", selection));
+            let capped = context::cap_bytes(selection, settings::get_number("max_selection_bytes"));
+            let markdown = format!("\n**Selected Text:**\n```\n{}\n```\n", capped);
+            push("Visual Selection", selection, markdown);
         }
 
         if let Some(ref diagnostics) = self.lsp_diagnostics {
-            ctx.push_str(&format!("\n**LSP Diagnostics:**\n```\n{}\n```\n", diagnostics));
+            let markdown = format!("\n**LSP Diagnostics:**\n```\n{}\n```\n", diagnostics);
+            push("LSP Diagnostics", diagnostics, markdown);
         }
 
         if let Some(ref content) = self.buffer_content {
             // Truncate if too long
-            let truncated = if content.len() > 5000 {
-                format!("{}...\n(truncated)", &content[..5000])
-            } else {
-                content.clone()
-            };
-            
+            let truncated = context::cap_bytes(content, settings::get_number("max_buffer_bytes"));
+
             let lang = self.filetype.as_deref().unwrap_or("");
-            ctx.push_str(&format!("\n**Buffer Content:**\n```{}\n{}\n```\n", lang, truncated));
+            let markdown = format!("\n**Buffer Content:**\n```{}\n{}\n```\n", lang, truncated);
+            push("Buffer Content", content, markdown);
+        }
+
+        sections
+    }
+
+    /// Format as markdown context for the AI prompt
+    pub fn to_markdown(&self) -> String {
+        let mut ctx = String::new();
+        ctx.push_str("## Neovim Context\n\n");
+
+        for section in self.sections() {
+            ctx.push_str(&section.markdown);
         }
 
         ctx
     }
+
+    /// Size of a named section in the prompt, formatted for display
+    fn section_size(&self, name: &str) -> String {
+        let size = self
+            .sections()
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.size())
+            .unwrap_or(0);
+        context::format_size(size)
+    }
 }
 
 /// Neovim-specific result actions
@@ -143,12 +234,15 @@ pub struct NvimApp {
     pub context_file: String,
     pub nvim_actions: Vec<NvimResultAction>,
     pub nvim_selected: usize,
+    /// Waiting for the user to confirm a Run action
+    pub confirming_run: bool,
 }
 
 impl NvimApp {
-    pub fn new(context_file: &str) -> Result<Self> {
-        let nvim_context = NvimContext::from_file(context_file)?;
-        let base = App::new()?;
+    pub fn new(context_file: &str, nvim_context: NvimContext) -> Result<Self> {
+        let mut base = App::new()?;
+        // The Neovim view has its own result actions, shown once the response is complete
+        base.stream_responses = false;
 
         Ok(Self {
             base,
@@ -162,6 +256,7 @@ impl NvimApp {
                 NvimResultAction::Cancel,
             ],
             nvim_selected: 0,
+            confirming_run: false,
         })
     }
 
@@ -170,7 +265,7 @@ impl NvimApp {
         let mut ctx = String::new();
 
         // Terminal context (respects privacy settings)
-        ctx.push_str(&context::gather_context()?);
+        ctx.push_str(&context::gather_context()?.to_markdown());
 
         // Neovim-specific context
         ctx.push('\n');
@@ -200,13 +295,19 @@ impl NvimApp {
 
         if let Some(ref current_line) = self.nvim_context.current_line {
             lines.push(String::new());
-            lines.push("Current Line:".to_string());
+            lines.push(format!(
+                "Current Line: ({})",
+                self.nvim_context.section_size("Current Line")
+            ));
             lines.push(format!("  {}", current_line));
         }
 
         if let Some(ref selection) = self.nvim_context.visual_selection {
             lines.push(String::new());
-            lines.push("Visual Selection:".to_string());
+            lines.push(format!(
+                "Visual Selection: ({})",
+                self.nvim_context.section_size("Visual Selection")
+            ));
             for line in selection.lines().take(10) {
                 lines.push(format!("  {}", line));
             }
@@ -217,7 +318,10 @@ impl NvimApp {
 
         if let Some(ref diagnostics) = self.nvim_context.lsp_diagnostics {
             lines.push(String::new());
-            lines.push("LSP Diagnostics:".to_string());
+            lines.push(format!(
+                "LSP Diagnostics: ({})",
+                self.nvim_context.section_size("LSP Diagnostics")
+            ));
             for line in diagnostics.lines().take(5) {
                 lines.push(format!("  {}", line));
             }
@@ -225,10 +329,13 @@ impl NvimApp {
 
         if let Some(ref content) = self.nvim_context.buffer_content {
             lines.push(String::new());
-            lines.push(format!("Buffer Content: {} chars", content.len()));
+            lines.push(format!(
+                "Buffer Content: {} chars ({})",
+                content.len(),
+                self.nvim_context.section_size("Buffer Content")
+            ));
             lines.push("  (first 500 chars)".to_string());
-            let preview: String = content.chars().take(500).collect();
-            for line in preview.lines().take(10) {
+            for line in format::truncate_chars(content, 500).lines().take(10) {
                 lines.push(format!("  {}", line));
             }
             if content.lines().count() > 10 {
@@ -249,9 +356,6 @@ impl NvimApp {
 
     /// Start an async query with Neovim context
     pub fn start_nvim_query(&mut self, query: &str) -> Result<()> {
-        use std::sync::mpsc;
-        use std::thread;
-
         // Save to prompt history
         session::add_to_prompt_history(query)?;
 
@@ -262,23 +366,10 @@ impl NvimApp {
         let history = session::get_session_history()?;
 
         // Build full prompt
-        let full_prompt = provider::build_full_prompt(query, &ctx, history.as_deref());
-
-        // Store the query for session saving later
-        self.base.pending_query = Some(query.to_string());
+        let full_prompt =
+            provider::build_full_prompt(query, &ctx, history.as_deref(), QueryMode::Command);
 
-        // Create channel for result
-        let (tx, rx) = mpsc::channel();
-        self.base.query_receiver = Some(rx);
-
-        // Run query in background thread
-        thread::spawn(move || {
-            let result = provider::run_query(&full_prompt);
-            let _ = tx.send(result.map_err(|e| e.to_string()));
-        });
-
-        // Set loading state
-        self.base.state = AppState::Loading;
+        self.base.spawn_query(query, full_prompt, QueryMode::Command);
 
         Ok(())
     }
@@ -289,6 +380,8 @@ fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    // Lets terminals with the kitty keyboard protocol report Shift+Enter; others ignore it
+    execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES))?;
     let backend = CrosstermBackend::new(stdout);
     let terminal = Terminal::new(backend)?;
     Ok(terminal)
@@ -299,6 +392,7 @@ fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
+        PopKeyboardEnhancementFlags,
         LeaveAlternateScreen,
         DisableMouseCapture
     )?;
@@ -397,41 +491,78 @@ fn render_nvim(frame: &mut ratatui::Frame, app: &NvimApp) {
 
             frame.render_widget(response_text, content_chunks[0]);
 
-            // Neovim-specific actions
-            let actions: Vec<ListItem> = app
-                .nvim_actions
-                .iter()
-                .enumerate()
-                .map(|(i, action)| {
-                    let style = if i == app.nvim_selected {
-                        Style::default()
-                            .fg(Color::Magenta)
-                            .add_modifier(Modifier::BOLD)
-                    } else {
-                        Style::default()
-                    };
-
-                    let prefix = if i == app.nvim_selected { "▶ " } else { "  " };
-                    let text = match action {
-                        NvimResultAction::Insert => "Insert at cursor",
-                        NvimResultAction::Replace => "Replace line/selection",
-                        NvimResultAction::Run => "Run/execute keys",
-                        NvimResultAction::Copy => "Copy to clipboard",
-                        NvimResultAction::Cancel => "Cancel",
-                    };
-
-                    ListItem::new(Line::from(format!("{}{}", prefix, text))).style(style)
-                })
-                .collect();
-
-            let action_list = List::new(actions).block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title(" Actions ")
-                    .border_style(Style::default().fg(Color::White)),
-            );
+            if app.confirming_run {
+                // Show exactly what will be sent to Neovim before running it
+                let (kind, shown) = if crate::app::contains_special_keys(response) {
+                    ("Keystrokes", response.clone())
+                } else {
+                    ("Ex command", format!(":{}", response.trim_start_matches(':')))
+                };
+
+                let confirm_lines = vec![
+                    Line::from(Span::styled(
+                        "Run this in Neovim?",
+                        Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                    )),
+                    Line::from(""),
+                    Line::from(vec![
+                        Span::styled(format!("{}: ", kind), Style::default().fg(Color::Gray)),
+                        Span::styled(shown, Style::default().fg(Color::White)),
+                    ]),
+                    Line::from(""),
+                    Line::from(Span::styled(
+                        "y/Enter: Run | n/Esc: Back",
+                        Style::default().fg(Color::DarkGray),
+                    )),
+                ];
+
+                let confirm = Paragraph::new(confirm_lines)
+                    .wrap(Wrap { trim: false })
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title(" Confirm Run ")
+                            .border_style(Style::default().fg(Color::Yellow)),
+                    );
+
+                frame.render_widget(confirm, content_chunks[1]);
+            } else {
+                // Neovim-specific actions
+                let actions: Vec<ListItem> = app
+                    .nvim_actions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, action)| {
+                        let style = if i == app.nvim_selected {
+                            Style::default()
+                                .fg(Color::Magenta)
+                                .add_modifier(Modifier::BOLD)
+                        } else {
+                            Style::default()
+                        };
+
+                        let prefix = if i == app.nvim_selected { "▶ " } else { "  " };
+                        let text = match action {
+                            NvimResultAction::Insert => "Insert at cursor",
+                            NvimResultAction::Replace => "Replace line/selection",
+                            NvimResultAction::Run => "Run/execute keys",
+                            NvimResultAction::Copy => "Copy to clipboard",
+                            NvimResultAction::Cancel => "Cancel",
+                        };
+
+                        ListItem::new(Line::from(format!("{}{}", prefix, text))).style(style)
+                    })
+                    .collect();
+
+                let action_list = List::new(actions).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(" Actions ")
+                        .border_style(Style::default().fg(Color::White)),
+                );
 
-            frame.render_widget(action_list, content_chunks[1]);
+                frame.render_widget(action_list, content_chunks[1]);
+            }
         }
         AppState::MainMenu => {
             // Render menu
@@ -448,8 +579,11 @@ fn render_nvim(frame: &mut ratatui::Frame, app: &NvimApp) {
                     let text = match item {
                         crate::app::MenuItem::AskQuestion => "Ask a question",
                         crate::app::MenuItem::RecentPrompts => "Recent prompts",
+                        crate::app::MenuItem::Templates => "Templates",
                         crate::app::MenuItem::ViewContext => "View context",
                         crate::app::MenuItem::PrivacySettings => "Privacy settings",
+                        crate::app::MenuItem::ResponseCache => "Response cache",
+                        crate::app::MenuItem::TagSession => "Tag session",
                         crate::app::MenuItem::ClearConversation => "Clear conversation",
                         crate::app::MenuItem::Exit => "Exit",
                     };
@@ -469,26 +603,16 @@ fn render_nvim(frame: &mut ratatui::Frame, app: &NvimApp) {
             // Render input
             let input_chunks = Layout::default()
                 .direction(Direction::Vertical)
-                .constraints([Constraint::Length(3), Constraint::Min(1)])
+                .constraints([Constraint::Length(ui::input_height(&app.base.input)), Constraint::Min(1)])
                 .split(chunks[1]);
 
-            let input = Paragraph::new(app.base.input.as_str())
-                .style(Style::default().fg(Color::White))
-                .block(
-                    Block::default()
-                        .borders(Borders::ALL)
-                        .title(" What do you need? ")
-                        .border_style(Style::default().fg(Color::Magenta)),
-                );
-            frame.render_widget(input, input_chunks[0]);
-
-            // Show cursor
-            frame.set_cursor_position((
-                input_chunks[0].x + app.base.cursor_position as u16 + 1,
-                input_chunks[0].y + 1,
-            ));
+            let block = Block::default()
+                .borders(Borders::ALL)
+                .title(format!(" {} ", app.base.input_label))
+                .border_style(Style::default().fg(Color::Magenta));
+            ui::render_input_box(frame, &app.base, block, input_chunks[0]);
 
-            let help = Paragraph::new("Press Enter to submit, Esc to cancel")
+            let help = Paragraph::new("Press Enter to submit, Alt+Enter for a new line, Esc to cancel")
                 .style(Style::default().fg(Color::Gray))
                 .alignment(Alignment::Center);
             frame.render_widget(help, input_chunks[1]);
@@ -509,6 +633,7 @@ fn render_nvim(frame: &mut ratatui::Frame, app: &NvimApp) {
                     format!("Using {}", app.base.current_provider),
                     Style::default().fg(Color::DarkGray),
                 )),
+                Line::from(Span::styled("Esc: Cancel", Style::default().fg(Color::DarkGray))),
             ];
 
             let loading = Paragraph::new(loading_text)
@@ -583,7 +708,9 @@ fn render_nvim(frame: &mut ratatui::Frame, app: &NvimApp) {
 
     // Center: Help text
     let help_text = match &app.base.state {
+        AppState::ShowingResult { .. } if app.confirming_run => "y/Enter: Run | n/Esc: Back",
         AppState::ShowingResult { .. } => "↑↓: Navigate | Enter: Select | Esc: Cancel",
+        AppState::Error { .. } if app.base.can_retry() => "r: Retry | Enter/Esc: Continue",
         _ => "↑↓: Navigate | Enter: Select | q: Quit",
     };
     let help = Paragraph::new(help_text)
@@ -613,9 +740,11 @@ fn render_nvim(frame: &mut ratatui::Frame, app: &NvimApp) {
 }
 
 /// Run Neovim interactive mode
-pub fn run_nvim_mode(context_file: &str) -> Result<()> {
+pub fn run_nvim_mode(context_file: &str, json_context: Option<&str>) -> Result<()> {
+    let nvim_context = NvimContext::load(context_file, json_context)?;
+    nvim_context.apply_prompt_override();
     let mut terminal = setup_terminal()?;
-    let mut app = NvimApp::new(context_file)?;
+    let mut app = NvimApp::new(context_file, nvim_context)?;
     let event_handler = EventHandler::new(100);
 
     // Clean up stale sessions
@@ -624,10 +753,15 @@ pub fn run_nvim_mode(context_file: &str) -> Result<()> {
     let mut result_action: Option<NvimResultAction> = None;
 
     while app.base.running {
+        if let Some(signal) = event_handler.terminate_signal() {
+            crate::app::exit_on_signal(&mut terminal, &mut app.base, signal);
+        }
+
         // Check if async query is complete
         if matches!(app.base.state, AppState::Loading) {
             if app.base.check_query_complete()? {
                 app.nvim_selected = 0;  // Reset action selection when result comes in
+                app.confirming_run = false;
             }
             app.base.tick_spinner();
         }
@@ -635,31 +769,53 @@ pub fn run_nvim_mode(context_file: &str) -> Result<()> {
         // Draw UI
         terminal.draw(|f| render_nvim(f, &app))?;
 
-        // Handle events (but not during loading - just animate)
+        // Handle events (during loading only Esc and q, to cancel the query)
         if let Some(event) = event_handler.next()? {
+            let AppEvent::Key(key) = event;
             if matches!(app.base.state, AppState::Loading) {
-                continue;  // Skip input during loading
+                app.base.handle_loading_key(key_to_action(key));
+                continue;
             }
 
-            let AppEvent::Key(key) = event;
             match &app.base.state {
                 AppState::ShowingResult { .. } => {
                     // Handle Neovim-specific result actions
                     let action = key_to_action(key);
-                    match action {
-                        KeyAction::Up => {
-                            if app.nvim_selected > 0 {
-                                app.nvim_selected -= 1;
+                    if app.confirming_run {
+                        match action {
+                            KeyAction::Select | KeyAction::Char('y') => {
+                                result_action = Some(NvimResultAction::Run);
+                                app.base.running = false;
                             }
-                        }
-                        KeyAction::Down => {
-                            if app.nvim_selected < app.nvim_actions.len() - 1 {
-                                app.nvim_selected += 1;
+                            KeyAction::Back | KeyAction::Char('n') => {
+                                app.confirming_run = false;
+                            }
+                            KeyAction::Quit => {
+                                result_action = Some(NvimResultAction::Cancel);
+                                app.base.running = false;
                             }
+                            _ => {}
+                        }
+                        continue;
+                    }
+
+                    match action {
+                        KeyAction::Up if app.nvim_selected > 0 => {
+                            app.nvim_selected -= 1;
+                        }
+                        KeyAction::Down if app.nvim_selected < app.nvim_actions.len() - 1 => {
+                            app.nvim_selected += 1;
                         }
                         KeyAction::Select => {
-                            result_action = Some(app.nvim_actions[app.nvim_selected].clone());
-                            app.base.running = false;
+                            let selected = app.nvim_actions[app.nvim_selected].clone();
+                            if selected == NvimResultAction::Run
+                                && settings::is_enabled("nvim_confirm_run")
+                            {
+                                app.confirming_run = true;
+                            } else {
+                                result_action = Some(selected);
+                                app.base.running = false;
+                            }
                         }
                         KeyAction::Back | KeyAction::Quit => {
                             result_action = Some(NvimResultAction::Cancel);
@@ -674,11 +830,9 @@ pub fn run_nvim_mode(context_file: &str) -> Result<()> {
                     let action = key_to_input_action(key);
                     
                     match action {
-                        KeyAction::Select => {
-                            if !app.base.input.trim().is_empty() {
-                                let query = app.base.input.clone();
-                                app.start_nvim_query(&query)?;
-                            }
+                        KeyAction::Select if !app.base.input.trim().is_empty() => {
+                            let query = app.base.input.clone();
+                            app.start_nvim_query(&query)?;
                         }
                         _ => {
                             // Use base handling for other input actions
@@ -686,6 +840,14 @@ pub fn run_nvim_mode(context_file: &str) -> Result<()> {
                         }
                     }
                 }
+                AppState::Error { .. }
+                    if key_to_action(key) == KeyAction::Char('r') && app.base.can_retry() =>
+                {
+                    // Retry with Neovim context
+                    if let Some(query) = app.base.last_query.clone() {
+                        app.start_nvim_query(&query)?;
+                    }
+                }
                 _ => {
                     // Use base app handling for other states
                     app.base.handle_key(AppEvent::Key(key))?;
@@ -715,11 +877,12 @@ pub fn run_nvim_mode(context_file: &str) -> Result<()> {
 }
 
 /// Run Neovim quick query mode (non-interactive)
-pub fn run_nvim_query_mode(context_file: &str, query: &str) -> Result<()> {
-    let nvim_context = NvimContext::from_file(context_file)?;
+pub fn run_nvim_query_mode(context_file: &str, json_context: Option<&str>, query: &str) -> Result<()> {
+    let nvim_context = NvimContext::load(context_file, json_context)?;
+    nvim_context.apply_prompt_override();
 
     // Get terminal context
-    let terminal_ctx = context::gather_context()?;
+    let terminal_ctx = context::gather_context()?.to_markdown();
 
     // Combine contexts
     let mut full_ctx = terminal_ctx;
@@ -727,13 +890,11 @@ pub fn run_nvim_query_mode(context_file: &str, query: &str) -> Result<()> {
     full_ctx.push_str(&nvim_context.to_markdown());
 
     // Build prompt
-    let full_prompt = provider::build_full_prompt(query, &full_ctx, None);
+    let full_prompt = provider::build_full_prompt(query, &full_ctx, None, QueryMode::Command);
 
     // Run query
-    let response = provider::run_query(&full_prompt)?;
+    let response = provider::run_query(&full_prompt, QueryMode::Command)?;
 
     // Print response
-    println!("{}", response);
-
-    Ok(())
+    crate::app::print_stdout(&response)
 }
@@ -0,0 +1,74 @@
+/// Context for a kind of task, picked with Ctrl+T before asking. Its toggles
+/// override the privacy settings for that one query; `privacy_mode` still wins.
+#[derive(Debug, PartialEq)]
+pub struct TaskPreset {
+    pub name: &'static str,
+    pub settings: &'static [(&'static str, &'static str)],
+}
+
+/// Built-in presets, in the order Ctrl+T cycles through them
+pub const TASK_PRESETS: &[TaskPreset] = &[
+    TaskPreset {
+        name: "git",
+        settings: &[
+            ("send_git_status", "true"),
+            ("send_git_diff", "true"),
+            ("send_shell_history", "false"),
+            ("send_terminal_content", "false"),
+            ("send_infra_summary", "false"),
+        ],
+    },
+    TaskPreset {
+        name: "docker",
+        settings: &[
+            ("send_working_dir", "true"),
+            ("send_env_var_names", "true"),
+            ("send_shell_history", "true"),
+            ("send_infra_summary", "true"),
+            ("send_git_status", "false"),
+            ("send_git_diff", "false"),
+        ],
+    },
+    TaskPreset {
+        name: "debug",
+        settings: &[
+            ("send_terminal_content", "true"),
+            ("send_last_exit", "true"),
+            ("send_shell_history", "true"),
+            ("send_current_process", "true"),
+            ("send_git_diff", "false"),
+        ],
+    },
+];
+
+/// Preset after `current` for Ctrl+T, ending with none before starting over
+pub fn next(current: Option<usize>) -> Option<usize> {
+    match current {
+        None => Some(0),
+        Some(i) if i + 1 < TASK_PRESETS.len() => Some(i + 1),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings;
+
+    #[test]
+    fn test_presets_only_set_privacy_toggles() {
+        let toggles: Vec<&str> = settings::PRIVACY_SETTINGS.iter().map(|(key, _)| *key).collect();
+        for preset in TASK_PRESETS {
+            for (key, value) in preset.settings {
+                assert!(toggles.contains(key), "{} sets {}", preset.name, key);
+                assert!(matches!(*value, "true" | "false"));
+            }
+        }
+
+        let mut seen = vec![next(None)];
+        while let Some(current) = *seen.last().unwrap() {
+            seen.push(next(Some(current)));
+        }
+        assert_eq!(seen, [Some(0), Some(1), Some(2), None]);
+    }
+}
@@ -0,0 +1,117 @@
+//! Golden snapshots of the full prompt for fixed settings and context. Any
+//! change to the prompt text shows up as a failing diff here; if it is
+//! intended, regenerate the snapshots with
+//! `UPDATE_GOLDEN=1 cargo test prompt_golden` and review them.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::context::{self, FakeSources};
+use crate::provider::{self, QueryMode};
+use crate::settings::TestDir;
+
+/// A temporary command-k directory, removed when dropped
+struct Fixture {
+    dir: TestDir,
+}
+
+impl Fixture {
+    /// Fresh fixture with these settings and the mock provider
+    fn new(name: &str, extra_settings: &str) -> Self {
+        let dir = TestDir::new(&format!("golden-{}", name));
+        dir.write_settings(&format!("ai_provider=mock\ntarget_shell=zsh\n{}", extra_settings));
+        Fixture { dir }
+    }
+
+    /// Fixed context inputs; no git, so the host repository can't leak into the snapshot
+    fn sources(&self) -> FakeSources {
+        FakeSources {
+            vars: [
+                ("SHELL", "/bin/zsh"),
+                ("HOME", "/home/user"),
+                ("OLDPWD", "/home/user/src"),
+                ("CMDK_LAST_EXIT", "101"),
+                ("CMDK_DIRSTACK", "/home/user/src/app\n/home/user/src\n/tmp"),
+                ("EDITOR", "nvim"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            cwd: Some(self.dir.path.clone()),
+            home: Some(PathBuf::from("/home/user")),
+            terminal_size: Some((120, 40)),
+            git: Vec::new(),
+            history: vec![
+                ": 1700000000:0;cd ~/src/app\n: 1700000001:0;cargo build\n: 1700000002:0;cargo test\n"
+                    .to_string(),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn prompt(&self, query: &str, history: Option<&str>, mode: QueryMode) -> String {
+        let ctx = context::gather_context_from(&self.sources()).unwrap().to_markdown();
+        provider::build_full_prompt(query, &ctx, history, mode)
+            .replace(&self.dir.path.display().to_string(), "<fixture>")
+    }
+}
+
+/// Compare against `tests/golden/<name>.txt`, or write it with UPDATE_GOLDEN set
+fn assert_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.txt", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|_| panic!("missing snapshot {:?}; run with UPDATE_GOLDEN=1", path));
+    assert!(
+        expected == actual,
+        "prompt differs from {:?}; run with UPDATE_GOLDEN=1 and review the diff\n--- actual ---\n{}",
+        path,
+        actual
+    );
+}
+
+#[test]
+fn golden_command_prompt() {
+    let fixture = Fixture::new("command", "");
+    assert_golden("command", &fixture.prompt("find large log files", None, QueryMode::Command));
+}
+
+#[test]
+fn golden_explain_prompt_with_persona_and_history() {
+    let fixture = Fixture::new(
+        "explain",
+        "persona=You are a patient Rust mentor.\nresponse_language=German\n",
+    );
+    let history = "## User: list files\n\n## Assistant:\nls -la\n";
+    assert_golden(
+        "explain_persona_history",
+        &fixture.prompt("what does -la do", Some(history), QueryMode::Explain),
+    );
+}
+
+#[test]
+fn golden_safe_mode_minimal_context() {
+    // Most context off, as with a privacy profile for demos
+    let fixture = Fixture::new(
+        "safe",
+        "safe_mode=true\nsend_shell_history=false\nsend_env_var_names=false\n\
+         send_terminal_size=false\nsend_last_exit=false\n",
+    );
+    assert_golden("safe_mode_minimal", &fixture.prompt("clean up old builds", None, QueryMode::Command));
+}
+
+#[test]
+fn golden_answer_without_context() {
+    // Ctrl+G: the query is sent without any context
+    let _fixture = Fixture::new("answer", "");
+    assert_golden(
+        "answer_no_context",
+        &provider::build_full_prompt("what is a symlink", "", None, QueryMode::Answer),
+    );
+}
@@ -1,7 +1,16 @@
 use anyhow::{anyhow, Context, Result};
-use std::io::Write;
-use std::process::{Command, Stdio};
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::attachment::Attachment;
+use crate::capabilities;
 use crate::settings;
 
 /// AI Provider types
@@ -10,7 +19,18 @@ pub enum Provider {
     Claude,
     Codex,
     Custom(String),
+    /// An OpenAI-compatible `/chat/completions` endpoint, with the API key
+    /// read from the `api_key_env` environment variable
+    OpenAiHttp {
+        base_url: String,
+        model: String,
+        api_key_env: String,
+        /// Left to the server when None
+        temperature: Option<f64>,
+    },
     Mock,
+    /// Disabled: never spawns a process or makes a network call
+    None,
 }
 
 impl std::fmt::Display for Provider {
@@ -19,11 +39,232 @@ impl std::fmt::Display for Provider {
             Provider::Claude => write!(f, "Claude"),
             Provider::Codex => write!(f, "Codex"),
             Provider::Custom(_) => write!(f, "Custom"),
+            Provider::OpenAiHttp { model, .. } => write!(f, "HTTP ({})", model),
             Provider::Mock => write!(f, "Mock (test)"),
+            Provider::None => write!(f, "None (disabled)"),
         }
     }
 }
 
+impl Provider {
+    /// Whether images can be sent alongside the prompt, for vision models.
+    /// HTTP providers get them as `image_url` parts; none of the CLI providers
+    /// can, so attachments are inlined into the context instead.
+    pub fn supports_attachments(&self) -> bool {
+        matches!(self, Provider::OpenAiHttp { .. })
+    }
+
+    /// Whether the provider can be asked for a `{command, explanation}` JSON
+    /// response instead of free text: codex if it has `--output-schema`, HTTP
+    /// providers through `response_format`.
+    pub fn supports_structured_output(&self) -> bool {
+        matches!(self, Provider::Codex | Provider::OpenAiHttp { .. })
+    }
+
+    /// Whether the provider process can be kept read-only for `safe_mode`:
+    /// codex via `--sandbox read-only`, claude via `--permission-mode plan`
+    pub fn enforces_read_only(&self) -> bool {
+        match self {
+            Provider::Codex => capabilities::supported_flags("codex", &["exec", "--help"], CODEX_FLAGS)
+                .is_none_or(|flags| flags.iter().any(|f| f == "--sandbox")),
+            Provider::Claude => claude_supports_plan_mode(),
+            _ => false,
+        }
+    }
+
+    /// Identifier used in settings and logs (e.g. "claude")
+    pub fn id(&self) -> &'static str {
+        match self {
+            Provider::Claude => "claude",
+            Provider::Codex => "codex",
+            Provider::Custom(_) => "custom",
+            Provider::OpenAiHttp { .. } => "http",
+            Provider::Mock => "mock",
+            Provider::None => "none",
+        }
+    }
+}
+
+/// Why a query failed, so the UI can offer the right follow-up action
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum QueryError {
+    #[error("{0}")]
+    ProviderMissing(String),
+    #[error("Provider timed out after {0}s")]
+    Timeout(u64),
+    #[error("The AI provider is rate limiting requests")]
+    RateLimited,
+    #[error("The AI provider returned an empty response")]
+    Empty,
+    #[error("The query was cancelled")]
+    Cancelled,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl QueryError {
+    /// Whether running the same query again might succeed
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, QueryError::ProviderMissing(_))
+    }
+
+    /// A short suggestion shown under the error message
+    pub fn hint(&self) -> &'static str {
+        match self {
+            QueryError::ProviderMissing(_) => "Install claude or codex, or pick another provider in settings (s).",
+            QueryError::Timeout(_) => "The provider may be slow or stuck. Press r to try again.",
+            QueryError::RateLimited => "Wait a moment, then press r to try again.",
+            QueryError::Empty => "Press r to try again, or p to rephrase the question.",
+            QueryError::Cancelled | QueryError::Other(_) => "",
+        }
+    }
+}
+
+impl From<anyhow::Error> for QueryError {
+    /// Failures are given their kind where they happen (see `provider_missing`,
+    /// `cli_error` and `http_error`); anything else is shown as it is
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<QueryError>() {
+            Some(query_err) => query_err.clone(),
+            None => QueryError::Other(err.to_string()),
+        }
+    }
+}
+
+/// Error for a provider that isn't installed or set up
+fn provider_missing(message: impl Into<String>) -> anyhow::Error {
+    QueryError::ProviderMissing(message.into()).into()
+}
+
+/// Error for a CLI provider that exited with a failure. Its stderr is the only
+/// sign of rate limiting a CLI gives, so that is recognized here.
+fn cli_error(name: &str, stderr: &str) -> anyhow::Error {
+    let lower = stderr.to_lowercase();
+    if lower.contains("rate limit") || lower.contains("429") || lower.contains("too many requests") {
+        return QueryError::RateLimited.into();
+    }
+    anyhow!("{} error: {}", name, stderr)
+}
+
+/// Timeout set on the command line, which takes precedence over the settings
+static TIMEOUT_OVERRIDE: OnceLock<u64> = OnceLock::new();
+
+/// Override the query timeout for this process (`--timeout`)
+pub fn set_timeout_override(secs: u64) {
+    let _ = TIMEOUT_OVERRIDE.set(secs);
+}
+
+/// How long a provider may run before it is killed (`None` = no limit)
+pub fn query_timeout() -> Option<Duration> {
+    let secs = TIMEOUT_OVERRIDE.get().copied().unwrap_or_else(|| {
+        // `query_timeout_secs` is the older name, still honoured when set
+        match settings::get_setting("query_timeout_secs") {
+            Ok(value) if !value.trim().is_empty() => value.trim().parse().unwrap_or(0),
+            _ => settings::get_number("provider_timeout_secs") as u64,
+        }
+    });
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Persona and extra instructions for this process, e.g. from the Neovim plugin
+#[derive(Debug, Default)]
+pub struct PromptOverride {
+    /// Replaces the `persona` setting
+    pub persona: Option<String>,
+    /// Added after the built-in rules for the mode
+    pub system_prompt: Option<String>,
+}
+
+static PROMPT_OVERRIDE: OnceLock<PromptOverride> = OnceLock::new();
+
+/// Override the persona and add instructions for every prompt this process builds
+pub fn set_prompt_override(prompt_override: PromptOverride) {
+    let _ = PROMPT_OVERRIDE.set(prompt_override);
+}
+
+/// Wait for a provider process, killing it if it runs past the query timeout
+/// or `cancel` is set
+fn wait_with_timeout(mut child: Child, name: &str, cancel: Option<&AtomicBool>) -> Result<Output> {
+    let timeout = query_timeout();
+    if timeout.is_none() && cancel.is_none() {
+        return child
+            .wait_with_output()
+            .with_context(|| format!("Failed to wait for {}", name));
+    }
+
+    // Drain the pipes on threads so a chatty process can't block on a full pipe
+    let stdout = child.stdout.take().map(|mut out| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = out.read_to_end(&mut buf);
+            buf
+        })
+    });
+    let stderr = drain_stderr(&mut child);
+
+    let status = poll_until_exit(&mut child, name, timeout, cancel)?;
+
+    Ok(Output {
+        status,
+        stdout: stdout.and_then(|h| h.join().ok()).unwrap_or_default(),
+        stderr: stderr.and_then(|h| h.join().ok()).unwrap_or_default(),
+    })
+}
+
+/// Write the prompt to a process's stdin on a thread, so a provider that
+/// doesn't read it can't hold up the timeout and cancel checks. A provider
+/// that exits without reading it reports that through its exit status.
+fn write_prompt(child: &mut Child, prompt: &str) {
+    if let Some(mut stdin) = child.stdin.take() {
+        let prompt = prompt.to_string();
+        thread::spawn(move || {
+            let _ = stdin.write_all(prompt.as_bytes());
+        });
+    }
+}
+
+/// Read a process's stderr on a thread
+fn drain_stderr(child: &mut Child) -> Option<thread::JoinHandle<Vec<u8>>> {
+    child.stderr.take().map(|mut err| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = err.read_to_end(&mut buf);
+            buf
+        })
+    })
+}
+
+/// Wait for a process to exit, killing it once `timeout` passes or `cancel` is set
+fn poll_until_exit(
+    child: &mut Child,
+    name: &str,
+    timeout: Option<Duration>,
+    cancel: Option<&AtomicBool>,
+) -> Result<ExitStatus> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("Failed to wait for {}", name))?
+        {
+            return Ok(status);
+        }
+        let error = if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            Some(QueryError::Cancelled)
+        } else if deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(QueryError::Timeout(timeout.map_or(0, |t| t.as_secs())))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(error.into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
 /// Check if a command exists in PATH
 fn command_exists(cmd: &str) -> bool {
     Command::new("which")
@@ -37,32 +278,73 @@ fn command_exists(cmd: &str) -> bool {
 
 /// Get the current AI provider based on settings
 pub fn get_current_provider() -> Result<Provider> {
-    let provider_setting = settings::get_setting("ai_provider")?;
+    resolve_provider(&settings::get_setting("ai_provider")?)
+}
+
+/// The first available provider of the chain, `ai_provider` then
+/// `fallback_providers`; if none is, the error for `ai_provider`
+pub fn get_available_provider() -> Result<Provider> {
+    match get_current_provider() {
+        Ok(provider) => Ok(provider),
+        Err(e) => fallback_providers()
+            .iter()
+            .find_map(|provider_setting| resolve_provider(provider_setting).ok())
+            .ok_or(e),
+    }
+}
 
-    match provider_setting.as_str() {
+/// The provider an `ai_provider` value stands for, if it is available
+fn resolve_provider(provider_setting: &str) -> Result<Provider> {
+    match provider_setting {
         "claude" => {
             if command_exists("claude") {
                 Ok(Provider::Claude)
             } else {
-                Err(anyhow!("claude not found in PATH"))
+                Err(provider_missing("claude not found in PATH"))
             }
         }
         "codex" => {
             if command_exists("codex") {
                 Ok(Provider::Codex)
             } else {
-                Err(anyhow!("codex not found in PATH"))
+                Err(provider_missing("codex not found in PATH"))
             }
         }
         "custom" => {
             let custom_cmd = settings::get_setting("custom_provider_cmd")?;
             if custom_cmd.is_empty() {
-                Err(anyhow!("custom_provider_cmd not set"))
+                Err(provider_missing("custom_provider_cmd not set"))
             } else {
                 Ok(Provider::Custom(custom_cmd))
             }
         }
+        named if named.starts_with("custom:") => {
+            let name = &named["custom:".len()..];
+            settings::custom_provider_cmd(name)
+                .map(Provider::Custom)
+                .ok_or_else(|| {
+                    provider_missing(format!(
+                        "custom provider '{}' not defined (add custom.{}=... to settings)",
+                        name, name
+                    ))
+                })
+        }
+        "http" => {
+            let base_url = settings::get_setting("http_base_url")?;
+            let model = settings::get_setting("http_model")?;
+            if base_url.is_empty() || model.is_empty() {
+                Err(provider_missing("http_base_url and http_model must be set for the http provider"))
+            } else {
+                Ok(Provider::OpenAiHttp {
+                    base_url,
+                    model,
+                    api_key_env: settings::get_setting("http_api_key_env")?,
+                    temperature: http_temperature(),
+                })
+            }
+        }
         "mock" => Ok(Provider::Mock),
+        "none" => Ok(Provider::None),
         _ => {
             // Auto-detect: prefer Claude, fall back to Codex
             if command_exists("claude") {
@@ -70,12 +352,52 @@ pub fn get_current_provider() -> Result<Provider> {
             } else if command_exists("codex") {
                 Ok(Provider::Codex)
             } else {
-                Err(anyhow!("No AI CLI found (install claude or codex)"))
+                Err(provider_missing("No AI CLI found (install claude or codex)"))
             }
         }
     }
 }
 
+/// How to get each kind of provider, shown when none is available
+pub const SETUP_STEPS: [(&str, &str); 4] = [
+    ("Claude", "npm install -g @anthropic-ai/claude-code"),
+    ("Codex", "npm install -g @openai/codex"),
+    ("Custom", "set custom_provider_cmd to any command that reads the prompt on stdin"),
+    ("HTTP", "set http_base_url and http_model for an OpenAI-compatible server"),
+];
+
+/// Setup guidance for a missing provider, as plain text for stderr
+pub fn setup_help() -> String {
+    let mut help = String::from("Install one of these AI providers:\n");
+    for (name, step) in SETUP_STEPS {
+        help.push_str(&format!("  {:<7} {}\n", name, step));
+    }
+    help.push_str(&format!(
+        "Then pick it with `cmdk-rs -s` or ai_provider=... in {}",
+        settings::get_settings_file().display()
+    ));
+    help
+}
+
+/// Values the settings menu cycles `ai_provider` through: the built-in
+/// providers, then `custom` if `custom_provider_cmd` is set, `http` if
+/// `http_base_url` is set, then each named custom provider
+pub fn provider_choices() -> Vec<String> {
+    let mut choices: Vec<String> = ["auto", "claude", "codex"].map(String::from).to_vec();
+    if !settings::get_setting("custom_provider_cmd").unwrap_or_default().is_empty() {
+        choices.push("custom".to_string());
+    }
+    if !settings::get_setting("http_base_url").unwrap_or_default().is_empty() {
+        choices.push("http".to_string());
+    }
+    choices.extend(
+        settings::custom_provider_names()
+            .into_iter()
+            .map(|name| format!("custom:{}", name)),
+    );
+    choices
+}
+
 /// Get display name of current provider
 pub fn get_current_provider_name() -> String {
     match get_current_provider() {
@@ -83,6 +405,8 @@ pub fn get_current_provider_name() -> String {
             let provider_setting = settings::get_setting("ai_provider").unwrap_or_default();
             if provider_setting == "auto" {
                 format!("{} (auto)", provider)
+            } else if let Some(name) = provider_setting.strip_prefix("custom:") {
+                format!("{} ({})", provider, name)
             } else {
                 provider.to_string()
             }
@@ -91,80 +415,551 @@ pub fn get_current_provider_name() -> String {
     }
 }
 
-/// Run an AI query and return the response
-pub fn run_query(prompt: &str) -> Result<String> {
+/// Check that the current provider can start, without sending a prompt.
+/// Runs `--version` for CLI providers, which also primes the OS file cache.
+pub fn probe_provider() -> Result<String> {
     let provider = get_current_provider()?;
+    let program = match provider {
+        Provider::Claude => "claude".to_string(),
+        Provider::Codex => "codex".to_string(),
+        Provider::Custom(ref cmd) => cmd
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow!("Empty custom command"))?
+            .to_string(),
+        Provider::OpenAiHttp { ref base_url, ref api_key_env, .. } => {
+            // Listing models checks the server and the API key without a completion
+            http_request("GET", base_url, "models", api_key_env)
+                .call()
+                .map_err(http_error)?;
+            return Ok(base_url.clone());
+        }
+        Provider::Mock => return Ok("mock".to_string()),
+        Provider::None => return Err(provider_missing("AI provider disabled")),
+    };
+
+    let output = Command::new(&program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {} --version", program))?;
+
+    if !output.status.success() {
+        return Err(anyhow!("{} --version failed", program));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Run an AI query and return the response
+pub fn run_query(prompt: &str, mode: QueryMode) -> Result<String> {
+    run_query_with_attachments(prompt, mode, &[]).map(|(response, _)| response)
+}
+
+/// Run an AI query with files from `--attach`; returns the response and the id
+/// of the provider that gave it. Images are sent to providers that support
+/// attachments; `build_query_prompt` has put the rest in the context.
+pub fn run_query_with_attachments(
+    prompt: &str,
+    mode: QueryMode,
+    attachments: &[Attachment],
+) -> Result<(String, &'static str)> {
+    run_query_with_stderr(prompt, attachments, mode, None).map(|answer| (answer.response, answer.provider))
+}
 
+/// What a provider answered, what it printed on stderr and which provider it was
+struct Answer {
+    response: String,
+    stderr: String,
+    provider: &'static str,
+}
+
+/// Run an AI query; also returns what the provider printed on stderr.
+/// With `fallback_providers` set, the query moves on to them when `ai_provider` fails.
+/// Setting `cancel` kills the provider.
+fn run_query_with_stderr(
+    prompt: &str,
+    attachments: &[Attachment],
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<Answer> {
+    let fallbacks = fallback_providers();
+    if fallbacks.is_empty() {
+        let provider = get_current_provider()?;
+        let (response, stderr) = run_provider_query(&provider, prompt, attachments, mode, cancel)?;
+        return Ok(Answer { response, stderr, provider: provider.id() });
+    }
+
+    let mut chain = vec![settings::get_setting("ai_provider")?];
+    chain.extend(fallbacks);
+    if settings::get_setting("fallback_mode")? == "race" {
+        run_race(&chain, prompt, attachments, mode, cancel)
+    } else {
+        run_sequential(&chain, prompt, attachments, mode, cancel)
+    }
+}
+
+/// Providers to try after `ai_provider`, from the comma-separated `fallback_providers`
+fn fallback_providers() -> Vec<String> {
+    settings::get_setting("fallback_providers")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Run a query with one provider; setting `cancel` kills its process
+fn run_provider_query(
+    provider: &Provider,
+    prompt: &str,
+    attachments: &[Attachment],
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<(String, String)> {
     match provider {
-        Provider::Claude => run_claude_query(prompt),
-        Provider::Codex => run_codex_query(prompt),
-        Provider::Custom(cmd) => run_custom_query(prompt, &cmd),
-        Provider::Mock => run_mock_query(prompt),
+        Provider::Claude => run_claude_query(prompt, cancel),
+        Provider::Codex => run_codex_query(prompt, mode, cancel).map(|response| (response, String::new())),
+        Provider::Custom(cmd) => run_custom_query(prompt, cmd, cancel),
+        Provider::OpenAiHttp { base_url, model, api_key_env, temperature } => {
+            let body = http_body(prompt, model, attachments, mode, *temperature);
+            run_http_query(body, base_url, api_key_env, mode, cancel).map(|response| (response, String::new()))
+        }
+        Provider::Mock => run_mock_query(prompt).map(|response| (response, String::new())),
+        Provider::None => Err(provider_missing("AI provider disabled")),
     }
 }
 
-/// Run a query using Claude CLI
-fn run_claude_query(prompt: &str) -> Result<String> {
-    let mut child = Command::new("claude")
-        .arg("--print")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn claude process")?;
-
-    // Write prompt to stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(prompt.as_bytes())
-            .context("Failed to write to claude stdin")?;
+/// Run one provider of the fallback chain; a blank answer counts as a failure
+fn run_chain_entry(
+    provider: &Provider,
+    prompt: &str,
+    attachments: &[Attachment],
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<Answer> {
+    let (response, stderr) = run_provider_query(provider, prompt, attachments, mode, cancel)?;
+    if response.trim().is_empty() {
+        return Err(QueryError::Empty.into());
+    }
+    Ok(Answer { response, stderr, provider: provider.id() })
+}
+
+/// Try each provider in turn; if all fail, the first one's error is returned
+fn run_sequential(
+    chain: &[String],
+    prompt: &str,
+    attachments: &[Attachment],
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<Answer> {
+    let mut first_error = None;
+    for provider_setting in chain {
+        if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            return Err(QueryError::Cancelled.into());
+        }
+        let result = resolve_provider(provider_setting)
+            .and_then(|provider| run_chain_entry(&provider, prompt, attachments, mode, cancel));
+        match result {
+            Ok(output) => return Ok(output),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.unwrap_or_else(|| anyhow!("No AI provider configured")))
+}
+
+/// Start the first `fallback_race_count` providers at once and take the first
+/// answer, killing the others; if they all fail, the rest are tried in turn
+fn run_race(
+    chain: &[String],
+    prompt: &str,
+    attachments: &[Attachment],
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<Answer> {
+    let count = settings::get_number("fallback_race_count").clamp(1, chain.len());
+    let (racers, rest) = chain.split_at(count);
+
+    let race_cancel = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let mut errors: Vec<Option<anyhow::Error>> = racers.iter().map(|_| None).collect();
+    for (i, provider_setting) in racers.iter().enumerate() {
+        // Resolved here, since settings are read on this thread
+        let provider = match resolve_provider(provider_setting) {
+            Ok(provider) => provider,
+            Err(e) => {
+                errors[i] = Some(e);
+                continue;
+            }
+        };
+        let (tx, cancel, prompt, attachments) = (tx.clone(), race_cancel.clone(), prompt.to_string(), attachments.to_vec());
+        thread::spawn(move || {
+            let _ = tx.send((i, run_chain_entry(&provider, &prompt, &attachments, mode, Some(&cancel))));
+        });
+    }
+    drop(tx);
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(50)) {
+            Ok((_, Ok(output))) => {
+                race_cancel.store(true, Ordering::Relaxed);
+                return Ok(output);
+            }
+            Ok((i, Err(e))) => errors[i] = Some(e),
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            race_cancel.store(true, Ordering::Relaxed);
+            return Err(QueryError::Cancelled.into());
+        }
+    }
+    if let Ok(output) = run_sequential(rest, prompt, attachments, mode, cancel) {
+        return Ok(output);
+    }
+    Err(errors
+        .into_iter()
+        .flatten()
+        .next()
+        .unwrap_or_else(|| anyhow!("No AI provider answered")))
+}
+
+/// Run an AI query, classifying failures and treating a blank response as an error
+pub fn run_query_checked(prompt: &str, mode: QueryMode) -> Result<String, QueryError> {
+    let response = run_query(prompt, mode)?;
+    if response.trim().is_empty() {
+        return Err(QueryError::Empty);
+    }
+    Ok(response)
+}
+
+/// A response, with the reasoning the provider gave for it (`show_reasoning`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub response: String,
+    pub reasoning: Option<String>,
+    /// Age in seconds of the cache entry the response was served from
+    pub from_cache: Option<u64>,
+    /// Id of the provider that answered, which may be a fallback
+    pub provider: String,
+}
+
+/// Like `run_query_checked`, but with `show_reasoning` on, reasoning printed
+/// on stderr or in a leading `<think>` block is split off the response.
+/// Setting `cancel` kills the provider.
+pub fn run_query_with_reasoning(
+    prompt: &str,
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<Reply, QueryError> {
+    finish_reply(run_query_with_stderr(prompt, &[], mode, cancel)?)
+}
+
+/// Progress of a streamed query
+#[derive(Debug)]
+pub enum QueryChunk {
+    /// Output that arrived since the last chunk
+    Partial(String),
+    /// The finished query; the response replaces the partial output
+    Done(Result<Reply, QueryError>),
+}
+
+/// Like `run_query_with_reasoning`, but claude's output is sent line by line as
+/// it arrives, before the finished reply. Other providers only send the reply.
+/// Setting `cancel` kills the provider.
+pub fn run_query_streaming(prompt: &str, mode: QueryMode, tx: &Sender<QueryChunk>, cancel: &AtomicBool) {
+    let result = match get_current_provider() {
+        Ok(Provider::Claude) if fallback_providers().is_empty() => stream_claude_query(prompt, tx, cancel)
+            .map_err(QueryError::from)
+            .and_then(|(response, stderr)| finish_reply(Answer { response, stderr, provider: "claude" })),
+        _ => run_query_with_reasoning(prompt, mode, Some(cancel)),
+    };
+    let _ = tx.send(QueryChunk::Done(result));
+}
+
+/// Split off reasoning if `show_reasoning` is on, and reject a blank response
+fn finish_reply(answer: Answer) -> Result<Reply, QueryError> {
+    let (response, reasoning) = if settings::is_enabled("show_reasoning") {
+        split_reasoning(&answer.response, &answer.stderr)
+    } else {
+        (answer.response, None)
+    };
+    if response.trim().is_empty() {
+        return Err(QueryError::Empty);
+    }
+    Ok(Reply { response, reasoning, from_cache: None, provider: answer.provider.to_string() })
+}
+
+/// Separate a leading `<think>` or `<thinking>` block from the answer; any
+/// stderr output follows it in the reasoning
+fn split_reasoning(response: &str, stderr: &str) -> (String, Option<String>) {
+    let trimmed = response.trim_start();
+    let mut reasoning = Vec::new();
+    let mut answer = response.trim();
+    for tag in ["think", "thinking"] {
+        let Some(rest) = trimmed.strip_prefix(&format!("<{}>", tag)) else {
+            continue;
+        };
+        if let Some((thought, after)) = rest.split_once(&format!("</{}>", tag)) {
+            reasoning.push(thought.trim());
+            answer = after.trim();
+        }
+        break;
     }
+    reasoning.push(stderr.trim());
+    reasoning.retain(|part| !part.is_empty());
+
+    (answer.to_string(), (!reasoning.is_empty()).then(|| reasoning.join("\n\n")))
+}
 
-    let output = child
-        .wait_with_output()
-        .context("Failed to wait for claude process")?;
+/// External command a provider runs; the prompt is written to its stdin
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderCommand {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl ProviderCommand {
+    fn spawn(&self) -> Result<Child> {
+        Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| provider_missing(format!("Failed to spawn {}: {}", self, e)))
+    }
+}
+
+impl std::fmt::Display for ProviderCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c));
+            if plain {
+                write!(f, " {}", arg)?;
+            } else {
+                write!(f, " '{}'", arg.replace('\'', "'\\''"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// File codex writes its final message to, kept out of the shared temp dir
+/// for the same reason as `write_schema_file`
+fn codex_output_file() -> std::path::PathBuf {
+    settings::get_command_k_dir().join(format!("codex-{}.txt", std::process::id()))
+}
+
+/// Build the command line a provider runs with the current settings
+pub fn provider_command(provider: &Provider) -> Result<ProviderCommand> {
+    match provider {
+        Provider::Claude => claude_command(),
+        Provider::Codex => codex_command(None),
+        Provider::Custom(cmd) => custom_command(cmd),
+        Provider::OpenAiHttp { .. } | Provider::Mock | Provider::None => {
+            Err(anyhow!("{} provider doesn't run an external command", provider))
+        }
+    }
+}
+
+fn claude_command() -> Result<ProviderCommand> {
+    // Older and newer CLIs may only have one spelling of print mode
+    let print_flag = capabilities::require_flag("claude", &["--help"], &["--print", "-p"])?;
+
+    let mut args = vec![print_flag];
+    if safe_mode() && claude_supports_plan_mode() {
+        // Plan mode can read but not edit files or run commands
+        args.extend(["--permission-mode".to_string(), "plan".to_string()]);
+    }
+    Ok(ProviderCommand {
+        program: "claude".to_string(),
+        args,
+    })
+}
+
+fn claude_supports_plan_mode() -> bool {
+    capabilities::supported_flags("claude", &["--help"], &["--permission-mode"])
+        .is_some_and(|flags| !flags.is_empty())
+}
+
+/// Whether `safe_mode` is on: keep providers read-only and refuse to run destructive commands
+pub fn safe_mode() -> bool {
+    settings::is_enabled("safe_mode")
+}
+
+/// Codex command line; with `schema`, the final message must match that JSON schema
+/// if this codex supports `--output-schema` (check with `codex_supports_schema`)
+fn codex_command(schema: Option<&Path>) -> Result<ProviderCommand> {
+    // Drop optional flags an installed codex doesn't know, rather than failing
+    let supported = capabilities::supported_flags("codex", &["exec", "--help"], CODEX_FLAGS);
+    let has = |flag: &str| supported.as_ref().is_none_or(|flags| flags.iter().any(|f| f == flag));
+
+    let mut args = vec!["exec"];
+    if has("--skip-git-repo-check") {
+        args.push("--skip-git-repo-check");
+    }
+    // Unlike the flags above, the read-only sandbox is never dropped: without it
+    // codex would run with whatever access it defaults to
+    if !has("--sandbox") {
+        return Err(anyhow!(
+            "your codex version doesn't support --sandbox, so it can't be run read-only; please update it"
+        ));
+    }
+    args.extend(["--sandbox", "read-only"]);
+    if has("-o") {
+        args.push("-o");
+    } else if has("--output-last-message") {
+        args.push("--output-last-message");
+    } else {
+        return Err(anyhow!(
+            "your codex version doesn't support -o/--output-last-message; please update it"
+        ));
+    }
+
+    let mut args: Vec<String> = args.into_iter().map(String::from).collect();
+    args.push(codex_output_file().to_string_lossy().to_string());
+    if let Some(schema) = schema {
+        args.push("--output-schema".to_string());
+        args.push(schema.to_string_lossy().to_string());
+    }
+    args.push("-".to_string());
+    Ok(ProviderCommand {
+        program: "codex".to_string(),
+        args,
+    })
+}
+
+fn custom_command(cmd: &str) -> Result<ProviderCommand> {
+    // Split command into program and args
+    let mut parts = cmd.split_whitespace().map(String::from);
+    let program = parts.next().ok_or_else(|| anyhow!("Empty custom command"))?;
+
+    Ok(ProviderCommand {
+        program,
+        args: parts.collect(),
+    })
+}
+
+/// Run a query using Claude CLI
+fn run_claude_query(prompt: &str, cancel: Option<&AtomicBool>) -> Result<(String, String)> {
+    let mut child = claude_command()?.spawn()?;
+
+    write_prompt(&mut child, prompt);
+
+    let output = wait_with_timeout(child, "claude process", cancel)?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("Claude error: {}", stderr));
+        return Err(cli_error("Claude", &stderr));
     }
 
     let response = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    Ok(response)
+    Ok((response, String::from_utf8_lossy(&output.stderr).to_string()))
 }
 
-/// Run a query using Codex CLI
-fn run_codex_query(prompt: &str) -> Result<String> {
-    // Codex needs special handling with a temp file for output
-    let temp_dir = std::env::temp_dir();
-    let output_file = temp_dir.join(format!("cmdk-codex-{}.txt", std::process::id()));
-
-    let mut child = Command::new("codex")
-        .args([
-            "exec",
-            "--skip-git-repo-check",
-            "--sandbox",
-            "read-only",
-            "-o",
-            output_file.to_str().unwrap(),
-            "-",
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .context("Failed to spawn codex process")?;
+/// Run a query using Claude CLI, sending each line of output as it arrives
+fn stream_claude_query(prompt: &str, tx: &Sender<QueryChunk>, cancel: &AtomicBool) -> Result<(String, String)> {
+    let mut child = claude_command()?.spawn()?;
 
-    // Write prompt to stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(prompt.as_bytes())
-            .context("Failed to write to codex stdin")?;
+    write_prompt(&mut child, prompt);
+
+    let stdout = child.stdout.take().map(|out| {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut response = String::new();
+            for line in BufReader::new(out).lines().map_while(Result::ok) {
+                let line = format!("{}\n", line);
+                let _ = tx.send(QueryChunk::Partial(line.clone()));
+                response.push_str(&line);
+            }
+            response
+        })
+    });
+    let stderr = drain_stderr(&mut child);
+
+    let status = poll_until_exit(&mut child, "claude process", query_timeout(), Some(cancel))?;
+
+    let response = stdout.and_then(|h| h.join().ok()).unwrap_or_default();
+    let stderr = String::from_utf8_lossy(&stderr.and_then(|h| h.join().ok()).unwrap_or_default()).to_string();
+    if !status.success() {
+        return Err(cli_error("Claude", &stderr));
     }
+    Ok((response.trim().to_string(), stderr))
+}
+
+/// Optional codex flags probed from `codex exec --help`
+const CODEX_FLAGS: &[&str] = &[
+    "--skip-git-repo-check",
+    "--sandbox",
+    "-o",
+    "--output-last-message",
+    "--output-schema",
+];
 
-    let status = child
-        .wait()
-        .context("Failed to wait for codex process")?;
+/// Whether the installed codex can constrain its answer to a JSON schema
+fn codex_supports_schema() -> bool {
+    capabilities::supported_flags("codex", &["exec", "--help"], CODEX_FLAGS)
+        .is_some_and(|flags| flags.iter().any(|f| f == "--output-schema"))
+}
+
+/// Write `STRUCTURED_RESPONSE_SCHEMA` to a new file in the command-k directory
+/// that only this user can read. A predictable name in the shared temp dir
+/// could be planted or symlinked by another user first.
+fn write_schema_file() -> Option<std::path::PathBuf> {
+    let path = settings::get_command_k_dir().join(format!("schema-{}.json", std::process::id()));
+    // Left over from an earlier process with the same pid
+    std::fs::remove_file(&path).ok();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options
+        .open(&path)
+        .and_then(|mut file| file.write_all(STRUCTURED_RESPONSE_SCHEMA.as_bytes()));
+    if written.is_err() {
+        std::fs::remove_file(&path).ok();
+        return None;
+    }
+    Some(path)
+}
+
+/// Run a query using Codex CLI
+fn run_codex_query(prompt: &str, mode: QueryMode, cancel: Option<&AtomicBool>) -> Result<String> {
+    // Ask for {command, explanation} JSON when the answer is a command
+    let schema_file = (matches!(mode, QueryMode::Command | QueryMode::Explain)
+        && Provider::Codex.supports_structured_output()
+        && codex_supports_schema())
+    .then(write_schema_file)
+    .flatten();
+    let structured = schema_file.is_some();
+
+    // Codex writes its answer to a file, so none may be left from an earlier run
+    let output_file = codex_output_file();
+    std::fs::remove_file(&output_file).ok();
+    let mut child = codex_command(schema_file.as_deref())?.spawn()?;
+
+    write_prompt(&mut child, prompt);
+
+    let status = wait_with_timeout(child, "codex process", cancel);
+    if let Some(ref schema_file) = schema_file {
+        std::fs::remove_file(schema_file).ok();
+    }
+    if status.is_err() {
+        // A killed codex may leave a partial answer behind
+        std::fs::remove_file(&output_file).ok();
+    }
+    let status = status?.status;
 
     // Read output from temp file
     let response = if output_file.exists() {
@@ -180,46 +975,249 @@ fn run_codex_query(prompt: &str) -> Result<String> {
         return Err(anyhow!("Codex error"));
     }
 
+    if structured {
+        // Free text is still fine if the model ignored the schema
+        if let Some(parsed) = parse_structured_response(&response, mode) {
+            return Ok(parsed);
+        }
+    }
+
     Ok(response)
 }
 
 /// Run a query using a custom command
-fn run_custom_query(prompt: &str, cmd: &str) -> Result<String> {
-    // Split command into program and args
-    let parts: Vec<&str> = cmd.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err(anyhow!("Empty custom command"));
+fn run_custom_query(prompt: &str, cmd: &str, cancel: Option<&AtomicBool>) -> Result<(String, String)> {
+    let mut child = custom_command(cmd)?.spawn()?;
+
+    write_prompt(&mut child, prompt);
+
+    let output = wait_with_timeout(child, "custom command", cancel)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(cli_error("Custom command", &stderr));
+    }
+
+    let response = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((response, String::from_utf8_lossy(&output.stderr).to_string()))
+}
+
+/// Request to `path` under an OpenAI-compatible API, authorized with the key in
+/// `$api_key_env` when it is set (local servers often need none)
+fn http_request(method: &str, base_url: &str, path: &str, api_key_env: &str) -> ureq::Request {
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), path);
+    let request = ureq::request(method, &url);
+    match std::env::var(api_key_env).ok().filter(|key| !api_key_env.is_empty() && !key.is_empty()) {
+        Some(key) => request.set("Authorization", &format!("Bearer {}", key)),
+        None => request,
+    }
+}
+
+/// Error for a failed HTTP request, with the status and body of an error response
+fn http_error(err: ureq::Error) -> anyhow::Error {
+    match err {
+        ureq::Error::Status(429, _) => QueryError::RateLimited.into(),
+        ureq::Error::Status(code, response) => {
+            let url = response.get_url().to_string();
+            let body = response.into_string().unwrap_or_default();
+            anyhow!("HTTP provider error: {} from {}: {}", code, url, body.trim())
+        }
+        ureq::Error::Transport(transport) => anyhow!("HTTP provider request failed: {}", transport),
     }
+}
 
-    let program = parts[0];
-    let args = &parts[1..];
+/// `http_temperature`, if it is set to a number
+fn http_temperature() -> Option<f64> {
+    settings::get_setting("http_temperature").ok()?.trim().parse().ok()
+}
 
-    let mut child = Command::new(program)
-        .args(args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context(format!("Failed to spawn custom command: {}", cmd))?;
+/// Temperature for an HTTP provider's `attempt`th regenerated answer: 0.2, 0.5,
+/// then 0.8, so each try strays further from the first, but never below
+/// `http_temperature`
+pub fn regenerate_temperature(attempt: u32) -> f64 {
+    const STEPS: [f64; 3] = [0.2, 0.5, 0.8];
+    let step = STEPS[(attempt.max(1) as usize - 1).min(STEPS.len() - 1)];
+    http_temperature().map_or(step, |configured| configured.max(step))
+}
 
-    // Write prompt to stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(prompt.as_bytes())
-            .context("Failed to write to custom command stdin")?;
+/// Chat completion request for an OpenAI-compatible server. Image attachments
+/// go with the prompt as `image_url` parts, and command answers are asked for
+/// as `STRUCTURED_RESPONSE_SCHEMA` JSON through `response_format`.
+fn http_body(
+    prompt: &str,
+    model: &str,
+    attachments: &[Attachment],
+    mode: QueryMode,
+    temperature: Option<f64>,
+) -> serde_json::Value {
+    let images: Vec<_> = attachments.iter().filter(|a| a.is_image()).collect();
+    let content = if images.is_empty() {
+        serde_json::json!(prompt)
+    } else {
+        let mut parts = vec![serde_json::json!({"type": "text", "text": prompt})];
+        parts.extend(
+            images
+                .iter()
+                .map(|image| serde_json::json!({"type": "image_url", "image_url": {"url": image.data_url()}})),
+        );
+        serde_json::Value::Array(parts)
+    };
+    let mut body = serde_json::json!({
+        "model": model,
+        "messages": [{"role": "user", "content": content}],
+    });
+    if let Some(temperature) = temperature {
+        body["temperature"] = serde_json::json!(temperature);
     }
+    if matches!(mode, QueryMode::Command | QueryMode::Explain) {
+        if let Ok(schema) = serde_json::from_str::<serde_json::Value>(STRUCTURED_RESPONSE_SCHEMA) {
+            body["response_format"] = serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {"name": "command_response", "strict": true, "schema": schema},
+            });
+        }
+    }
+    body
+}
 
-    let output = child
-        .wait_with_output()
-        .context("Failed to wait for custom command")?;
+/// Run a query as a chat completion against an OpenAI-compatible server
+fn run_http_query(
+    mut body: serde_json::Value,
+    base_url: &str,
+    api_key_env: &str,
+    mode: QueryMode,
+    cancel: Option<&AtomicBool>,
+) -> Result<String> {
+    let request = http_request("POST", base_url, "chat/completions", api_key_env);
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("Custom command error: {}", stderr));
+    // The request blocks, so it runs on its own thread while the timeout and
+    // `cancel` are watched; an abandoned request finishes in the background
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut response = request.clone().send_json(&body);
+        // Servers without `response_format` support reject it; ask again for free text
+        if matches!(response, Err(ureq::Error::Status(400 | 422, _))) {
+            if let Some(fields) = body.as_object_mut().filter(|b| b.contains_key("response_format")) {
+                fields.remove("response_format");
+                response = request.send_json(&body);
+            }
+        }
+        let _ = tx.send(response);
+    });
+    let timeout = query_timeout();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let response = loop {
+        match rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(response) => break response.map_err(http_error)?,
+            Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("HTTP provider request failed")),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            return Err(QueryError::Cancelled.into());
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(QueryError::Timeout(timeout.map_or(0, |t| t.as_secs())).into());
+        }
+    };
+
+    let reply: serde_json::Value = response
+        .into_json()
+        .context("HTTP provider returned invalid JSON")?;
+    let content = reply["choices"][0]["message"]["content"]
+        .as_str()
+        .map(str::trim)
+        .ok_or_else(|| anyhow!("HTTP provider response has no choices[0].message.content"))?;
+    if matches!(mode, QueryMode::Command | QueryMode::Explain) {
+        // Free text is still fine if the server ignored the schema
+        if let Some(parsed) = parse_structured_response(content, mode) {
+            return Ok(parsed);
+        }
     }
+    Ok(content.to_string())
+}
 
-    let response = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    Ok(response)
+/// Heuristic check for commands that delete data or rewrite history
+/// (rm -rf, mkfs, dd, force-push, DROP TABLE, ...), to warn before running them
+pub fn is_destructive(cmd: &str) -> bool {
+    static PATTERNS: OnceLock<Vec<regex_lite::Regex>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            r"\brm\s+(\S+\s+)*-(-recursive|-force|[a-zA-Z]*[rRf])",
+            r"\bmkfs(\.\w+)?\b",
+            r"\bdd\b.*\bof=",
+            r"\b(shred|wipefs)\b",
+            r">\s*/dev/(sd|nvme|hd|disk)",
+            r"\bgit\s+push\b.*\s(--force(-with-lease)?|-f)\b",
+            r"\bgit\s+reset\s+--hard\b",
+            r"\bgit\s+clean\s+-\S*f",
+            r"(?i)\b(drop|truncate)\s+(table|database|schema)\b",
+            r"(?i)\bdelete\s+from\s+\w+\s*(;|$)",
+            r":\(\)\s*\{",
+        ]
+        .iter()
+        .filter_map(|p| regex_lite::Regex::new(p).ok())
+        .collect()
+    });
+    patterns.iter().any(|p| p.is_match(cmd))
+}
+
+/// Whether `cmd` parses as a shell script (`sh -n`, the shell Run uses), to catch
+/// prose or broken syntax before it is run. The last answer is cached since the
+/// result view asks on every frame. True if `sh` can't be started.
+pub fn parses_as_shell(cmd: &str) -> bool {
+    static LAST_CHECK: Mutex<Option<(String, bool)>> = Mutex::new(None);
+    let mut last = LAST_CHECK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((ref checked, parses)) = *last {
+        if checked == cmd {
+            return parses;
+        }
+    }
+
+    let parses = Command::new("sh")
+        .args(["-n", "-c", cmd])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(true, |status| status.success());
+    *last = Some((cmd.to_string(), parses));
+    parses
+}
+
+/// JSON schema for a structured command answer, for providers with a JSON/tool mode
+pub const STRUCTURED_RESPONSE_SCHEMA: &str = r#"{
+  "type": "object",
+  "properties": {
+    "command": { "type": "string", "description": "The exact command to run" },
+    "explanation": { "type": "string", "description": "How the command works; may be empty" }
+  },
+  "required": ["command", "explanation"],
+  "additionalProperties": false
+}"#;
+
+/// A response requested with `STRUCTURED_RESPONSE_SCHEMA`
+#[derive(Debug, Deserialize)]
+struct StructuredResponse {
+    command: String,
+    #[serde(default)]
+    explanation: String,
+}
+
+/// Turn a structured JSON response into the text a mode expects: just the
+/// command, or the command followed by its explanation.
+/// Returns None if the text isn't a structured response.
+pub fn parse_structured_response(text: &str, mode: QueryMode) -> Option<String> {
+    let parsed: StructuredResponse = serde_json::from_str(text.trim()).ok()?;
+    let command = parsed.command.trim();
+    if command.is_empty() {
+        return None;
+    }
+    let explanation = parsed.explanation.trim();
+    if mode == QueryMode::Explain && !explanation.is_empty() {
+        Some(format!("{}\n\n{}", command, explanation))
+    } else {
+        Some(command.to_string())
+    }
 }
 
 /// Run a mock query for testing
@@ -228,12 +1226,38 @@ fn run_mock_query(prompt: &str) -> Result<String> {
     Ok(format!("echo 'Mock response for: {}'", prompt.lines().last().unwrap_or("empty")))
 }
 
-/// Build a full prompt with context and system instructions
-pub fn build_full_prompt(user_query: &str, context: &str, history: Option<&str>) -> String {
-    let mut prompt = String::new();
+/// What kind of answer a query asks for
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum QueryMode {
+    /// Just the command to run
+    #[default]
+    Command,
+    /// The command plus an explanation of how it works
+    Explain,
+    /// A plain prose answer, for questions that aren't about a command
+    Answer,
+    /// A clearer rewrite of the user's request, to be run as a new query
+    Rephrase,
+}
+
+impl QueryMode {
+    /// Mode for new queries, from the `default_mode` setting
+    pub fn from_settings() -> Self {
+        match settings::get_setting("default_mode").unwrap_or_default().as_str() {
+            "explain" => QueryMode::Explain,
+            "answer" => QueryMode::Answer,
+            _ => QueryMode::Command,
+        }
+    }
+}
 
-    prompt.push_str(
-        r#"You are a terminal command assistant. Output ONLY the exact command to run.
+/// Added in safe mode for providers that can't be sandboxed
+const SAFE_MODE_RULES: &str = r#"SAFE MODE: Never suggest commands that delete or overwrite data, format disks, rewrite git history, kill processes or drop databases. If the request needs one, reply with a read-only alternative (a dry run, a listing or a preview) instead.
+
+"#;
+
+/// Rules for command mode: output only the command
+const COMMAND_RULES: &str = r#"You are a terminal command assistant. Output ONLY the exact command to run.
 
 CRITICAL RULES:
 - Output ONLY the command itself - no shell prompts, no $, no explanation
@@ -241,7 +1265,43 @@ CRITICAL RULES:
 - Single command only (use && or ; for multiple)
 - If asked for explanation, then explain - otherwise just the command
 
-SPECIAL KEYS:
+"#;
+
+/// Rules for explain mode: the command followed by a short explanation
+const EXPLAIN_RULES: &str = r#"You are a terminal command assistant. Explain how to do what the user asks.
+
+RULES:
+- Start with the command on its own line - no shell prompts, no $
+- Then explain concisely what the command does and what each part or flag means
+- Plain text only - no markdown headings or code blocks
+- Keep it short - a few lines is usually enough
+
+"#;
+
+/// Rules for answer mode: a direct prose answer
+const ANSWER_RULES: &str = r#"You are a helpful terminal assistant. Answer the user's question directly.
+
+RULES:
+- Answer in plain prose - short paragraphs or a brief list
+- Include a command only if it genuinely helps, on its own line with no shell prompt
+- No markdown headings
+- Be concise
+
+"#;
+
+/// Rules for rephrase mode: rewrite the request instead of answering it
+const REPHRASE_RULES: &str = r#"You are a terminal command assistant. Do NOT answer the request below.
+Instead, rewrite it as a clearer, more specific request that would get a better command.
+
+RULES:
+- Output ONLY the rewritten request as a single line - no quotes, no preamble
+- Keep the user's intent; use the context below to fill in missing details
+- Mention the tool, file or directory involved when the context makes it obvious
+
+"#;
+
+/// Key notation shared by all modes
+const KEY_NOTATION: &str = r#"SPECIAL KEYS:
 When the user needs to press special keys (like exiting vim, or keyboard shortcuts), use this notation:
 - <Esc> - Escape key
 - <Enter> or <CR> - Enter/Return key
@@ -263,8 +1323,84 @@ For vim/vi operations:
 For tmux operations:
 - Use prefix notation like: <C-b>d (Ctrl+B then d)
 
-"#,
-    );
+"#;
+
+/// Shell whose syntax commands should use: the `target_shell` setting, or `$SHELL`
+pub fn target_shell() -> Option<String> {
+    let configured = settings::get_setting("target_shell").unwrap_or_default();
+    let shell = if configured.trim().is_empty() {
+        std::env::var("SHELL").ok()?
+    } else {
+        configured
+    };
+
+    let name = std::path::Path::new(shell.trim())
+        .file_name()?
+        .to_string_lossy()
+        .to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Instruction telling the model which shell syntax to produce
+fn shell_instruction(shell: &str) -> String {
+    let hint = match shell {
+        "fish" => " Use fish syntax, e.g. `set -x VAR value` instead of `export VAR=value` and `(cmd)` instead of `$(cmd)`.",
+        "pwsh" | "powershell" => " Use PowerShell cmdlets and syntax, not POSIX shell constructs.",
+        "nu" => " Use Nushell syntax, not POSIX shell constructs.",
+        _ => "",
+    };
+    format!("TARGET SHELL: Produce {}-compatible syntax.{}\n\n", shell, hint)
+}
+
+/// Build a full prompt with context and system instructions
+pub fn build_full_prompt(
+    user_query: &str,
+    context: &str,
+    history: Option<&str>,
+    mode: QueryMode,
+) -> String {
+    let mut prompt = String::new();
+
+    let prompt_override = PROMPT_OVERRIDE.get();
+
+    // The persona layers a voice or domain focus on top of the rules below
+    let persona = match prompt_override.and_then(|o| o.persona.clone()) {
+        Some(persona) => persona,
+        None => settings::get_setting("persona").unwrap_or_default(),
+    };
+    if !persona.trim().is_empty() {
+        prompt.push_str(persona.trim());
+        prompt.push_str("\n\n");
+    }
+
+    prompt.push_str(match mode {
+        QueryMode::Command => COMMAND_RULES,
+        QueryMode::Explain => EXPLAIN_RULES,
+        QueryMode::Answer => ANSWER_RULES,
+        QueryMode::Rephrase => REPHRASE_RULES,
+    });
+    if let Some(instructions) = prompt_override.and_then(|o| o.system_prompt.as_deref()) {
+        prompt.push_str(&format!("ADDITIONAL INSTRUCTIONS:\n{}\n\n", instructions.trim()));
+    }
+    if mode != QueryMode::Rephrase {
+        if let Some(shell) = target_shell() {
+            prompt.push_str(&shell_instruction(&shell));
+        }
+    }
+    // Prose can follow the user's language; commands stay as they are
+    if matches!(mode, QueryMode::Explain | QueryMode::Answer) {
+        let language = settings::get_setting("response_language").unwrap_or_default();
+        if !language.trim().is_empty() {
+            prompt.push_str(&format!(
+                "LANGUAGE: Write your answer in {}. Keep commands, flags, file names and code unchanged.\n\n",
+                language.trim()
+            ));
+        }
+    }
+    if safe_mode() && !get_current_provider().is_ok_and(|p| p.enforces_read_only()) {
+        prompt.push_str(SAFE_MODE_RULES);
+    }
+    prompt.push_str(KEY_NOTATION);
 
     prompt.push_str(context);
 
@@ -280,14 +1416,210 @@ For tmux operations:
     prompt
 }
 
+/// Prompt for another answer to the same question (`attempt` is how many
+/// answers came before). CLI providers have no temperature to raise (HTTP ones
+/// get `regenerate_temperature`), so the previous answer is included and a
+/// different approach is asked for.
+pub fn build_regenerate_prompt(
+    user_query: &str,
+    context: &str,
+    history: Option<&str>,
+    mode: QueryMode,
+    previous: &str,
+    attempt: u32,
+) -> String {
+    let mut prompt = build_full_prompt(user_query, context, history, mode);
+    prompt.push_str(&format!(
+        "\n## Previous Answer (attempt {}, rejected):\n{}\n\n\
+         Give a different approach than before. Do not repeat the previous answer.\n",
+        attempt,
+        previous.trim()
+    ));
+    prompt
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::settings::TestDir;
 
     #[test]
     fn test_build_prompt() {
-        let prompt = build_full_prompt("list files", "## Context\nShell: zsh", None);
+        let prompt = build_full_prompt(
+            "list files",
+            "## Context\nShell: zsh",
+            None,
+            QueryMode::Command,
+        );
         assert!(prompt.contains("list files"));
         assert!(prompt.contains("terminal command assistant"));
     }
+
+    #[test]
+    fn test_split_reasoning() {
+        let (response, reasoning) = split_reasoning("\n<think>\nthe user wants files\n</think>\n\nls -la\n", "");
+        assert_eq!(response, "ls -la");
+        assert_eq!(reasoning.as_deref(), Some("the user wants files"));
+
+        let (response, reasoning) = split_reasoning("ls -la", "  checked the cwd\n");
+        assert_eq!(response, "ls -la");
+        assert_eq!(reasoning.as_deref(), Some("checked the cwd"));
+
+        // An unclosed tag is left alone
+        assert_eq!(split_reasoning("<thinking>ls", ""), ("<thinking>ls".to_string(), None));
+    }
+
+    #[test]
+    fn test_build_regenerate_prompt() {
+        let prompt = build_regenerate_prompt("list files", "", None, QueryMode::Command, "ls\n", 1);
+        let (original, retry) = prompt.split_once("## User: list files\n").unwrap();
+        assert!(original.contains("terminal command assistant"));
+        assert!(retry.contains("(attempt 1, rejected):\nls\n"));
+        assert!(retry.contains("different approach"));
+
+        let temperatures: Vec<f64> = (1..=4).map(regenerate_temperature).collect();
+        assert_eq!(temperatures, [0.2, 0.5, 0.8, 0.8]);
+    }
+
+    #[test]
+    fn test_parse_structured_response() {
+        let json = r#"{"command": "du -sh *", "explanation": "Shows the size of each entry."}"#;
+        assert_eq!(parse_structured_response(json, QueryMode::Command).unwrap(), "du -sh *");
+        assert_eq!(
+            parse_structured_response(json, QueryMode::Explain).unwrap(),
+            "du -sh *\n\nShows the size of each entry."
+        );
+        assert!(parse_structured_response("du -sh *", QueryMode::Command).is_none());
+    }
+
+    #[test]
+    fn test_is_destructive() {
+        for cmd in [
+            "rm -rf build/",
+            "sudo rm -r /var/tmp/x",
+            "rm --force a.txt",
+            "mkfs.ext4 /dev/sdb1",
+            "dd if=image.iso of=/dev/sdb bs=4M",
+            "git push --force origin main",
+            "git push -f",
+            "psql -c 'DROP TABLE users;'",
+        ] {
+            assert!(is_destructive(cmd), "{}", cmd);
+        }
+        for cmd in ["rm notes.txt", "ls -rf", "git push origin main", "dd --help"] {
+            assert!(!is_destructive(cmd), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn test_parses_as_shell() {
+        assert!(parses_as_shell("find . -name '*.log' | xargs rm"));
+        assert!(parses_as_shell("for f in *.txt; do echo \"$f\"; done"));
+        assert!(!parses_as_shell("Here's how you can list the files"));
+        assert!(!parses_as_shell("if [ -f x ]; then echo y"));
+    }
+
+    #[test]
+    fn test_provider_command_display() {
+        let cmd = custom_command("llm -m gpt-4o --system it's").unwrap();
+        assert_eq!(cmd.program, "llm");
+        assert_eq!(cmd.to_string(), r"llm -m gpt-4o --system 'it'\''s'");
+    }
+
+    #[test]
+    fn test_shell_instruction() {
+        assert!(shell_instruction("fish").contains("set -x"));
+        assert!(shell_instruction("zsh").starts_with("TARGET SHELL: Produce zsh-compatible"));
+    }
+
+    #[test]
+    fn test_query_error_kinds() {
+        let missing = QueryError::from(resolve_provider("custom:nonexistent").unwrap_err());
+        assert!(matches!(missing, QueryError::ProviderMissing(_)));
+        assert!(!missing.is_retryable());
+        assert_eq!(
+            QueryError::from(cli_error("Claude", "Error: 429 Too Many Requests")),
+            QueryError::RateLimited
+        );
+        // The kind survives added context, and messages alone don't decide it
+        assert_eq!(
+            QueryError::from(anyhow::Error::from(QueryError::Empty).context("claude process")),
+            QueryError::Empty
+        );
+        assert_eq!(
+            QueryError::from(cli_error("Claude", "request timed out")),
+            QueryError::Other("Claude error: request timed out".to_string())
+        );
+    }
+
+    #[test]
+    fn test_http_error() {
+        let response = ureq::Response::new(401, "Unauthorized", r#"{"error": "bad key"}"#).unwrap();
+        let message = http_error(ureq::Error::Status(401, response)).to_string();
+        assert!(message.starts_with("HTTP provider error: 401 from "));
+        assert!(message.ends_with(r#"{"error": "bad key"}"#));
+
+        let response = ureq::Response::new(429, "Too Many Requests", "").unwrap();
+        assert_eq!(QueryError::from(http_error(ureq::Error::Status(429, response))), QueryError::RateLimited);
+    }
+
+    #[test]
+    fn test_http_body() {
+        let body = http_body("list files", "llama", &[], QueryMode::Answer, None);
+        assert_eq!(body["messages"][0]["content"], "list files");
+        assert!(body.get("response_format").is_none() && body.get("temperature").is_none());
+
+        let image = Attachment { path: "shot.png".to_string(), mime: "image/png", data: b"png".to_vec() };
+        let body = http_body("what is this", "llama", &[image], QueryMode::Command, Some(0.5));
+        assert_eq!(body["temperature"], 0.5);
+        let content = &body["messages"][0]["content"];
+        assert_eq!(content[0], serde_json::json!({"type": "text", "text": "what is this"}));
+        assert_eq!(content[1]["image_url"]["url"], "data:image/png;base64,cG5n");
+        assert_eq!(body["response_format"]["json_schema"]["schema"]["required"][0], "command");
+    }
+
+    #[test]
+    fn test_fallback_chain() {
+        let dir = TestDir::new("fallback");
+        let query = |conf: &str| {
+            dir.write_settings(&format!("custom.fail=false\ncustom.slow=sleep 30\ncustom.echo=cat\n{}", conf));
+            let started = Instant::now();
+            let response = run_query_with_stderr("answer", &[], QueryMode::Command, None).map(|answer| answer.response);
+            (response, started.elapsed())
+        };
+
+        let (sequential, _) = query("ai_provider=custom:fail\nfallback_providers=custom:echo\n");
+        let (failed, _) = query("ai_provider=custom:fail\nfallback_providers=custom:missing\n");
+        let (raced, elapsed) =
+            query("ai_provider=custom:slow\nfallback_providers=custom:echo\nfallback_mode=race\n");
+        let (after_race, _) = query(
+            "ai_provider=custom:fail\nfallback_providers=custom:fail,custom:echo\n\
+             fallback_mode=race\n",
+        );
+        // An unavailable primary leaves the query to the fallback, which is named as the one answering
+        dir.write_settings("ai_provider=custom:nope\nfallback_providers=mock\n");
+        let available = get_available_provider();
+        let unavailable = run_query_with_stderr("answer", &[], QueryMode::Command, None);
+
+        assert_eq!(sequential.unwrap(), "answer");
+        // The primary provider's error is the one reported
+        assert!(!failed.unwrap_err().to_string().contains("missing"));
+        assert_eq!(raced.unwrap(), "answer");
+        assert!(elapsed < Duration::from_secs(10));
+        assert_eq!(after_race.unwrap(), "answer");
+        assert_eq!(available.unwrap(), Provider::Mock);
+        assert_eq!(unavailable.unwrap().provider, "mock");
+    }
+
+    #[test]
+    fn test_provider_timeout() {
+        let dir = TestDir::new("timeout");
+        dir.write_settings("ai_provider=custom:slow\ncustom.slow=sleep 5\nprovider_timeout_secs=1\n");
+        let started = Instant::now();
+        let result = run_query("answer", QueryMode::Command);
+        let elapsed = started.elapsed();
+
+        assert_eq!(result.unwrap_err().to_string(), "Provider timed out after 1s");
+        assert!(elapsed < Duration::from_secs(4));
+    }
 }
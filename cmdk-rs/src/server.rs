@@ -0,0 +1,243 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::provider::{self, QueryError, QueryMode};
+
+/// Default port for `--serve`
+pub const DEFAULT_PORT: u16 = 7474;
+
+/// Largest request body accepted (1 MB)
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Connections handled at once; more are turned away with 503
+const MAX_CONNECTIONS: usize = 8;
+
+/// How long a client may take to send its request or read the response
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of a `POST /query` request
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    query: String,
+    /// Context supplied by the caller (markdown or plain text); the daemon's own
+    /// terminal state is not gathered since it is unrelated to the caller
+    #[serde(default)]
+    context: Option<String>,
+    #[serde(default)]
+    explain: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryResponse {
+    response: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Run the HTTP daemon on localhost until interrupted
+pub fn run_server(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("Failed to listen on 127.0.0.1:{}", port))?;
+
+    eprintln!("cmdk-rs listening on http://127.0.0.1:{}", port);
+    eprintln!("  POST /query  {{\"query\": \"...\", \"context\": \"...\"}}");
+    eprintln!("  GET  /health");
+
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Connection failed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        {
+            eprintln!("Connection failed: {}", e);
+            continue;
+        }
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            write_response(stream, "503 Service Unavailable", &error_json("Too many connections")).ok();
+            continue;
+        }
+        let slot = ConnectionSlot(Arc::clone(&active));
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(stream) {
+                eprintln!("Request failed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// One of the `MAX_CONNECTIONS` slots, given back when the connection is done
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether a Host or Origin header names this machine, so a web page can't
+/// reach the daemon through a cross-origin request or DNS rebinding
+fn is_local(value: &str) -> bool {
+    let host = value
+        .strip_prefix("http://")
+        .or_else(|| value.strip_prefix("https://"))
+        .unwrap_or(value);
+    let host = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    matches!(host, "127.0.0.1" | "localhost" | "::1")
+}
+
+/// Read one request, answer it and close the connection
+fn handle_connection(stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let mut content_length = 0;
+    let mut content_type = String::new();
+    let mut host = None;
+    let mut origin = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "content-type" => content_type = value.to_ascii_lowercase(),
+                "host" => host = Some(value.to_string()),
+                "origin" => origin = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    // Browsers always send Host, and Origin on cross-origin requests
+    let local = host.as_deref().is_some_and(is_local) && origin.as_deref().is_none_or(is_local);
+    // A form or `text/plain` POST needs no CORS preflight; a JSON one does
+    let json = content_type.split(';').next().is_some_and(|t| t.trim() == "application/json");
+
+    let (status, body) = match (method, path) {
+        _ if !local => ("403 Forbidden", error_json("Only local clients may connect")),
+        ("GET", "/health") => ("200 OK", r#"{"status":"ok"}"#.to_string()),
+        ("POST", "/query") if !json => (
+            "415 Unsupported Media Type",
+            error_json("Content-Type must be application/json"),
+        ),
+        ("POST", "/query") if content_length > MAX_BODY_BYTES => {
+            ("413 Payload Too Large", error_json("Request body too large"))
+        }
+        ("POST", "/query") => {
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            handle_query(&body)
+        }
+        (_, "/query") | (_, "/health") => ("405 Method Not Allowed", error_json("Method not allowed")),
+        _ => ("404 Not Found", error_json("Not found")),
+    };
+
+    write_response(stream, status, &body)
+}
+
+/// Run a query request and build the status and JSON body to send back
+fn handle_query(body: &[u8]) -> (&'static str, String) {
+    let request: QueryRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return ("400 Bad Request", error_json(&format!("Invalid request: {}", e))),
+    };
+
+    if request.query.trim().is_empty() {
+        return ("400 Bad Request", error_json("query must not be empty"));
+    }
+
+    let ctx = match request.context.as_deref() {
+        Some(context) if !context.trim().is_empty() => {
+            format!("## Terminal Context\n\n{}\n", context)
+        }
+        _ => String::new(),
+    };
+    let mode = if request.explain {
+        QueryMode::Explain
+    } else {
+        QueryMode::Command
+    };
+    let full_prompt = provider::build_full_prompt(&request.query, &ctx, None, mode);
+
+    match provider::run_query_checked(&full_prompt, mode) {
+        Ok(response) => (
+            "200 OK",
+            serde_json::to_string(&QueryResponse { response }).unwrap_or_default(),
+        ),
+        Err(e) => {
+            let status = match e {
+                QueryError::ProviderMissing(_) => "503 Service Unavailable",
+                QueryError::RateLimited => "429 Too Many Requests",
+                QueryError::Timeout(_) => "504 Gateway Timeout",
+                QueryError::Empty | QueryError::Cancelled | QueryError::Other(_) => "502 Bad Gateway",
+            };
+            (status, error_json(&e.to_string()))
+        }
+    }
+}
+
+fn error_json(message: &str) -> String {
+    serde_json::to_string(&ErrorResponse {
+        error: message.to_string(),
+    })
+    .unwrap_or_default()
+}
+
+fn write_response(mut stream: TcpStream, status: &str, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_local() {
+        assert!(is_local("localhost:7474"));
+        assert!(is_local("127.0.0.1"));
+        assert!(is_local("[::1]:7474"));
+        assert!(is_local("http://localhost:3000"));
+        assert!(!is_local("evil.example:7474"));
+        assert!(!is_local("https://localhost.evil.example"));
+        assert!(!is_local("null"));
+    }
+}
@@ -1,27 +1,112 @@
-use anyhow::{Context, Result};
-use md5::{Digest, Md5};
-use std::fs;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
 
+use crate::format::{format_response, truncate_chars, Destination};
+use crate::redact;
 use crate::settings;
+#[cfg(feature = "sqlite")]
+use crate::storage;
 
 /// Session timeout in seconds (1 hour)
 const SESSION_TIMEOUT: u64 = 3600;
 
+/// Start of the optional first line of a transcript that holds its tags
+const TAGS_HEADER: &str = "<!-- tags:";
+
+/// Start of the line `format_turn` records after each turn, with its provider and sizes
+const TURN_STATS_PREFIX: &str = "<!-- cmdk:";
+
+/// Turns from this process only, once a fresh session has been started.
+/// The session file is still written, but earlier turns are not sent as history.
+static FRESH_HISTORY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Ignore the existing session for the rest of this process without deleting it
+pub fn start_fresh_session() {
+    if let Ok(mut fresh) = FRESH_HISTORY.lock() {
+        *fresh = Some(String::new());
+    }
+}
+
+/// History of a fresh session started by this process (e.g. a --replay recap), if any
+pub fn fresh_session_history() -> Option<String> {
+    FRESH_HISTORY
+        .lock()
+        .ok()?
+        .clone()
+        .filter(|history| !history.trim().is_empty())
+}
+
+/// Start a fresh session that carries a short recap of the last `replay_turns`
+/// turns of the current one, instead of the full history
+pub fn start_fresh_session_with_recap() -> Result<()> {
+    let history = get_session_history()?.unwrap_or_default();
+    let recap = summarize_recent_turns(&history, settings::get_number("replay_turns"));
+    if let Ok(mut fresh) = FRESH_HISTORY.lock() {
+        *fresh = Some(recap);
+    }
+    Ok(())
+}
+
+/// Condense the last `turns` turns of a session into one line per question and
+/// answer. Answers are cut to their first line; empty if there is nothing to recap.
+fn summarize_recent_turns(history: &str, turns: usize) -> String {
+    const MAX_CHARS: usize = 120;
+    let shorten = |text: &str| -> String {
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with("```"))
+            .unwrap_or("");
+        if line.chars().count() > MAX_CHARS {
+            format!("{}…", truncate_chars(line, MAX_CHARS))
+        } else {
+            line.to_string()
+        }
+    };
+
+    let recent = keep_recent_turns(&strip_turn_stats(history), turns);
+    let mut starts = turn_starts(&recent);
+    starts.push(recent.len());
+    let mut recap = String::new();
+    for bounds in starts.windows(2) {
+        let turn = &recent[bounds[0] + "## User:".len()..bounds[1]];
+        let (question, answer) = turn.split_once("\n## Assistant:").unwrap_or((turn, ""));
+        recap.push_str(&format!("- Asked: {}\n  Answer: {}\n", shorten(question), shorten(answer)));
+    }
+
+    if recap.is_empty() {
+        recap
+    } else {
+        format!("Summary of an earlier, related conversation:\n{}\n", recap)
+    }
+}
+
 /// Get the session file path for the current directory
 pub fn get_session_file() -> PathBuf {
-    let dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-    let dir_str = dir.to_string_lossy();
+    settings::get_command_k_dir().join(format!("cli-session-{}.md", settings::directory_hash()))
+}
 
-    // Hash the directory path (first 8 chars of MD5)
-    let mut hasher = Md5::new();
-    hasher.update(dir_str.as_bytes());
-    let result = hasher.finalize();
-    let hash = format!("{:x}", result);
-    let short_hash = &hash[..8];
+/// Session key for the current directory: the session file's name
+#[cfg(feature = "sqlite")]
+fn session_key() -> String {
+    get_session_file()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
 
-    settings::get_command_k_dir().join(format!("cli-session-{}.md", short_hash))
+/// The SQLite store, if `storage=sqlite` is set
+#[cfg(feature = "sqlite")]
+fn sqlite_store() -> Result<Option<std::sync::Arc<storage::Store>>> {
+    if settings::get_setting("storage")? == "sqlite" {
+        Ok(Some(storage::Store::open()?))
+    } else {
+        Ok(None)
+    }
 }
 
 /// Get the last result file path
@@ -36,6 +121,16 @@ pub fn get_history_file() -> PathBuf {
 
 /// Check if session file is stale and remove it if so
 pub fn cleanup_stale_session() -> Result<()> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        if let Some((_, updated)) = store.session(&session_key())? {
+            if storage::now().saturating_sub(updated) > SESSION_TIMEOUT {
+                store.remove_session(&session_key())?;
+            }
+        }
+        return Ok(());
+    }
+
     let session_file = get_session_file();
 
     if session_file.exists() {
@@ -55,23 +150,43 @@ pub fn cleanup_stale_session() -> Result<()> {
 
 /// Get the conversation history from the session file
 pub fn get_session_history() -> Result<Option<String>> {
+    if let Some(ref fresh) = *FRESH_HISTORY.lock().unwrap_or_else(|e| e.into_inner()) {
+        return Ok(Some(strip_turn_stats(fresh)).filter(|h| !h.trim().is_empty()));
+    }
+
     cleanup_stale_session()?;
 
-    let session_file = get_session_file();
+    let content = read_session_content()?;
+    Ok(Some(strip_turn_stats(strip_tags_header(&content))).filter(|c| !c.trim().is_empty()))
+}
 
+/// Raw transcript of the current session (empty if there is none)
+fn read_session_content() -> Result<String> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return Ok(store.session(&session_key())?.map(|(content, _)| content).unwrap_or_default());
+    }
+
+    let session_file = get_session_file();
     if session_file.exists() {
-        let content = fs::read_to_string(&session_file)
-            .context("Failed to read session file")?;
-        if content.trim().is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(content))
-        }
+        fs::read_to_string(&session_file).context("Failed to read session file")
     } else {
-        Ok(None)
+        Ok(String::new())
     }
 }
 
+/// Replace the transcript of the current session
+fn write_session_content(content: &str) -> Result<()> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return store.write_session(&session_key(), content);
+    }
+
+    fs::create_dir_all(settings::get_command_k_dir())?;
+    fs::write(get_session_file(), content)?;
+    Ok(())
+}
+
 /// Get the number of turns in the current session
 pub fn get_session_turn_count() -> usize {
     if let Ok(Some(history)) = get_session_history() {
@@ -81,27 +196,32 @@ pub fn get_session_turn_count() -> usize {
     }
 }
 
+/// Size information recorded after each turn in the session file
+#[derive(Debug, Clone, Default)]
+pub struct TurnStats {
+    pub provider: String,
+    pub prompt_bytes: usize,
+}
+
 /// Append a user message and response to the session history
-pub fn append_to_session(user_message: &str, response: &str) -> Result<()> {
-    let session_file = get_session_file();
-    let dir = settings::get_command_k_dir();
+pub fn append_to_session(user_message: &str, response: &str, stats: &TurnStats) -> Result<()> {
+    let mut content = read_session_content()?;
+
+    // Rotate out old turns once the session grows past the size limit (0 = unlimited)
+    let max_bytes = settings::get_number("max_session_bytes");
+    if max_bytes > 0 && content.len() > max_bytes {
+        let kept = keep_recent_turns(strip_tags_header(&content), settings::get_number("session_keep_turns"));
+        content = with_tags(&kept, &parse_tags(&content));
+    }
 
-    // Ensure directory exists
-    fs::create_dir_all(&dir)?;
+    let turn = format_turn(user_message, response, stats);
+    content.push_str(&turn);
 
-    // Append to session file
-    let mut content = if session_file.exists() {
-        fs::read_to_string(&session_file)?
-    } else {
-        String::new()
-    };
-
-    content.push_str(&format!("## User: {}\n\n", user_message));
-    content.push_str("## Assistant:\n");
-    content.push_str(response);
-    content.push_str("\n\n");
+    write_session_content(&content)?;
 
-    fs::write(&session_file, content)?;
+    if let Some(ref mut fresh) = *FRESH_HISTORY.lock().unwrap_or_else(|e| e.into_inner()) {
+        fresh.push_str(&turn);
+    }
 
     // Also save the last result
     save_last_result(response)?;
@@ -109,8 +229,286 @@ pub fn append_to_session(user_message: &str, response: &str) -> Result<()> {
     Ok(())
 }
 
+/// Tags of the current session
+pub fn session_tags() -> Result<Vec<String>> {
+    Ok(parse_tags(&read_session_content()?))
+}
+
+/// Replace the tags of the current session (no tags removes the header)
+pub fn set_session_tags(tags: &[String]) -> Result<()> {
+    cleanup_stale_session()?;
+    let content = read_session_content()?;
+    write_session_content(&with_tags(&content, tags))
+}
+
+/// Tags from a comma-separated list: trimmed, without empty or repeated ones
+pub fn parse_tag_list(list: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in list.split(',').map(|t| t.trim().replace("-->", "")) {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Tags in the header of a transcript
+fn parse_tags(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(TAGS_HEADER))
+        .and_then(|rest| rest.trim_end().strip_suffix("-->"))
+        .map(parse_tag_list)
+        .unwrap_or_default()
+}
+
+/// A transcript without its tags header
+fn strip_tags_header(content: &str) -> &str {
+    if content.starts_with(TAGS_HEADER) {
+        content.split_once('\n').map_or("", |(_, rest)| rest)
+    } else {
+        content
+    }
+}
+
+/// A transcript without the stats line after each turn, which is for
+/// `--sessions` and repro reports, not for the model
+fn strip_turn_stats(content: &str) -> String {
+    content
+        .split_inclusive('\n')
+        .filter(|line| !line.starts_with(TURN_STATS_PREFIX))
+        .collect()
+}
+
+/// A transcript with its tags header replaced
+fn with_tags(content: &str, tags: &[String]) -> String {
+    let body = strip_tags_header(content);
+    if tags.is_empty() {
+        body.to_string()
+    } else {
+        format!("{} {} -->\n{}", TAGS_HEADER, tags.join(", "), body)
+    }
+}
+
+/// A stored session, as listed by `--sessions`
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub name: String,
+    pub turns: usize,
+    /// Seconds since the last turn
+    pub age: u64,
+    pub tags: Vec<String>,
+    pub first_question: String,
+}
+
+impl SessionSummary {
+    fn new(name: String, content: &str, age: u64) -> Self {
+        let body = strip_tags_header(content);
+        let first_question = body
+            .lines()
+            .find_map(|line| line.strip_prefix("## User:"))
+            .unwrap_or("")
+            .trim()
+            .to_string();
+        SessionSummary {
+            name,
+            turns: turn_starts(body).len(),
+            age,
+            tags: parse_tags(content),
+            first_question,
+        }
+    }
+}
+
+/// Every stored session, most recently used first
+pub fn list_sessions() -> Result<Vec<SessionSummary>> {
+    let mut sessions = Vec::new();
+
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        for (key, content, updated) in store.sessions()? {
+            let age = storage::now().saturating_sub(updated);
+            sessions.push(SessionSummary::new(key, &content, age));
+        }
+        sessions.sort_by_key(|s| s.age);
+        return Ok(sessions);
+    }
+
+    let Ok(entries) = fs::read_dir(settings::get_command_k_dir()) else {
+        return Ok(sessions);
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("cli-session-") || !name.ends_with(".md") {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let age = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map_or(0, |age| age.as_secs());
+        sessions.push(SessionSummary::new(name, &content, age));
+    }
+    sessions.sort_by_key(|s| s.age);
+    Ok(sessions)
+}
+
+/// A turn as it is stored in the session transcript
+fn format_turn(user_message: &str, response: &str, stats: &TurnStats) -> String {
+    let mut turn = format!("## User: {}\n\n", user_message);
+    turn.push_str("## Assistant:\n");
+    turn.push_str(response);
+    turn.push('\n');
+    turn.push_str(&format!(
+        "<!-- cmdk: provider={} prompt_bytes={} response_bytes={} -->\n\n",
+        stats.provider,
+        stats.prompt_bytes,
+        response.len()
+    ));
+    turn
+}
+
+/// Append a conversation exported from another tool to this directory's
+/// session, so it can be continued. Returns the number of turns imported.
+pub fn import_conversation(path: &str) -> Result<usize> {
+    let markdown = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read conversation file: {}", path))?;
+    let turns = parse_conversation(&markdown)
+        .with_context(|| format!("Failed to import conversation: {}", path))?;
+
+    // Continue the live session, not one that would be dropped as stale
+    cleanup_stale_session()?;
+    let mut content = read_session_content()?;
+    let stats = TurnStats {
+        provider: "import".to_string(),
+        prompt_bytes: 0,
+    };
+    for (user, assistant) in &turns {
+        content.push_str(&format_turn(user, assistant, &stats));
+    }
+    write_session_content(&content)?;
+
+    Ok(turns.len())
+}
+
+/// Split a markdown conversation into (user, assistant) turns. Messages start
+/// at a `User:` or `Assistant:` heading of any level (`Human` and `AI` work
+/// too); anything before the first one, like a title, is skipped. Every user
+/// message needs a reply, so the turn count of the session stays right.
+fn parse_conversation(markdown: &str) -> Result<Vec<(String, String)>> {
+    let mut turns = Vec::new();
+    // The message being read: whether it is the user's, and its lines
+    let mut current: Option<(bool, Vec<&str>)> = None;
+    let mut user_message: Option<String> = None;
+    let mut in_code_block = false;
+
+    let mut finish = |message: Option<(bool, Vec<&str>)>, line_no: usize| -> Result<()> {
+        let Some((is_user, lines)) = message else {
+            return Ok(());
+        };
+        let text = lines.join("\n").trim().to_string();
+        match (is_user, user_message.take()) {
+            (true, None) if text.is_empty() => bail!("line {}: empty user message", line_no),
+            (true, None) => user_message = Some(text),
+            (true, Some(_)) => bail!("line {}: user message without an assistant reply", line_no),
+            (false, None) => bail!("line {}: assistant reply without a user message", line_no),
+            (false, Some(user)) => turns.push((user, text)),
+        }
+        Ok(())
+    };
+
+    for (i, line) in markdown.lines().enumerate() {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        // A `# user: ...` shell comment in a code block is not a heading
+        if let Some((is_user, rest)) = role_heading(line).filter(|_| !in_code_block) {
+            finish(current.take(), i + 1)?;
+            current = Some((is_user, vec![rest]));
+        } else if let Some((_, ref mut lines)) = current {
+            // Our own turn metadata, when re-importing an exported session
+            if !line.starts_with(TURN_STATS_PREFIX) {
+                lines.push(line);
+            }
+        }
+    }
+    let line_count = markdown.lines().count();
+    finish(current.take(), line_count)?;
+
+    if user_message.is_some() {
+        bail!("line {}: user message without an assistant reply", line_count);
+    }
+    if turns.is_empty() {
+        bail!("no `## User:` / `## Assistant:` messages found");
+    }
+    Ok(turns)
+}
+
+/// Whether a line starts a message: the role (true for the user) and any
+/// text after the heading
+fn role_heading(line: &str) -> Option<(bool, &str)> {
+    let heading = line.strip_prefix('#')?.trim_start_matches('#').trim_start();
+    let (name, rest) = heading.split_once(':').unwrap_or((heading, ""));
+    match name.trim().to_lowercase().as_str() {
+        "user" | "human" => Some((true, rest.trim())),
+        "assistant" | "ai" => Some((false, rest.trim())),
+        _ => None,
+    }
+}
+
+/// Bundle a prompt and its response as markdown for a bug report, with secrets redacted
+pub fn format_repro(prompt: &str, response: &str, provider: &str) -> String {
+    let mut repro = String::from("# cmdk-rs reproduction\n\n");
+    repro.push_str(&format!("- Version: {}\n", env!("CARGO_PKG_VERSION")));
+    repro.push_str(&format!("- Provider: {}\n\n", provider));
+    repro.push_str("## Prompt\n\n");
+    repro.push_str(&format_response(prompt, Destination::Export));
+    repro.push_str("\n\n## Response\n\n");
+    repro.push_str(&format_response(response, Destination::Export));
+    repro.push('\n');
+    redact::redact_secrets(&repro)
+}
+
+/// Keep only the most recent `keep` turns of a session transcript
+fn keep_recent_turns(content: &str, keep: usize) -> String {
+    let turn_starts = turn_starts(content);
+
+    if turn_starts.len() <= keep {
+        return content.to_string();
+    }
+
+    if keep == 0 {
+        return String::new();
+    }
+
+    content[turn_starts[turn_starts.len() - keep]..].to_string()
+}
+
+/// Byte offsets of each `## User:` heading that starts a line
+fn turn_starts(content: &str) -> Vec<usize> {
+    content
+        .match_indices("## User:")
+        .map(|(i, _)| i)
+        .filter(|&i| i == 0 || content[..i].ends_with('\n'))
+        .collect()
+}
+
 /// Clear the current session
 pub fn clear_session() -> Result<()> {
+    if let Some(ref mut fresh) = *FRESH_HISTORY.lock().unwrap_or_else(|e| e.into_inner()) {
+        fresh.clear();
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return store.remove_session(&session_key());
+    }
+
     let session_file = get_session_file();
     if session_file.exists() {
         fs::remove_file(&session_file)?;
@@ -120,6 +518,11 @@ pub fn clear_session() -> Result<()> {
 
 /// Save the last result to a file
 pub fn save_last_result(result: &str) -> Result<()> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return store.save_last_result(result);
+    }
+
     let result_file = get_result_file();
     let dir = settings::get_command_k_dir();
     fs::create_dir_all(&dir)?;
@@ -130,6 +533,11 @@ pub fn save_last_result(result: &str) -> Result<()> {
 /// Get the last result
 #[allow(dead_code)]
 pub fn get_last_result() -> Result<Option<String>> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return Ok(store.last_result()?.filter(|r| !r.trim().is_empty()));
+    }
+
     let result_file = get_result_file();
     if result_file.exists() {
         let content = fs::read_to_string(&result_file)?;
@@ -145,39 +553,188 @@ pub fn get_last_result() -> Result<Option<String>> {
 
 /// Add a prompt to the history file
 pub fn add_to_prompt_history(prompt: &str) -> Result<()> {
+    append_to_prompt_history(&[&encode_prompt(prompt)])
+}
+
+/// Multi-line prompts are kept on one history line, with this in place of each newline
+const PROMPT_LINE_BREAK: char = '\u{1e}';
+
+fn encode_prompt(prompt: &str) -> String {
+    prompt.replace('\n', &PROMPT_LINE_BREAK.to_string())
+}
+
+fn decode_prompt(line: &str) -> String {
+    line.replace(PROMPT_LINE_BREAK, "\n")
+}
+
+/// Append lines to the history file without rewriting it, so an interrupted
+/// write can at worst leave one partial line at the end
+fn append_to_prompt_history(prompts: &[&str]) -> Result<()> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return store.append_prompts(prompts);
+    }
+
     let history_file = get_history_file();
     let dir = settings::get_command_k_dir();
     fs::create_dir_all(&dir)?;
 
-    let mut content = if history_file.exists() {
-        fs::read_to_string(&history_file)?
-    } else {
-        String::new()
-    };
-
-    content.push_str(prompt);
-    content.push('\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&history_file)?;
+
+    // Start on a fresh line if an earlier write was cut short
+    let mut buf = String::new();
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            buf.push('\n');
+        }
+    }
+    for prompt in prompts {
+        buf.push_str(prompt);
+        buf.push('\n');
+    }
 
-    fs::write(&history_file, content)?;
+    // A single write, so concurrent instances don't interleave lines
+    file.write_all(buf.as_bytes())?;
     Ok(())
 }
 
+/// Remove every occurrence of a prompt from the history file.
+/// Returns the number of lines removed.
+pub fn remove_from_prompt_history(prompt: &str) -> Result<usize> {
+    let prompt = &encode_prompt(prompt);
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return store.remove_prompt(prompt);
+    }
+
+    let history_file = get_history_file();
+    if !history_file.exists() {
+        return Ok(0);
+    }
+
+    let content = fs::read_to_string(&history_file)?;
+    let target = prompt.trim();
+    let mut removed = 0;
+    let mut kept = String::new();
+    for line in content.lines() {
+        if line.trim() == target {
+            removed += 1;
+        } else {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+
+    if removed > 0 {
+        fs::write(&history_file, kept)?;
+    }
+    Ok(removed)
+}
+
+/// Import prompts from a file into the history, skipping duplicates.
+/// Returns the number of prompts imported and the number skipped as duplicates.
+pub fn import_prompt_history(path: &str) -> Result<(usize, usize)> {
+    let incoming = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read prompt file: {}", path))?;
+
+    let content = read_prompt_history()?;
+
+    let (new_prompts, duplicates) = filter_new_prompts(&content, &incoming);
+
+    if !new_prompts.is_empty() {
+        let prompts: Vec<&str> = new_prompts.iter().map(String::as_str).collect();
+        append_to_prompt_history(&prompts)?;
+    }
+
+    Ok((new_prompts.len(), duplicates))
+}
+
+/// Split incoming lines into prompts not yet in the history and a duplicate count
+fn filter_new_prompts(existing: &str, incoming: &str) -> (Vec<String>, usize) {
+    let mut seen: std::collections::HashSet<String> = existing
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    let mut new_prompts = Vec::new();
+    let mut duplicates = 0;
+
+    for line in incoming.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if seen.insert(trimmed.to_string()) {
+            new_prompts.push(trimmed.to_string());
+        } else {
+            duplicates += 1;
+        }
+    }
+
+    (new_prompts, duplicates)
+}
+
 /// Get recent prompts from history (deduplicated, most recent first)
 pub fn get_recent_prompts(limit: usize) -> Result<Vec<String>> {
-    let history_file = get_history_file();
+    // Optionally only look at the most recent lines instead of the whole file
+    let window = if settings::is_enabled("dedup_recent_only") {
+        Some(settings::get_number("dedup_window"))
+    } else {
+        None
+    };
 
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        let prompts = store.recent_prompts(limit, window)?;
+        return Ok(prompts.iter().map(|line| decode_prompt(line)).collect());
+    }
+
+    let content = read_prompt_history()?;
+    Ok(unique_recent_prompts(&content, limit, window))
+}
+
+/// The whole prompt history, one prompt per line
+fn read_prompt_history() -> Result<String> {
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = sqlite_store()? {
+        return store.prompt_history();
+    }
+
+    let history_file = get_history_file();
     if !history_file.exists() {
-        return Ok(Vec::new());
+        return Ok(String::new());
     }
 
-    let content = fs::read_to_string(&history_file)?;
-    let lines: Vec<&str> = content.lines().collect();
+    // Lossy, since an interrupted write may have split a multi-byte character
+    Ok(String::from_utf8_lossy(&fs::read(&history_file)?).to_string())
+}
+
+/// Most recent unique prompts, considering only the last `window` lines if given
+fn unique_recent_prompts(content: &str, limit: usize, window: Option<usize>) -> Vec<String> {
+    // Every complete entry ends with a newline; anything after the last one
+    // is a partial write
+    let complete = match content.rfind('\n') {
+        Some(end) => &content[..=end],
+        None => "",
+    };
+    let lines: Vec<&str> = complete.lines().collect();
+    let window = window.unwrap_or(lines.len());
 
     // Reverse, deduplicate, and limit
     let mut seen = std::collections::HashSet::new();
-    let prompts: Vec<String> = lines
+    lines
         .iter()
         .rev()
+        .take(window)
         .filter(|line| {
             let trimmed = line.trim();
             if trimmed.is_empty() || seen.contains(trimmed) {
@@ -188,15 +745,14 @@ pub fn get_recent_prompts(limit: usize) -> Result<Vec<String>> {
             }
         })
         .take(limit)
-        .map(|s| s.to_string())
-        .collect();
-
-    Ok(prompts)
+        .map(|line| decode_prompt(line))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::settings::TestDir;
 
     #[test]
     fn test_session_file_hash() {
@@ -205,4 +761,104 @@ mod tests {
         let file2 = get_session_file();
         assert_eq!(file1, file2);
     }
+
+    #[test]
+    fn test_keep_recent_turns() {
+        let session = "## User: one\n\n## Assistant:\nls\n\n\
+                       ## User: two\n\n## Assistant:\npwd\n\n\
+                       ## User: three\n\n## Assistant:\ncd\n\n";
+        let trimmed = keep_recent_turns(session, 2);
+        assert!(!trimmed.contains("one"));
+        assert!(trimmed.starts_with("## User: two"));
+        assert_eq!(trimmed.matches("## User:").count(), 2);
+        assert_eq!(keep_recent_turns(session, 5), session);
+    }
+
+    #[test]
+    fn test_session_tags() {
+        let session = "## User: one\n\n## Assistant:\nls\n\n";
+        let tagged = with_tags(session, &parse_tag_list(" docker, ,debugging,docker"));
+        assert_eq!(tagged, format!("<!-- tags: docker, debugging -->\n{}", session));
+        assert_eq!(parse_tags(&tagged), vec!["docker", "debugging"]);
+        assert_eq!(strip_tags_header(&tagged), session);
+        assert_eq!(with_tags(&tagged, &[]), session);
+        assert!(parse_tags(session).is_empty());
+
+        let summary = SessionSummary::new("s".to_string(), &tagged, 0);
+        assert_eq!((summary.turns, summary.first_question.as_str()), (1, "one"));
+    }
+
+    #[test]
+    fn test_session_history_without_turn_stats() {
+        let _dir = TestDir::new("session");
+        let stats = TurnStats {
+            provider: "mock".to_string(),
+            prompt_bytes: 42,
+        };
+        append_to_session("list files", "ls -la", &stats).unwrap();
+        append_to_session("and hidden ones?", "ls -A", &stats).unwrap();
+        assert!(read_session_content().unwrap().contains("<!-- cmdk: provider=mock prompt_bytes=42"));
+
+        let history = get_session_history().unwrap().unwrap();
+        assert_eq!(
+            history,
+            "## User: list files\n\n## Assistant:\nls -la\n\n## User: and hidden ones?\n\n## Assistant:\nls -A\n\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_conversation() {
+        let exported = "# Chat export\n\n### Human:\nhow do I\nlist files?\n\n### AI\nUse `ls`:\n\n\
+                        ```\n# user: me\nls -la\n```\n<!-- cmdk: provider=mock -->\n## User: and hidden ones?\n\n\
+                        ## Assistant:\nls -A\n";
+        let turns = parse_conversation(exported).unwrap();
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[0].0, "how do I\nlist files?");
+        assert_eq!(turns[0].1, "Use `ls`:\n\n```\n# user: me\nls -la\n```");
+        assert_eq!(turns[1], ("and hidden ones?".to_string(), "ls -A".to_string()));
+
+        assert!(parse_conversation("just some notes\n").is_err());
+        assert!(parse_conversation("## Assistant:\nhi\n").is_err());
+        assert!(parse_conversation("## User: a\n## User: b\n## Assistant:\nc\n").is_err());
+        assert!(parse_conversation("## User: a\n## Assistant:\nb\n## User: c\n").is_err());
+    }
+
+    #[test]
+    fn test_summarize_recent_turns() {
+        let session = "## User: one\n\n## Assistant:\nls\n<!-- cmdk: provider=mock -->\n\n\
+                       ## User: two\n\n## Assistant:\n\n```\npwd\n```\nsee ## User: docs\n\n";
+        let recap = summarize_recent_turns(session, 1);
+        assert!(recap.contains("- Asked: two\n  Answer: pwd\n"));
+        assert!(!recap.contains("one"));
+        assert!(!recap.contains("## User:"));
+        assert_eq!(summarize_recent_turns("", 3), "");
+
+        let long = format!("## User: {}\n\n## Assistant:\nok\n\n", "é".repeat(130));
+        assert!(summarize_recent_turns(&long, 1).contains(&format!("- Asked: {}…\n", "é".repeat(120))));
+    }
+
+    #[test]
+    fn test_unique_recent_prompts_window() {
+        let history = "old prompt\nbuild\ntest\nbuild\n";
+        assert_eq!(
+            unique_recent_prompts(history, 10, None),
+            vec!["build", "test", "old prompt"]
+        );
+        assert_eq!(unique_recent_prompts(history, 10, Some(3)), vec!["build", "test"]);
+        assert_eq!(unique_recent_prompts(history, 1, None), vec!["build"]);
+        assert_eq!(unique_recent_prompts("build\ntes", 10, None), vec!["build"]);
+
+        let snippet = "why does this fail?\nfor f in *; do\n  echo $f\ndone";
+        let history = format!("build\n{}\n", encode_prompt(snippet));
+        assert_eq!(unique_recent_prompts(&history, 10, None), vec![snippet, "build"]);
+    }
+
+    #[test]
+    fn test_filter_new_prompts() {
+        let existing = "list files\nshow disk usage\n";
+        let incoming = "list files\n\n  find big files  \nfind big files\nshow disk usage\n";
+        let (new_prompts, duplicates) = filter_new_prompts(existing, incoming);
+        assert_eq!(new_prompts, vec!["find big files".to_string()]);
+        assert_eq!(duplicates, 3);
+    }
 }
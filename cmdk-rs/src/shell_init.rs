@@ -0,0 +1,41 @@
+use anyhow::{bail, Result};
+
+/// Shells `--init-shell` has a hook for
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+const BASH_HOOK: &str = r#"# cmdk-rs shell integration: eval "$(cmdk-rs --init-shell bash)"
+__cmdk_precmd() {
+    export CMDK_LAST_EXIT=$?
+    export CMDK_DIRSTACK="$(dirs -p)"
+}
+if [[ "$PROMPT_COMMAND" != *__cmdk_precmd* ]]; then
+    PROMPT_COMMAND="__cmdk_precmd${PROMPT_COMMAND:+; $PROMPT_COMMAND}"
+fi
+"#;
+
+const ZSH_HOOK: &str = r#"# cmdk-rs shell integration: eval "$(cmdk-rs --init-shell zsh)"
+__cmdk_precmd() {
+    export CMDK_LAST_EXIT=$?
+    export CMDK_DIRSTACK="$(dirs -p)"
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd __cmdk_precmd
+"#;
+
+const FISH_HOOK: &str = r#"# cmdk-rs shell integration: cmdk-rs --init-shell fish | source
+function __cmdk_postexec --on-event fish_postexec
+    set -gx CMDK_LAST_EXIT $status
+    set -gx CMDK_DIRSTACK (string join \n $PWD $dirstack)
+end
+"#;
+
+/// Hook that exports the variables cmdk-rs reads from the shell
+/// (`$CMDK_LAST_EXIT`, `$CMDK_DIRSTACK`) before each prompt
+pub fn hook(shell: &str) -> Result<&'static str> {
+    match shell {
+        "bash" => Ok(BASH_HOOK),
+        "zsh" => Ok(ZSH_HOOK),
+        "fish" => Ok(FISH_HOOK),
+        _ => bail!("Unsupported shell: {} (expected one of: {})", shell, SHELLS.join(", ")),
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::settings;
+
+/// Database file inside the command-k directory
+const DB_FILE: &str = "cmdk.db";
+
+/// SQLite store for prompt history, sessions and the last result (`storage=sqlite`).
+/// Sessions are keyed by the name their flat file would have, so both
+/// backends hash the working directory the same way.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    /// The database in the command-k directory, creating it and importing the
+    /// flat files on first use. Each directory's database is opened once per
+    /// process and shared from then on.
+    pub fn open() -> Result<Arc<Self>> {
+        static OPEN: Mutex<Option<HashMap<PathBuf, Arc<Store>>>> = Mutex::new(None);
+        let dir = settings::get_command_k_dir();
+        let mut open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(store) = open.as_ref().and_then(|stores| stores.get(&dir)) {
+            return Ok(store.clone());
+        }
+
+        fs::create_dir_all(&dir)?;
+        let conn = Connection::open(dir.join(DB_FILE)).context("Failed to open session database")?;
+        let store = Arc::new(Store { conn: Mutex::new(conn) });
+        store.init(&dir)?;
+        open.get_or_insert_with(HashMap::new).insert(dir, store.clone());
+        Ok(store)
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn init(&self, dir: &Path) -> Result<()> {
+        let conn = self.conn();
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS prompts (id INTEGER PRIMARY KEY, prompt TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS sessions (key TEXT PRIMARY KEY, content TEXT NOT NULL, updated INTEGER NOT NULL);
+             CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);",
+        )?;
+
+        let migrated: Option<String> = conn
+            .query_row("SELECT value FROM meta WHERE key = 'migrated'", [], |row| row.get(0))
+            .optional()?;
+        if migrated.is_none() {
+            migrate_flat_files(&conn, dir)?;
+        }
+        Ok(())
+    }
+
+    /// All prompts, oldest first, one per line (the prompt_history file format)
+    pub fn prompt_history(&self) -> Result<String> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT prompt FROM prompts ORDER BY id")?;
+        let mut content = String::new();
+        for prompt in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            content.push_str(&prompt?);
+            content.push('\n');
+        }
+        Ok(content)
+    }
+
+    /// The `limit` most recent distinct prompts, newest first, looking only at
+    /// the last `window` entries if given. Compared after trimming, like
+    /// `remove_prompt`.
+    pub fn recent_prompts(&self, limit: usize, window: Option<usize>) -> Result<Vec<String>> {
+        let conn = self.conn();
+        // SQLite takes the bare `prompt` from the row with the max(id)
+        let mut stmt = conn.prepare(
+            "SELECT prompt, max(id) FROM (SELECT id, prompt FROM prompts ORDER BY id DESC LIMIT ?1)
+             WHERE trim(prompt) != '' GROUP BY trim(prompt) ORDER BY max(id) DESC LIMIT ?2",
+        )?;
+        let window = window.map_or(-1, |w| w as i64);
+        let rows = stmt.query_map(params![window, limit as i64], |row| row.get::<_, String>(0))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn append_prompts(&self, prompts: &[&str]) -> Result<()> {
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        for prompt in prompts {
+            tx.execute("INSERT INTO prompts (prompt) VALUES (?1)", params![prompt])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete every prompt matching `prompt` after trimming; returns the count
+    pub fn remove_prompt(&self, prompt: &str) -> Result<usize> {
+        Ok(self
+            .conn()
+            .execute("DELETE FROM prompts WHERE trim(prompt) = ?1", params![prompt.trim()])?)
+    }
+
+    /// Session transcript and its last-modified time in Unix seconds
+    pub fn session(&self, key: &str) -> Result<Option<(String, u64)>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT content, updated FROM sessions WHERE key = ?1",
+                params![key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    pub fn write_session(&self, key: &str, content: &str) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO sessions (key, content, updated) VALUES (?1, ?2, ?3)",
+            params![key, content, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Every session: key, transcript and last-modified time
+    pub fn sessions(&self) -> Result<Vec<(String, String, u64)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT key, content, updated FROM sessions")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn remove_session(&self, key: &str) -> Result<()> {
+        self.conn().execute("DELETE FROM sessions WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+
+    pub fn last_result(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row("SELECT value FROM meta WHERE key = 'last_result'", [], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn save_last_result(&self, result: &str) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('last_result', ?1)",
+            params![result],
+        )?;
+        Ok(())
+    }
+}
+
+/// Copy prompt_history, cli-session-*.md and last-result.txt into the
+/// database. The files are left in place so switching back loses nothing.
+fn migrate_flat_files(conn: &Connection, dir: &Path) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+
+    if let Ok(bytes) = fs::read(dir.join("prompt_history")) {
+        let content = String::from_utf8_lossy(&bytes);
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            tx.execute("INSERT INTO prompts (prompt) VALUES (?1)", params![line])?;
+        }
+    }
+
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("cli-session-") || !name.ends_with(".md") {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let updated = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .map(unix_secs)
+            .unwrap_or_else(|_| now());
+        tx.execute(
+            "INSERT OR REPLACE INTO sessions (key, content, updated) VALUES (?1, ?2, ?3)",
+            params![name, content, updated],
+        )?;
+    }
+
+    if let Ok(result) = fs::read_to_string(dir.join("last-result.txt")) {
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('last_result', ?1)",
+            params![result],
+        )?;
+    }
+
+    tx.execute("INSERT INTO meta (key, value) VALUES ('migrated', '1')", [])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Current time in Unix seconds
+pub fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::TestDir;
+
+    #[test]
+    fn test_migrates_flat_files() {
+        let test_dir = TestDir::new("storage-test");
+        let dir = &test_dir.path;
+        fs::write(dir.join("prompt_history"), "list files\nshow disk usage\n").unwrap();
+        fs::write(dir.join("cli-session-abcd1234.md"), "## User: hi\n").unwrap();
+        fs::write(dir.join("last-result.txt"), "ls -la").unwrap();
+
+        let store = Store { conn: Mutex::new(Connection::open_in_memory().unwrap()) };
+        store.init(dir).unwrap();
+        assert_eq!(store.prompt_history().unwrap(), "list files\nshow disk usage\n");
+        assert_eq!(store.session("cli-session-abcd1234.md").unwrap().unwrap().0, "## User: hi\n");
+        assert_eq!(store.last_result().unwrap().as_deref(), Some("ls -la"));
+
+        // Only the first open imports
+        fs::write(dir.join("prompt_history"), "other\n").unwrap();
+        store.init(dir).unwrap();
+        store.append_prompts(&["list files"]).unwrap();
+        store.append_prompts(&["show disk usage", "find big files", " list files "]).unwrap();
+        assert_eq!(store.recent_prompts(2, None).unwrap(), [" list files ", "find big files"]);
+        assert_eq!(store.recent_prompts(5, Some(2)).unwrap(), [" list files ", "find big files"]);
+        assert_eq!(store.remove_prompt("list files").unwrap(), 3);
+        assert_eq!(store.recent_prompts(5, None).unwrap(), ["find big files", "show disk usage"]);
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use regex_lite::Regex;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::settings;
+
+/// A named prompt with `{placeholders}` filled in before it is sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub name: String,
+    pub text: String,
+}
+
+impl Template {
+    /// Placeholder names in order of first appearance, without duplicates.
+    /// Names are word characters and dashes, so `awk '{print $1}'` is left alone.
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for caps in placeholder_regex().captures_iter(&self.text) {
+            let name = &caps[1];
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    /// The template text with each placeholder replaced by its value;
+    /// `values` are in the order returned by `placeholders`
+    pub fn fill(&self, values: &[String]) -> String {
+        let names = self.placeholders();
+        placeholder_regex()
+            .replace_all(&self.text, |caps: &regex_lite::Captures| {
+                names
+                    .iter()
+                    .position(|n| n == &caps[1])
+                    .and_then(|i| values.get(i))
+                    .cloned()
+                    .unwrap_or_else(|| caps[0].to_string())
+            })
+            .into_owned()
+    }
+}
+
+fn placeholder_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\{([A-Za-z0-9_-]+)\}").unwrap())
+}
+
+/// Templates file in the command-k directory
+pub fn get_templates_file() -> PathBuf {
+    settings::get_command_k_dir().join("templates")
+}
+
+/// Parse `name=template text` lines, skipping comments and empty lines
+pub fn parse_templates(content: &str) -> Vec<Template> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, text)| Template {
+            name: name.trim().to_string(),
+            text: text.trim().to_string(),
+        })
+        .filter(|t| !t.name.is_empty() && !t.text.is_empty())
+        .collect()
+}
+
+/// All templates, in file order (empty if there is no templates file)
+pub fn load_templates() -> Result<Vec<Template>> {
+    let path = get_templates_file();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read templates file: {:?}", path))?;
+    Ok(parse_templates(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_fill() {
+        let templates = parse_templates(
+            "# comment\n\nfunction = generate a {lang} function that {task}, in {lang}\nbroken\nport=a=b {x}\n",
+        );
+        assert_eq!(templates.len(), 2);
+        assert_eq!(templates[0].name, "function");
+        assert_eq!(templates[0].placeholders(), vec!["lang", "task"]);
+        assert_eq!(
+            templates[0].fill(&["rust".to_string(), "prints {lang}".to_string()]),
+            "generate a rust function that prints {lang}, in rust"
+        );
+        assert_eq!(templates[1].text, "a=b {x}");
+
+        let odd = Template { name: "t".into(), text: "awk '{print $1}' {{file} {}".into() };
+        assert_eq!(odd.placeholders(), vec!["file"]);
+    }
+}
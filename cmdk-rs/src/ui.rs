@@ -1,12 +1,19 @@
 use ratatui::{
     layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
+    text::{Line, Span, Text},
     widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
     Frame,
 };
 
-use crate::app::{App, AppState, MenuItem, ResultAction, SettingsMenuItem};
+use crate::app::{self, App, AppState, MenuItem, ResultAction, SettingsMenuItem};
+use crate::context;
+use crate::diff::{self, DiffLine};
+use crate::format;
+use crate::highlight;
+use crate::provider::{self, QueryError, QueryMode};
+use crate::settings;
+use crate::templates;
 
 /// Main UI rendering function
 pub fn render(frame: &mut Frame, app: &App) {
@@ -74,12 +81,23 @@ fn render_header(frame: &mut Frame, app: &App, area: Rect) {
 fn render_content(frame: &mut Frame, app: &App, area: Rect) {
     match &app.state {
         AppState::MainMenu => render_main_menu(frame, app, area),
-        AppState::PromptInput => render_prompt_input(frame, app, area),
+        AppState::PromptInput
+        | AppState::PipeInput
+        | AppState::EditCommandInput
+        | AppState::TemplateInput
+        | AppState::ProfileNameInput
+        | AppState::TagInput
+        | AppState::AliasNameInput => {
+            render_prompt_input(frame, app, area)
+        }
         AppState::Loading => render_loading(frame, app, area),
         AppState::ShowingResult { response } => render_result(frame, app, response, area),
         AppState::ContextView => render_context_view(frame, app, area),
         AppState::SettingsMenu => render_settings_menu(frame, app, area),
         AppState::RecentPrompts => render_recent_prompts(frame, app, area),
+        AppState::Templates => render_templates(frame, app, area),
+        AppState::ResponseCache => render_response_cache(frame, app, area),
+        AppState::NoProvider { reason } => render_no_provider(frame, reason, area),
         AppState::Error { message } => render_error(frame, message, area),
     }
 }
@@ -103,8 +121,11 @@ fn render_main_menu(frame: &mut Frame, app: &App, area: Rect) {
             let text = match item {
                 MenuItem::AskQuestion => "Ask a question",
                 MenuItem::RecentPrompts => "Recent prompts",
+                MenuItem::Templates => "Templates",
                 MenuItem::ViewContext => "View context",
                 MenuItem::PrivacySettings => "Privacy settings",
+                MenuItem::ResponseCache => "Response cache",
+                MenuItem::TagSession => "Tag session",
                 MenuItem::ClearConversation => "Clear conversation",
                 MenuItem::Exit => "Exit",
             };
@@ -123,33 +144,79 @@ fn render_main_menu(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(list, area);
 }
 
+/// Most input lines shown at once; longer input scrolls with the cursor
+const MAX_INPUT_ROWS: usize = 8;
+
+/// Height of the input box for `input`, borders included
+pub fn input_height(input: &str) -> u16 {
+    input.split('\n').count().min(MAX_INPUT_ROWS) as u16 + 2
+}
+
+/// Draw the input in `block`, scrolled so the cursor's line is visible, and place the cursor
+pub fn render_input_box(frame: &mut Frame, app: &App, block: Block, area: Rect) {
+    let (row, col) = app::cursor_row_col(&app.input, app.cursor_position);
+    let visible = area.height.saturating_sub(2).max(1) as usize;
+    let scroll = row.saturating_sub(visible - 1);
+    let input = Paragraph::new(app.input.as_str())
+        .style(Style::default().fg(Color::White))
+        .block(block)
+        .scroll((scroll as u16, 0));
+    frame.render_widget(input, area);
+
+    frame.set_cursor_position((area.x + col as u16 + 1, area.y + (row - scroll) as u16 + 1));
+}
+
 /// Render the prompt input
 fn render_prompt_input(frame: &mut Frame, app: &App, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(3), Constraint::Min(1)])
+        .constraints([Constraint::Length(input_height(&app.input)), Constraint::Min(1)])
         .split(area);
 
-    let input = Paragraph::new(app.input.as_str())
-        .style(Style::default().fg(Color::White))
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title(" What do you need? ")
-                .border_style(Style::default().fg(Color::Magenta)),
-        );
-
-    frame.render_widget(input, chunks[0]);
-
-    // Show cursor position
-    frame.set_cursor_position((
-        chunks[0].x + app.cursor_position as u16 + 1,
-        chunks[0].y + 1,
-    ));
+    let title = match (&app.state, app.query_mode) {
+        (AppState::PipeInput, _) => " Pipe response to command ".to_string(),
+        (AppState::EditCommandInput, _) => " Edit command, then Enter to run ".to_string(),
+        (AppState::ProfileNameInput, _) => " Save privacy settings as profile ".to_string(),
+        (AppState::TagInput, _) => " Session tags (comma-separated, empty to remove) ".to_string(),
+        (AppState::AliasNameInput, _) => " Alias name for this command ".to_string(),
+        (AppState::TemplateInput, _) => format!(
+            " {} ({}/{}) ",
+            app.current_placeholder().unwrap_or_default(),
+            app.template_values.len() + 1,
+            app.templates[app.templates_selected].placeholders().len()
+        ),
+        (_, QueryMode::Rephrase) => " Suggested prompt (edit or press Enter) ".to_string(),
+        _ => format!(" {} ", app.input_label),
+    };
 
-    let help = Paragraph::new("Press Enter to submit, Esc to cancel")
-        .style(Style::default().fg(Color::Gray))
-        .alignment(Alignment::Center);
+    let mut block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Magenta));
+    if matches!(app.state, AppState::PromptInput) {
+        let (label, color) = match app.active_preset() {
+            _ if !app.include_context => (" context: off ".to_string(), Color::Yellow),
+            Some(preset) => (format!(" context: {} task ", preset.name), Color::Cyan),
+            None => (" context: on ".to_string(), Color::Gray),
+        };
+        block = block.title(Line::styled(label, Style::default().fg(color)).right_aligned());
+    }
+    render_input_box(frame, app, block, chunks[0]);
+
+    let help_text = match app.state {
+        AppState::PromptInput => {
+            "Press Enter to submit, Alt+Enter for a new line, Ctrl+G to toggle context, Ctrl+T for a task preset, Esc to cancel"
+        }
+        AppState::EditCommandInput => "Press Enter to run, Alt+Enter for a new line, Esc to go back",
+        _ => "Press Enter to submit, Esc to cancel",
+    };
+    let mut help_lines = vec![Line::styled(help_text, Style::default().fg(Color::Gray))];
+    if let Some(warning) = app.prompt_size_warning() {
+        help_lines.push(Line::styled(warning, Style::default().fg(Color::Yellow)));
+    }
+    let help = Paragraph::new(help_lines)
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
 
     frame.render_widget(help, chunks[1]);
 }
@@ -161,7 +228,7 @@ const SPINNER_FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦
 fn render_loading(frame: &mut Frame, app: &App, area: Rect) {
     let spinner = SPINNER_FRAMES[app.spinner_frame % SPINNER_FRAMES.len()];
     
-    let loading_text = vec![
+    let mut loading_text = vec![
         Line::from(""),
         Line::from(vec![
             Span::styled(
@@ -183,6 +250,10 @@ fn render_loading(frame: &mut Frame, app: &App, area: Rect) {
             ),
         ]),
     ];
+    if let Some(warning) = app.prompt_size_warning() {
+        loading_text.push(Line::from(""));
+        loading_text.push(Line::styled(warning, Style::default().fg(Color::Yellow)));
+    }
 
     let loading = Paragraph::new(loading_text)
         .alignment(Alignment::Center)
@@ -197,23 +268,140 @@ fn render_loading(frame: &mut Frame, app: &App, area: Rect) {
 
 /// Render the result view
 fn render_result(frame: &mut Frame, app: &App, response: &str, area: Rect) {
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([Constraint::Min(5), Constraint::Length(8)])
-        .split(area);
+    let chunks = if app.full_view.is_some() {
+        // The response takes the whole area; the actions come back on close
+        Layout::default()
+            .constraints([Constraint::Min(5)])
+            .split(area)
+    } else {
+        Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(5), Constraint::Length(app.result_actions.len() as u16 + 2)])
+            .split(area)
+    };
 
     // Response display
-    let response_text = Paragraph::new(response)
+    let title = match app.query_mode {
+        _ if app.show_changes => " What changed ",
+        QueryMode::Explain => " Explanation ",
+        QueryMode::Answer => " Answer ",
+        _ => " Response ",
+    };
+    let title = match app.regenerate_count {
+        _ if app.streaming => format!("{}(streaming…) ", title),
+        _ if app.stopped_early => format!("{}(stopped early) ", title),
+        n if n > 0 && !app.show_changes => format!("{}(attempt {}) ", title, n + 1),
+        _ => title.to_string(),
+    };
+    let response_text = if app.show_changes {
+        changes_text(app)
+    } else if app.line_selection.is_some() || app.show_line_numbers {
+        let width = response.lines().count().to_string().len();
+        Text::from(
+            response
+                .lines()
+                .enumerate()
+                .map(|(i, line)| {
+                    let style = match app.line_selection {
+                        Some(selection) if i == selection.cursor => {
+                            Style::default().fg(Color::Black).bg(Color::Magenta)
+                        }
+                        Some(selection) if selection.contains(i) => {
+                            Style::default().fg(Color::Black).bg(Color::Green)
+                        }
+                        _ => Style::default(),
+                    };
+                    let mut spans = Vec::new();
+                    if app.show_line_numbers {
+                        spans.push(Span::styled(
+                            format!("{:>width$} │ ", i + 1, width = width),
+                            Style::default().fg(Color::DarkGray),
+                        ));
+                    }
+                    spans.push(Span::styled(line, style));
+                    Line::from(spans)
+                })
+                .collect::<Vec<_>>(),
+        )
+    } else if let Some(selected) = app.selected_code_block() {
+        // Several code blocks: the one Run/Copy would use stands out
+        let (first, last) = selected.lines;
+        let mut lines = reasoning_lines(app);
+        lines.extend(response.lines().enumerate().map(|(i, line)| {
+            if (first..=last).contains(&i) {
+                Line::styled(line.to_string(), Style::default().fg(Color::Magenta))
+            } else {
+                Line::from(line.to_string())
+            }
+        }));
+        Text::from(lines)
+    } else {
+        let response = if app.pretty_wrap && app.query_mode == QueryMode::Command {
+            format::wrap_at_operators(response, chunks[0].width.saturating_sub(2) as usize)
+        } else {
+            response.to_string()
+        };
+        let mut lines = reasoning_lines(app);
+        if app.highlight {
+            lines.extend(highlight::response_lines(&response, app.query_mode == QueryMode::Command));
+        } else {
+            lines.extend(Text::from(response).lines);
+        }
+        Text::from(lines)
+    };
+    let mut block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Green));
+    if let Some(age) = app.cached_age.filter(|_| !app.show_changes) {
+        block = block.title(Span::styled(
+            format!(" (cached, {} old) ", format::format_age(age)),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    let block_count = app.code_blocks().len();
+    if block_count > 1 && !app.show_changes {
+        let label = format!(" block {}/{} (Tab: next) ", app.selected_block.min(block_count - 1) + 1, block_count);
+        block = block.title(Line::styled(label, Style::default().fg(Color::Magenta)).right_aligned());
+    }
+    if app.command_response().is_some_and(|cmd| provider::is_destructive(&cmd)) {
+        block = block
+            .title(Span::styled(
+                " ⚠ destructive ",
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ))
+            .border_style(Style::default().fg(Color::Red));
+    }
+    let response_text = Paragraph::new(response_text)
         .style(Style::default().fg(Color::Green))
-        .wrap(Wrap { trim: false })
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title(" Response ")
-                .border_style(Style::default().fg(Color::Green)),
-        );
+        .wrap(Wrap { trim: false });
+
+    // Compare the wrapped height with the space inside the borders
+    let inner_height = chunks[0].height.saturating_sub(2);
+    let total_lines = response_text.line_count(chunks[0].width.saturating_sub(2));
+    let max_scroll = u16::try_from(total_lines)
+        .unwrap_or(u16::MAX)
+        .saturating_sub(inner_height);
+
+    if let Some(scroll) = app.full_view {
+        app.full_view_max_scroll.set(max_scroll);
+        let scroll = scroll.min(max_scroll);
+        if scroll < max_scroll {
+            block = block.title_bottom(
+                Line::styled(" ▼ more ", Style::default().fg(Color::Yellow)).right_aligned(),
+            );
+        }
+        frame.render_widget(response_text.scroll((scroll, 0)).block(block), chunks[0]);
+        return;
+    }
 
-    frame.render_widget(response_text, chunks[0]);
+    if max_scroll > 0 {
+        block = block.title_bottom(
+            Line::styled(" ▼ more (f: full view) ", Style::default().fg(Color::Yellow))
+                .right_aligned(),
+        );
+    }
+    frame.render_widget(response_text.block(block), chunks[0]);
 
     // Action menu
     let actions: Vec<ListItem> = app
@@ -221,7 +409,14 @@ fn render_result(frame: &mut Frame, app: &App, response: &str, area: Rect) {
         .iter()
         .enumerate()
         .map(|(i, action)| {
-            let style = if i == app.result_selected {
+            let run_disabled = *action == ResultAction::RunCommand && app.run_disabled();
+            let man_program = match action {
+                ResultAction::OpenManPage => app.man_page_program(),
+                _ => None,
+            };
+            let style = if run_disabled || *action == ResultAction::OpenManPage && man_program.is_none() {
+                Style::default().fg(Color::DarkGray)
+            } else if i == app.result_selected {
                 Style::default()
                     .fg(Color::Magenta)
                     .add_modifier(Modifier::BOLD)
@@ -235,10 +430,23 @@ fn render_result(frame: &mut Frame, app: &App, response: &str, area: Rect) {
                 "  "
             };
             let text = match action {
-                ResultAction::RunCommand => "Run command",
-                ResultAction::CopyToClipboard => "Copy to clipboard",
-                ResultAction::AskFollowUp => "Ask follow-up",
-                ResultAction::BackToMenu => "Back to menu",
+                ResultAction::RunCommand if run_disabled => {
+                    "Run command (response doesn't look like a runnable command)".to_string()
+                }
+                ResultAction::RunCommand => "Run command".to_string(),
+                ResultAction::EditAndRun => "Edit and run...".to_string(),
+                ResultAction::CopyToClipboard => "Copy to clipboard".to_string(),
+                ResultAction::PipeTo => "Pipe to command...".to_string(),
+                ResultAction::SaveAsAlias => "Save as alias...".to_string(),
+                ResultAction::OpenManPage => match man_program {
+                    Some(program) => format!("Open man page ({})", program),
+                    None => "Open man page (no program found)".to_string(),
+                },
+                ResultAction::AskFollowUp => "Ask follow-up".to_string(),
+                ResultAction::Regenerate => "Regenerate".to_string(),
+                ResultAction::WhatChanged => "What changed".to_string(),
+                ResultAction::BackToMenu => "Back to menu".to_string(),
+                ResultAction::Quit => "Quit".to_string(),
             };
 
             ListItem::new(Line::from(format!("{}{}", prefix, text))).style(style)
@@ -255,6 +463,40 @@ fn render_result(frame: &mut Frame, app: &App, response: &str, area: Rect) {
     frame.render_widget(action_list, chunks[1]);
 }
 
+/// The reasoning section above a response, collapsed to one line until t is pressed
+fn reasoning_lines(app: &App) -> Vec<Line<'static>> {
+    let Some(ref reasoning) = app.last_reasoning else {
+        return Vec::new();
+    };
+    let dim = Style::default().fg(Color::DarkGray);
+    if !app.reasoning_expanded {
+        return vec![Line::styled("▸ Reasoning (t to show)", dim), Line::from("")];
+    }
+    let mut lines = vec![Line::styled("▾ Reasoning (t to hide)", dim)];
+    lines.extend(
+        reasoning
+            .lines()
+            .map(|line| Line::styled(format!("  {}", line), dim.add_modifier(Modifier::ITALIC))),
+    );
+    lines.push(Line::from(""));
+    lines
+}
+
+/// The previous command against the current one, removed lines in red and added in green
+fn changes_text(app: &App) -> Text<'static> {
+    let previous = app.previous_command.clone().unwrap_or_default();
+    let current = app.last_command.clone().unwrap_or_default();
+    let lines: Vec<Line> = diff::line_diff(&previous, &current)
+        .into_iter()
+        .map(|line| match line {
+            DiffLine::Same(text) => Line::styled(format!("  {}", text), Style::default().fg(Color::Gray)),
+            DiffLine::Removed(text) => Line::styled(format!("- {}", text), Style::default().fg(Color::Red)),
+            DiffLine::Added(text) => Line::styled(format!("+ {}", text), Style::default().fg(Color::Green)),
+        })
+        .collect();
+    Text::from(lines)
+}
+
 /// Render context view
 fn render_context_view(frame: &mut Frame, app: &App, area: Rect) {
     let context = Paragraph::new(app.context_display.as_str())
@@ -281,6 +523,8 @@ fn render_settings_menu(frame: &mut Frame, app: &App, area: Rect) {
                 Style::default()
                     .fg(Color::Magenta)
                     .add_modifier(Modifier::BOLD)
+            } else if matches!(item, SettingsMenuItem::Toggle { ignored: true, .. }) {
+                Style::default().fg(Color::DarkGray)
             } else {
                 Style::default()
             };
@@ -293,16 +537,32 @@ fn render_settings_menu(frame: &mut Frame, app: &App, area: Rect) {
 
             let text = match item {
                 SettingsMenuItem::ChangeProvider => {
-                    format!("🤖 Change AI provider (current: {})", app.current_provider)
+                    let scope = if app.provider_pinned { ", project override" } else { "" };
+                    format!("🤖 Change AI provider (current: {}{})", app.current_provider, scope)
                 }
+                SettingsMenuItem::ClearProjectProvider => "Clear project override".to_string(),
                 SettingsMenuItem::Separator => "─────────────".to_string(),
-                SettingsMenuItem::Toggle { key: _, label, enabled } => {
+                SettingsMenuItem::PrivacyMode { mode } => {
+                    let meaning = match mode.as_str() {
+                        "minimal" => "shell and OS only",
+                        "off" => "no context",
+                        _ => "use the toggles below",
+                    };
+                    format!("🔒 Privacy mode: {} ({})", mode, meaning)
+                }
+                SettingsMenuItem::Toggle { key: _, label, enabled, ignored } => {
                     let check = if *enabled { "✓" } else { "✗" };
-                    format!("{} {}", check, label)
+                    let note = if *ignored { " (ignored: privacy mode)" } else { "" };
+                    format!("{} {}{}", check, label, note)
                 }
                 SettingsMenuItem::Separator2 => "─────────────".to_string(),
                 SettingsMenuItem::EnableAll => "Enable all".to_string(),
                 SettingsMenuItem::DisableAll => "Disable all".to_string(),
+                SettingsMenuItem::SwitchProfile { current } => format!(
+                    "Switch profile (current: {})",
+                    current.as_deref().unwrap_or("custom")
+                ),
+                SettingsMenuItem::SaveProfile => "Save as profile...".to_string(),
                 SettingsMenuItem::Back => "← Back".to_string(),
             };
 
@@ -354,27 +614,165 @@ fn render_recent_prompts(frame: &mut Frame, app: &App, area: Rect) {
                 "  "
             };
 
-            // Truncate long prompts
-            let display = if prompt.len() > 60 {
-                format!("{}...", &prompt[..57])
+            // Truncate long prompts, showing line breaks in multi-line ones
+            let display = format::ellipsize(&prompt.replace('\n', " ↵ "), 60);
+
+            ListItem::new(Line::from(format!("{}{}", prefix, display))).style(style)
+        })
+        .collect();
+
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(if app.confirming_delete {
+                " Delete this prompt from history? (y/n) "
+            } else {
+                " Recent Prompts (Enter to select, Esc to go back) "
+            })
+            .border_style(Style::default().fg(if app.confirming_delete {
+                Color::Red
+            } else {
+                Color::Cyan
+            })),
+    );
+
+    frame.render_widget(list, area);
+}
+
+/// Render cached responses
+fn render_response_cache(frame: &mut Frame, app: &App, area: Rect) {
+    if app.cache_entries.is_empty() {
+        let msg = Paragraph::new("No cached responses (responses are cached with response_cache=true)")
+            .style(Style::default().fg(Color::Yellow))
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL).title(" Response Cache "));
+        frame.render_widget(msg, area);
+        return;
+    }
+
+    let items: Vec<ListItem> = app
+        .cache_entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let (prefix, style) = if i == app.cache_selected {
+                ("▶ ", Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD))
             } else {
-                prompt.clone()
+                ("  ", Style::default())
             };
+            let query = entry.query.lines().next().unwrap_or("");
+            let query = format::ellipsize(query, 60);
+            ListItem::new(Line::from(vec![
+                Span::styled(format!("{}{}", prefix, query), style),
+                Span::styled(
+                    format!(
+                        "  {} ago, {}",
+                        format::format_age(entry.age()),
+                        context::format_size(entry.size())
+                    ),
+                    Style::default().fg(Color::Gray),
+                ),
+            ]))
+        })
+        .collect();
 
-            ListItem::new(Line::from(format!("{}{}", prefix, display))).style(style)
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(if app.confirming_clear_cache {
+                " Clear all cached responses? (y/n) "
+            } else {
+                " Response Cache (d to delete, c to clear all) "
+            })
+            .border_style(Style::default().fg(if app.confirming_clear_cache {
+                Color::Red
+            } else {
+                Color::Cyan
+            })),
+    );
+
+    frame.render_widget(list, area);
+}
+
+/// Render prompt templates
+fn render_templates(frame: &mut Frame, app: &App, area: Rect) {
+    if app.templates.is_empty() {
+        let msg = Paragraph::new(format!(
+            "No templates yet. Add lines like\n\nfunction=generate a {{lang}} function that {{task}}\n\nto {}",
+            templates::get_templates_file().display()
+        ))
+        .style(Style::default().fg(Color::Yellow))
+        .alignment(Alignment::Center)
+        .block(Block::default().borders(Borders::ALL).title(" Templates "));
+        frame.render_widget(msg, area);
+        return;
+    }
+
+    let items: Vec<ListItem> = app
+        .templates
+        .iter()
+        .enumerate()
+        .map(|(i, template)| {
+            let (prefix, style) = if i == app.templates_selected {
+                ("▶ ", Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD))
+            } else {
+                ("  ", Style::default())
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(format!("{}{}", prefix, template.name), style),
+                Span::styled(format!("  {}", template.text), Style::default().fg(Color::Gray)),
+            ]))
         })
         .collect();
 
     let list = List::new(items).block(
         Block::default()
             .borders(Borders::ALL)
-            .title(" Recent Prompts (Enter to select, Esc to go back) ")
+            .title(" Templates (Enter to fill in, Esc to go back) ")
             .border_style(Style::default().fg(Color::Cyan)),
     );
 
     frame.render_widget(list, area);
 }
 
+/// Render the setup screen shown when no provider is available
+fn render_no_provider(frame: &mut Frame, reason: &str, area: Rect) {
+    let mut lines = vec![
+        Line::from(Span::styled(reason.to_string(), Style::default().fg(Color::Yellow))),
+        Line::from(""),
+        Line::from("Install one of these AI providers:"),
+        Line::from(""),
+    ];
+    for (name, step) in provider::SETUP_STEPS {
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!("  {:<8}", name),
+                Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(step),
+        ]));
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(vec![
+        Span::raw("Then press "),
+        Span::styled("s", Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD)),
+        Span::raw(" to pick it in settings, or set ai_provider in "),
+        Span::styled(
+            settings::get_settings_file().display().to_string(),
+            Style::default().fg(Color::Gray),
+        ),
+    ]));
+
+    let setup = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(" No AI provider found ")
+            .border_style(Style::default().fg(Color::Yellow)),
+    );
+
+    frame.render_widget(setup, area);
+}
+
 /// Render error message
 fn render_error(frame: &mut Frame, message: &str, area: Rect) {
     let error = Paragraph::new(message)
@@ -393,13 +791,41 @@ fn render_error(frame: &mut Frame, message: &str, area: Rect) {
 /// Render the status bar
 fn render_status_bar(frame: &mut Frame, app: &App, area: Rect) {
     let help_text = match &app.state {
-        AppState::MainMenu => "↑↓: Navigate | Enter: Select | q: Quit",
-        AppState::PromptInput => "Enter: Submit | Esc: Cancel",
+        AppState::MainMenu => "↑↓: Move | Enter: Select | n/N: New (+recap) | q: Quit",
+        AppState::PromptInput => "Enter: Submit | Alt+Enter: New line | Ctrl+G: Context | Esc: Cancel",
+        AppState::PipeInput => "Enter: Pipe | Esc: Back",
+        AppState::EditCommandInput => "Enter: Run | Alt+Enter: New line | Esc: Back",
+        AppState::Loading if app.cancel_query.is_some() => "Esc: Cancel",
         AppState::Loading => "Please wait...",
-        AppState::ShowingResult { .. } => "↑↓: Navigate | Enter: Select | Esc: Back",
+        AppState::ShowingResult { .. } if app.streaming => "Receiving response... | Esc: Stop here | q: Quit",
+        AppState::ShowingResult { .. } if app.full_view.is_some() => {
+            "↑↓: Scroll | Home/End: Top/Bottom | f/Esc: Close"
+        }
+        AppState::ShowingResult { .. } if app.show_changes => "Enter/Esc: Back to response | f: Full view",
+        AppState::ShowingResult { .. } if app.line_selection.is_some() => {
+            "j/k: Move | J/K: Extend | Enter: Copy | Esc: Done"
+        }
+        AppState::ShowingResult { .. } => match app.query_mode {
+            QueryMode::Explain => "↑↓/Enter: Action | Esc: Back | e: Command | p: Rephrase | v: Select | l: Nums | R: Repro | g: Regenerate",
+            _ => "↑↓/Enter: Action | Esc: Back | e: Explain | p: Rephrase | v: Select | l: Nums | R: Repro | g: Regenerate",
+        },
         AppState::ContextView => "Esc: Back | q: Quit",
+        AppState::SettingsMenu if app.confirming_project_save.is_some() => {
+            "Save provider for this project? y: Yes | other keys: No"
+        }
         AppState::SettingsMenu => "↑↓: Navigate | Enter: Toggle | Esc: Back",
-        AppState::RecentPrompts => "↑↓: Navigate | Enter: Select | Esc: Back",
+        AppState::RecentPrompts if app.confirming_delete => "y: Delete | any other key: Cancel",
+        AppState::RecentPrompts => "↑↓: Navigate | Enter: Select | d: Delete | Esc: Back",
+        AppState::Templates => "↑↓: Navigate | Enter: Select | Esc: Back",
+        AppState::TemplateInput => "Enter: Next | Esc: Back",
+        AppState::ProfileNameInput | AppState::TagInput | AppState::AliasNameInput => "Enter: Save | Esc: Back",
+        AppState::ResponseCache if app.confirming_clear_cache => "y/Enter: Clear all | any other key: Cancel",
+        AppState::ResponseCache => "↑↓: Navigate | d: Delete | c: Clear all | Esc: Back",
+        AppState::NoProvider { .. } => "s: Settings | Enter/Esc: Continue | q: Quit",
+        AppState::Error { .. } if app.can_retry() => "r: Retry | p: Rephrase | Enter/Esc: Continue",
+        AppState::Error { .. } if matches!(app.last_error, Some(QueryError::ProviderMissing(_))) => {
+            "s: Settings | Enter/Esc: Continue"
+        }
         AppState::Error { .. } => "Enter/Esc: Continue",
     };
 
@@ -407,20 +833,31 @@ fn render_status_bar(frame: &mut Frame, app: &App, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([
-            Constraint::Percentage(25),
-            Constraint::Percentage(50),
-            Constraint::Percentage(25),
+            Constraint::Percentage(20),
+            Constraint::Percentage(60),
+            Constraint::Percentage(20),
         ])
         .split(area);
 
     // Left: Provider info
-    let provider_text = Line::from(vec![
+    let mut provider_spans = vec![
         Span::styled("AI: ", Style::default().fg(Color::DarkGray)),
         Span::styled(
             &app.current_provider,
             Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD),
         ),
-    ]);
+    ];
+    // Warm-up indicator (only shown when warmup_on_change is enabled)
+    if app.warmup_receiver.is_some() {
+        provider_spans.push(Span::styled(" …", Style::default().fg(Color::Yellow)));
+    } else if let Some(ready) = app.provider_ready {
+        provider_spans.push(if ready {
+            Span::styled(" ✓", Style::default().fg(Color::Green))
+        } else {
+            Span::styled(" ✗", Style::default().fg(Color::Red))
+        });
+    }
+    let provider_text = Line::from(provider_spans);
     let provider = Paragraph::new(provider_text)
         .style(Style::default())
         .block(
@@ -431,8 +868,12 @@ fn render_status_bar(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(provider, chunks[0]);
 
     // Center: Help text
+    let (help_text, help_color) = match app.notice {
+        Some(ref notice) => (notice.as_str(), Color::Yellow),
+        None => (help_text, Color::Gray),
+    };
     let help = Paragraph::new(help_text)
-        .style(Style::default().fg(Color::Gray))
+        .style(Style::default().fg(help_color))
         .alignment(Alignment::Center)
         .block(
             Block::default()
@@ -441,17 +882,14 @@ fn render_status_bar(frame: &mut Frame, app: &App, area: Rect) {
         );
     frame.render_widget(help, chunks[1]);
 
-    // Right: Working directory (truncated)
-    let cwd = std::env::current_dir()
-        .map(|p| {
-            let path = p.display().to_string();
-            if path.len() > 20 {
-                format!("…{}", &path[path.len() - 19..])
-            } else {
-                path
-            }
-        })
-        .unwrap_or_else(|_| "?".to_string());
+    // Right: Working directory (truncated), unless hidden for screen-sharing
+    let cwd = if !app.show_cwd {
+        "hidden".to_string()
+    } else {
+        std::env::current_dir()
+            .map(|p| format::ellipsize_start(&p.display().to_string(), 20))
+            .unwrap_or_else(|_| "?".to_string())
+    };
 
     let cwd_text = Line::from(vec![
         Span::styled("📁 ", Style::default()),
@@ -19,6 +19,10 @@ M.config = {
   send_cursor_position = true,
   send_visual_selection = true,
   send_lsp_diagnostics = true,
+  -- Extra instructions and persona for the AI (string, or function returning one),
+  -- e.g. set per project from an exrc file
+  system_prompt = nil,
+  persona = nil,
 }
 
 -- State
@@ -198,6 +202,16 @@ local function write_context_file(ctx)
     table.insert(lines, "CMDK_NVIM_LSP_DIAGNOSTICS=" .. ctx.lsp_diagnostics:gsub("\n", "\\n"))
   end
 
+  for key, option in pairs({ CMDK_NVIM_SYSTEM_PROMPT = "system_prompt", CMDK_NVIM_PERSONA = "persona" }) do
+    local value = M.config[option]
+    if type(value) == "function" then
+      value = value()
+    end
+    if type(value) == "string" and value ~= "" then
+      table.insert(lines, key .. "=" .. value:gsub("\n", "\\n"))
+    end
+  end
+
   -- Write buffer content to a separate file
   if ctx.buffer_content then
     local content_file = tmpfile .. ".content"